@@ -1,17 +1,29 @@
-use std::net::IpAddr;
+use std::{collections::HashMap, net::IpAddr};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use proto::{
     clients::CtlClient,
-    common::service::{ResourceConfig, ServiceId, ServiceImage, ServiceSpec},
-    ctl::deployer::RedeploymentPolicy,
+    common::{
+        instance::InstanceId,
+        service::{
+            AntiAffinity, Autoscaling, Placement, ResourceConfig, ScaleToZero, ScalingTarget,
+            ServiceId, ServiceImage, ServiceSpec,
+        },
+    },
+    ctl::deployer::{
+        AllocStrategy, DeployServiceRes, DeploymentId, DeploymentInfo, InstanceState,
+        RedeploymentPolicy, RevisionId, RevisionInfo, ServiceSummary, TerminationOutcome,
+    },
 };
 use tabled::{self, Table, Tabled};
+use utils::args::parse_label;
 
 #[derive(Debug, Parser)]
 pub struct Cli {
     #[command(subcommand)]
     cmd: Cmd,
+    /// Controller's HTTP address, or a comma-separated list of addresses if
+    /// the control plane is replicated.
     #[arg(short, long)]
     ctl_addr: String,
 }
@@ -45,29 +57,118 @@ pub enum ServiceCmd {
     Show {
         id: String,
     },
-    Deploy {
-        #[arg(long)]
+    Deploy(DeployArgs),
+    /// Lists the service's revisions, i.e., its latest deploys.
+    Revisions {
+        id: String,
+    },
+    /// Redeploys the spec of one of the service's revisions.
+    Rollback {
         id: String,
         #[arg(long)]
-        image: String,
-        #[arg(long)]
-        public: bool,
+        revision: RevisionId,
+    },
+    /// Promotes the service's canary deployment.
+    Promote {
+        id: String,
+    },
+    /// Changes the amount of instances of the service, without redeploying it.
+    Scale {
+        id: String,
         #[arg(long)]
         concurrency: u32,
-        // #[arg(long)]
-        // cpu_shares: i64,
-        // #[arg(long)]
-        // memory_limit: i64,
+    },
+    /// Aborts the service's canary deployment.
+    Abort {
+        id: String,
     },
     Terminate {
         id: String,
     },
 }
 
+#[derive(Debug, Args)]
+pub struct DeployArgs {
+    #[arg(long)]
+    id: String,
+    #[arg(long)]
+    image: String,
+    #[arg(long)]
+    public: bool,
+    #[arg(long)]
+    concurrency: u32,
+    /// How to handle a service which is already deployed.
+    #[arg(long, value_enum, default_value = "none")]
+    redeployment_policy: RedeploymentPolicyArg,
+    /// Rolling policy only: instances allowed above the concurrency.
+    #[arg(long, default_value = "1")]
+    max_surge: u32,
+    /// Rolling policy only: instances allowed below the concurrency.
+    #[arg(long, default_value = "0")]
+    max_unavailable: u32,
+    /// Canary policy only: percentage of traffic sent to the new instances.
+    #[arg(long, default_value = "10")]
+    canary_weight: u32,
+    /// How to place the instances into the cluster's workers.
+    #[arg(long, value_enum, default_value = "scheduler")]
+    alloc_strategy: AllocStrategyArg,
+    /// CPU shares reserved for each instance (1024 per CPU).
+    #[arg(long, default_value = "0")]
+    cpu_shares: i64,
+    /// Memory reserved for each instance, in bytes.
+    #[arg(long, default_value = "0")]
+    memory_limit: i64,
+    /// Only places instances in workers with the given label, in the
+    /// `key=value` format. May be specified multiple times.
+    #[arg(long = "node-selector", value_parser = parse_label)]
+    node_selector: Vec<(String, String)>,
+    /// Places at most this many instances in each worker.
+    #[arg(long, conflicts_with = "spread_by_label")]
+    max_per_worker: Option<u32>,
+    /// Evenly spreads instances across the values of the given worker
+    /// label (e.g., `zone`).
+    #[arg(long)]
+    spread_by_label: Option<String>,
+    /// Enables autoscaling, up to this many instances. Requires a target.
+    #[arg(long, requires = "target")]
+    autoscale_max: Option<u32>,
+    /// Autoscaling only: the minimum amount of instances.
+    #[arg(long, default_value = "1")]
+    autoscale_min: u32,
+    /// Autoscaling only: target CPU usage (percentage) of the instances'
+    /// workers.
+    #[arg(long, group = "target", requires = "autoscale_max")]
+    target_cpu: Option<f64>,
+    /// Autoscaling only: target amount of in-flight requests per instance.
+    #[arg(long, group = "target", requires = "autoscale_max")]
+    target_in_flight: Option<u32>,
+    /// Terminates all instances after this many seconds without requests.
+    /// The next request starts an instance again.
+    #[arg(long)]
+    scale_to_zero_after: Option<u64>,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum RedeploymentPolicyArg {
+    None,
+    Replace,
+    Rolling,
+    BlueGreen,
+    Canary,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum AllocStrategyArg {
+    RoundRobin,
+    BestFit,
+    LeastLoaded,
+    Scheduler,
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     let cli = Cli::parse();
-    let ctl_client = CtlClient::new(&cli.ctl_addr);
+    let ctl_client = CtlClient::new(&cli.ctl_addr)?;
     match cli.cmd {
         Cmd::Node(cmd) => handle_node(cmd, ctl_client).await?,
         Cmd::Service(cmd) => handle_service(cmd, ctl_client).await?,
@@ -89,35 +190,265 @@ async fn handle_node(cmd: NodeCmd, ctl_client: CtlClient) -> eyre::Result<()> {
 
 async fn handle_service(cmd: ServiceCmd, ctl_client: CtlClient) -> eyre::Result<()> {
     match cmd {
-        ServiceCmd::List => todo!(),
-        ServiceCmd::Show { .. } => todo!(),
-        ServiceCmd::Deploy {
-            id,
-            image,
-            public,
-            concurrency,
-        } => {
-            let spec = ServiceSpec {
-                service_id: ServiceId(id),
-                image: ServiceImage(image),
-                public,
-                concurrency,
-                resource_config: ResourceConfig {
-                    // These are being ignored by the server, hence we may mock
-                    // them here.
-                    cpu_shares: 0,
-                    memory_limit: 0,
-                },
-            };
-            let rd = RedeploymentPolicy::None;
-            let res = ctl_client.deploy_service(spec, rd).await?;
-            println!("Successfully deployed service #{}", res.deployment_id);
+        ServiceCmd::List => {
+            let services = ctl_client.list_services().await?.services;
+            print_services_table(services);
+            Ok(())
+        }
+        ServiceCmd::Show { id } => {
+            let res = ctl_client.show_service(ServiceId(id)).await?;
+            let spec = &res.service_spec;
+            println!("Service:  {}", spec.service_id);
+            println!("Image:    {}", spec.image.0);
+            println!("Public:   {}", spec.public);
+            println!("Created:  {}", res.created_at);
+            println!("Degraded: {}", res.degraded);
+            print_deployments_table(res.deployments);
+            Ok(())
+        }
+        ServiceCmd::Deploy(args) => deploy_service(args, ctl_client).await,
+        ServiceCmd::Revisions { id } => {
+            let revisions = ctl_client.list_revisions(ServiceId(id)).await?.revisions;
+            print_revisions_table(revisions);
+            Ok(())
+        }
+        ServiceCmd::Rollback { id, revision } => {
+            let res = ctl_client.rollback_service(ServiceId(id), revision).await?;
+            print_deploy_res(&res);
+            Ok(())
+        }
+        ServiceCmd::Promote { id } => {
+            let res = ctl_client.promote_canary(ServiceId(id)).await?;
+            println!("Successfully promoted deployment #{}", res.deployment_id);
+            Ok(())
+        }
+        ServiceCmd::Scale { id, concurrency } => {
+            let res = ctl_client.scale_service(ServiceId(id), concurrency).await?;
+            println!(
+                "Successfully scaled deployment #{} ({} instances started, {} terminating)",
+                res.deployment_id,
+                res.started.len(),
+                res.terminating.len()
+            );
+            Ok(())
+        }
+        ServiceCmd::Abort { id } => {
+            let res = ctl_client.abort_canary(ServiceId(id)).await?;
+            println!("Successfully aborted deployment #{}", res.deployment_id);
             Ok(())
         }
-        ServiceCmd::Terminate { .. } => todo!(),
+        ServiceCmd::Terminate { id } => {
+            let res = ctl_client.terminate_service(ServiceId(id)).await?;
+            print_instance_states_table(res.instances);
+            match res.outcome {
+                TerminationOutcome::Complete => println!("Successfully terminated service"),
+                TerminationOutcome::Incomplete => {
+                    println!("Terminated service, but some instances failed to terminate");
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn deploy_service(args: DeployArgs, ctl_client: CtlClient) -> eyre::Result<()> {
+    let DeployArgs {
+        id,
+        image,
+        public,
+        concurrency,
+        redeployment_policy,
+        max_surge,
+        max_unavailable,
+        canary_weight,
+        alloc_strategy,
+        cpu_shares,
+        memory_limit,
+        node_selector,
+        max_per_worker,
+        spread_by_label,
+        autoscale_max,
+        autoscale_min,
+        target_cpu,
+        target_in_flight,
+        scale_to_zero_after,
+    } = args;
+    let anti_affinity = match (max_per_worker, spread_by_label) {
+        (Some(max), _) => Some(AntiAffinity::MaxPerWorker { max }),
+        (None, Some(key)) => Some(AntiAffinity::SpreadByLabel { key }),
+        (None, None) => None,
+    };
+    let autoscaling = autoscale_max.map(|max| Autoscaling {
+        min: autoscale_min,
+        max,
+        target: match (target_cpu, target_in_flight) {
+            (Some(usage), _) => ScalingTarget::Cpu { usage },
+            // Clap requires either of the targets.
+            (None, per_instance) => ScalingTarget::InFlightRequests {
+                per_instance: per_instance.unwrap(),
+            },
+        },
+    });
+    let spec = ServiceSpec {
+        service_id: ServiceId(id),
+        image: ServiceImage(image),
+        public,
+        concurrency,
+        resource_config: ResourceConfig {
+            cpu_shares,
+            memory_limit,
+        },
+        placement: Placement {
+            node_selector: node_selector.into_iter().collect(),
+            anti_affinity,
+        },
+        autoscaling,
+        scale_to_zero: scale_to_zero_after.map(|idle_timeout| ScaleToZero { idle_timeout }),
+    };
+    let rd = match redeployment_policy {
+        RedeploymentPolicyArg::None => RedeploymentPolicy::None,
+        RedeploymentPolicyArg::Replace => RedeploymentPolicy::Replace,
+        RedeploymentPolicyArg::Rolling => RedeploymentPolicy::Rolling {
+            max_surge,
+            max_unavailable,
+        },
+        RedeploymentPolicyArg::BlueGreen => RedeploymentPolicy::BlueGreen,
+        RedeploymentPolicyArg::Canary => RedeploymentPolicy::Canary {
+            weight: canary_weight,
+        },
+    };
+    let alloc_strategy = match alloc_strategy {
+        AllocStrategyArg::RoundRobin => AllocStrategy::RoundRobin,
+        AllocStrategyArg::BestFit => AllocStrategy::BestFit,
+        AllocStrategyArg::LeastLoaded => AllocStrategy::LeastLoaded,
+        AllocStrategyArg::Scheduler => AllocStrategy::Scheduler,
+    };
+    let res = ctl_client.deploy_service(spec, rd, alloc_strategy).await?;
+    print_deploy_res(&res);
+    Ok(())
+}
+
+fn print_deploy_res(res: &DeployServiceRes) {
+    println!(
+        "Successfully deployed service #{} (revision {})",
+        res.deployment_id, res.revision_id
+    );
+    if !res.pending.is_empty() {
+        println!(
+            "{} instances are pending, waiting for enough capacity",
+            res.pending.len()
+        );
+    }
+    if let Some(rollout) = res.rollout {
+        println!(
+            "Rollout is {:?} ({} instances queued)",
+            rollout.state, rollout.queued
+        );
     }
 }
 
+fn print_revisions_table(revisions: Vec<RevisionInfo>) {
+    #[derive(Tabled)]
+    pub struct RevisionTable {
+        revision: RevisionId,
+        deployment: DeploymentId,
+        image: String,
+        concurrency: u32,
+        created_at: String,
+    }
+
+    let revisions: Vec<_> = revisions
+        .into_iter()
+        .map(|r| RevisionTable {
+            revision: r.revision_id,
+            deployment: r.deployment_id,
+            image: r.service_spec.image.0,
+            concurrency: r.service_spec.concurrency,
+            created_at: r.created_at.to_string(),
+        })
+        .collect();
+    let table = Table::new(revisions).to_string();
+    println!("{table}");
+}
+
+fn print_services_table(services: Vec<ServiceSummary>) {
+    #[derive(Tabled)]
+    pub struct ServiceTable {
+        service: ServiceId,
+        image: String,
+        deployments: usize,
+        running: usize,
+        degraded: bool,
+        created_at: String,
+    }
+
+    let services: Vec<_> = services
+        .into_iter()
+        .map(|s| ServiceTable {
+            service: s.service_id,
+            image: s.service_spec.image.0,
+            deployments: s.deployments,
+            running: s.running_instances,
+            degraded: s.degraded,
+            created_at: s.created_at.to_string(),
+        })
+        .collect();
+    let table = Table::new(services).to_string();
+    println!("{table}");
+}
+
+fn print_deployments_table(deployments: Vec<DeploymentInfo>) {
+    #[derive(Tabled)]
+    pub struct InstanceTable {
+        deployment: DeploymentId,
+        deployment_state: String,
+        instance: InstanceId,
+        worker: String,
+        state: String,
+        updated_at: String,
+        anomalies: usize,
+    }
+
+    let instances: Vec<_> = deployments
+        .into_iter()
+        .flat_map(|d| {
+            d.instances
+                .into_iter()
+                .map(move |(instance, i)| InstanceTable {
+                    deployment: d.deployment_id,
+                    deployment_state: format!("{:?}", d.state),
+                    instance,
+                    worker: i
+                        .worker_addr
+                        .map_or_else(|| "-".to_owned(), |addr| addr.to_string()),
+                    state: format!("{:?}", i.state),
+                    updated_at: i.updated_at.to_string(),
+                    anomalies: i.anomalies.len(),
+                })
+        })
+        .collect();
+    let table = Table::new(instances).to_string();
+    println!("{table}");
+}
+
+fn print_instance_states_table(instances: HashMap<InstanceId, InstanceState>) {
+    #[derive(Tabled)]
+    pub struct InstanceTable {
+        instance: InstanceId,
+        state: String,
+    }
+
+    let instances: Vec<_> = instances
+        .into_iter()
+        .map(|(instance, state)| InstanceTable {
+            instance,
+            state: format!("{state:?}"),
+        })
+        .collect();
+    let table = Table::new(instances).to_string();
+    println!("{table}");
+}
+
 fn print_table(addrs: Vec<IpAddr>) {
     #[derive(Tabled)]
     pub struct WorkerTable {
@@ -1,6 +1,13 @@
-use std::time::Duration;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use clap::Parser;
+use eyre::Context as _;
+use proto::well_known::{CTL_BALANCER_PORT, CTL_HTTP_PORT};
+
+use crate::{
+    cluster::NodeId,
+    deployer::alloc::scheduler::{FilterKind, ScorerKind},
+};
 
 #[derive(Debug, Parser)]
 pub struct CtlArgs {
@@ -18,6 +25,147 @@ pub struct CtlArgs {
         value_parser = parse_duration
     )]
     pub worker_liveness_timeout: Duration,
+
+    /// Directory in which the controller's state is stored, so that it's
+    /// restored after a restart. If absent, the state is kept in memory only.
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Port in which the controller's HTTP API is served.
+    #[arg(long, default_value_t = CTL_HTTP_PORT)]
+    pub http_port: u16,
+
+    /// Port in which the controller's balancer is served.
+    #[arg(long, default_value_t = CTL_BALANCER_PORT)]
+    pub balancer_port: u16,
+
+    /// This controller's id among `--peers`.
+    #[arg(long)]
+    pub node_id: Option<NodeId>,
+
+    /// Every controller of a replicated control plane (including this one), as
+    /// a comma-separated list of `<id>=<ip>:<port>`, where the address is the
+    /// one in which each controller replicates its state.
+    ///
+    /// Only the elected leader runs the control plane, and another controller
+    /// takes over once it fails. If absent, the controller runs alone.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_peer,
+        requires_all = ["node_id", "data_dir"]
+    )]
+    pub peers: Vec<(NodeId, SocketAddr)>,
+
+    /// Filter plugins used by the scheduler to remove the workers in which an
+    /// instance can't be placed. The scheduler places the instances of
+    /// deployments which don't request a specific allocation strategy.
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "capacity")]
+    pub scheduler_filters: Vec<FilterKind>,
+
+    /// Scorer plugins used by the scheduler to rank the remaining workers. The
+    /// instance is placed into the worker with the greatest total score.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "spread,least-cpu-usage"
+    )]
+    pub scheduler_scorers: Vec<ScorerKind>,
+
+    /// Whether to periodically move instances from the most loaded workers
+    /// into the least loaded ones, e.g., after new workers join the cluster.
+    #[arg(long)]
+    pub rebalance: bool,
+
+    /// Interval between rebalancing passes.
+    ///
+    /// Time in seconds.
+    #[arg(
+        long,
+        default_value = "30",
+        value_parser = parse_duration
+    )]
+    pub rebalance_interval: Duration,
+
+    /// The maximum amount of instances moved in each rebalancing interval.
+    #[arg(long, default_value = "2")]
+    pub rebalance_max_moves: u32,
+
+    /// Interval between autoscaling passes, in which services with an
+    /// autoscaling policy are scaled according to their load.
+    ///
+    /// Time in seconds.
+    #[arg(
+        long,
+        default_value = "15",
+        value_parser = parse_duration
+    )]
+    pub autoscale_interval: Duration,
+
+    /// The minimum time between two scaling operations of the same service.
+    ///
+    /// Time in seconds.
+    #[arg(
+        long,
+        default_value = "60",
+        value_parser = parse_duration
+    )]
+    pub autoscale_cooldown: Duration,
+
+    /// Interval between reconciliation passes, which converge the cluster to
+    /// the desired state of each service (e.g., restarting missing instances).
+    ///
+    /// Time in seconds.
+    #[arg(
+        long,
+        default_value = "30",
+        value_parser = parse_duration
+    )]
+    pub reconcile_interval: Duration,
+
+    /// How long an instance may be deploying before it's given up as failed to
+    /// start (e.g., its worker crashed without reporting), and replaced by a
+    /// new instance.
+    ///
+    /// Time in seconds.
+    #[arg(
+        long,
+        default_value = "120",
+        value_parser = parse_duration
+    )]
+    pub deploying_timeout: Duration,
+
+    /// How long to wait for the deploy outcome of an instance which was
+    /// terminated while deploying, before giving it up as never started.
+    ///
+    /// Time in seconds.
+    #[arg(
+        long,
+        default_value = "120",
+        value_parser = parse_duration
+    )]
+    pub pre_terminating_timeout: Duration,
+
+    /// How long an instance may be terminating before the termination attempt
+    /// is considered failed.
+    ///
+    /// Time in seconds.
+    #[arg(
+        long,
+        default_value = "60",
+        value_parser = parse_duration
+    )]
+    pub terminating_timeout: Duration,
+}
+
+fn parse_peer(arg: &str) -> eyre::Result<(NodeId, SocketAddr)> {
+    let (id, addr) = arg
+        .split_once('=')
+        .ok_or_else(|| eyre::eyre!("expected `<id>=<ip>:<port>`"))?;
+    let id = id.parse().wrap_err("invalid node id")?;
+    let addr = addr.parse().wrap_err("invalid address")?;
+    Ok((id, addr))
 }
 
 fn parse_duration(arg: &str) -> eyre::Result<Duration> {
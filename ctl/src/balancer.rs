@@ -1,12 +1,13 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
+    pin::pin,
     str::FromStr as _,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use axum::{
@@ -18,30 +19,52 @@ use axum::{
     },
     response::IntoResponse,
 };
+use futures_util::StreamExt as _;
 use hyper_util::{
     client::legacy::{connect::HttpConnector, Client},
     rt::TokioExecutor,
 };
 use proto::{
     common::{instance::InstanceId, service::ServiceId},
+    ctl::deployer::DeploymentId,
     well_known::{PROXY_FORWARDED_HEADER_NAME, PROXY_INSTANCE_HEADER_NAME, WORKER_PROXY_PORT},
 };
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        Notify,
+    },
+    time,
+};
 use tracing::{instrument, trace, warn};
-use utils::http::{self, OptionExt as _, ResultExt as _};
+use utils::http::{self, ResultExt as _};
+
+use crate::{deployer::DeployerHandle, worker_mgr::WorkerEvent};
+
+/// How long a request may wait for a service with no instances to start.
+const COLD_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct ProxyState {
+    pub balancer: BalancerState,
+    pub deployer: DeployerHandle,
+}
 
 #[instrument(skip_all)]
 pub async fn proxy(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(balancer): State<BalancerState>,
+    State(ProxyState { balancer, deployer }): State<ProxyState>,
     mut req: Request,
 ) -> http::Result<impl IntoResponse> {
     let service_id = extract_service_id(&mut req)?;
     trace!(%service_id, "got request");
 
-    let (instance_id, server_addr) = balancer
-        .next(&service_id)
-        .or_http_error(StatusCode::NOT_FOUND, "service not found")?;
+    let (deployment_id, instance_id, server_addr) = match balancer.next(&service_id) {
+        Some(instance) => instance,
+        None => cold_start(&balancer, &deployer, &service_id).await?,
+    };
     trace!(%service_id, %instance_id, %server_addr, "received and balanced user request");
+    let in_flight = balancer.track(&service_id, deployment_id);
 
     *req.uri_mut() = {
         let uri = req.uri();
@@ -60,11 +83,50 @@ pub async fn proxy(
         HeaderValue::from_str(&addr.ip().to_string()).unwrap(),
     );
 
-    balancer
+    let res = balancer
         .client
         .request(req)
         .await
-        .http_error(StatusCode::BAD_GATEWAY, "bad gateway")
+        .http_error(StatusCode::BAD_GATEWAY, "bad gateway")?;
+    // The request is in-flight until its response body is fully streamed.
+    Ok(res.map(|body| {
+        let stream = Body::new(body).into_data_stream().map(move |chunk| {
+            let _in_flight = &in_flight;
+            chunk
+        });
+        Body::from_stream(stream)
+    }))
+}
+
+/// Asks the deployer to start an instance of a service which has none (e.g.,
+/// because it was scaled to zero), and waits until it's added to the balancer.
+///
+/// Only services which may scale to zero are woken up.
+async fn cold_start(
+    balancer: &BalancerState,
+    deployer: &DeployerHandle,
+    service_id: &ServiceId,
+) -> http::Result<(DeploymentId, InstanceId, IpAddr)> {
+    if !balancer.scale_to_zero.lock().unwrap().contains(service_id) {
+        return Err(http::Error::public(
+            StatusCode::NOT_FOUND,
+            "service not found",
+        ));
+    }
+    let deadline = time::Instant::now() + COLD_START_TIMEOUT;
+    // Created before waking the service, so that no addition is missed.
+    let mut added = pin!(balancer.added.notified());
+    deployer.wake_service(service_id.clone()).await?;
+    loop {
+        if let Some(instance) = balancer.next(service_id) {
+            return Ok(instance);
+        }
+        if time::timeout_at(deadline, added.as_mut()).await.is_err() {
+            let msg = "timed out waiting for service to start";
+            return Err(http::Error::public(StatusCode::SERVICE_UNAVAILABLE, msg));
+        }
+        added.set(balancer.added.notified());
+    }
 }
 
 fn extract_service_id(req: &mut Request) -> http::Result<ServiceId> {
@@ -82,15 +144,109 @@ fn extract_service_id(req: &mut Request) -> http::Result<ServiceId> {
     Ok(ServiceId(service_id.to_string()))
 }
 
+/// The instances of a service, grouped by deployment.
 #[derive(Default)]
 pub struct InstanceBag {
+    pub groups: Vec<InstanceGroup>,
+    pub count: AtomicUsize,
+}
+
+pub struct InstanceGroup {
+    pub deployment_id: DeploymentId,
+    /// The percentage of the service's traffic which is sent to this group.
+    ///
+    /// Unweighted groups share the traffic which wasn't claimed by weighted
+    /// groups, proportionally to their number of instances.
+    pub weight: Option<u32>,
     pub instances: Vec<(InstanceId, IpAddr)>,
     pub count: AtomicUsize,
 }
 
+impl InstanceGroup {
+    fn new(deployment_id: DeploymentId) -> Self {
+        InstanceGroup {
+            deployment_id,
+            weight: None,
+            instances: Vec::new(),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    fn next(&self) -> (DeploymentId, InstanceId, IpAddr) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+        let (instance_id, addr) = self.instances[count % self.instances.len()];
+        (self.deployment_id, instance_id, addr)
+    }
+}
+
+impl InstanceBag {
+    fn group_mut(&mut self, deployment_id: DeploymentId) -> &mut InstanceGroup {
+        let pos = self
+            .groups
+            .iter()
+            .position(|g| g.deployment_id == deployment_id);
+        let i = pos.unwrap_or_else(|| {
+            self.groups.push(InstanceGroup::new(deployment_id));
+            self.groups.len() - 1
+        });
+        &mut self.groups[i]
+    }
+
+    fn next(&self) -> Option<(DeploymentId, InstanceId, IpAddr)> {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+
+        // Weighted groups claim consecutive ranges of a 100-slot window.
+        let slot = count % 100;
+        let mut claimed = 0;
+        for group in &self.groups {
+            let Some(weight) = group.weight else {
+                continue;
+            };
+            if slot < claimed + weight as usize && !group.instances.is_empty() {
+                return Some(group.next());
+            }
+            claimed += weight as usize;
+        }
+
+        // The remaining traffic is balanced through all unweighted instances.
+        let unweighted = || self.groups.iter().filter(|g| g.weight.is_none());
+        let len: usize = unweighted().map(|g| g.instances.len()).sum();
+        if len > 0 {
+            let mut i = count % len;
+            for group in unweighted() {
+                if i < group.instances.len() {
+                    let (instance_id, addr) = group.instances[i];
+                    return Some((group.deployment_id, instance_id, addr));
+                }
+                i -= group.instances.len();
+            }
+        }
+
+        // If there are only (possibly empty) weighted groups, fall back to
+        // any group with instances.
+        let group = self.groups.iter().find(|g| !g.instances.is_empty())?;
+        Some(group.next())
+    }
+}
+
+/// The requests received by a service.
+pub struct Traffic {
+    /// The amount of requests which are being handled, for each deployment of
+    /// the service.
+    in_flight: HashMap<DeploymentId, Arc<AtomicUsize>>,
+    last_request: Instant,
+}
+
+type TrafficMap = Arc<Mutex<HashMap<ServiceId, Traffic>>>;
+
 #[derive(Clone)]
 pub struct BalancerState {
     pub addrs: Arc<Mutex<HashMap<ServiceId, InstanceBag>>>,
+    pub traffic: TrafficMap,
+    /// Services which may be scaled to zero, hence are woken up by requests.
+    pub scale_to_zero: Arc<Mutex<HashSet<ServiceId>>>,
+    /// Notified whenever instances are added.
+    pub added: Arc<Notify>,
     pub client: Client<HttpConnector, Body>,
 }
 
@@ -98,40 +254,103 @@ impl BalancerState {
     #[must_use]
     pub fn new() -> (Self, BalancerHandle) {
         let addrs = Arc::new(Mutex::new(HashMap::default()));
+        let traffic = TrafficMap::default();
+        let scale_to_zero = Arc::new(Mutex::new(HashSet::new()));
+        let added = Arc::new(Notify::new());
         let state = BalancerState {
             addrs: addrs.clone(),
+            traffic: traffic.clone(),
+            scale_to_zero: scale_to_zero.clone(),
+            added: added.clone(),
             client: {
                 let mut connector = HttpConnector::new();
-                connector.set_keepalive(Some(Duration::from_secs(60)));
+                connector.set_keepalive(Some(Duration::from_mins(1)));
                 connector.set_nodelay(true);
                 Client::builder(TokioExecutor::new()).build::<_, Body>(connector)
             },
         };
-        let handle = BalancerHandle { addrs };
+        let handle = BalancerHandle {
+            addrs,
+            traffic,
+            scale_to_zero,
+            added,
+        };
         (state, handle)
     }
 
-    pub fn next(&self, service: &ServiceId) -> Option<(InstanceId, IpAddr)> {
+    pub fn next(&self, service: &ServiceId) -> Option<(DeploymentId, InstanceId, IpAddr)> {
         let map = self.addrs.lock().unwrap();
-        let bag = map.get(service)?;
-        let count = bag.count.fetch_add(1, Ordering::Relaxed);
-        Some(bag.instances[count % bag.instances.len()])
+        map.get(service)?.next()
+    }
+
+    /// Counts a request to the given deployment of a service as in-flight
+    /// until the returned guard is dropped.
+    fn track(&self, service: &ServiceId, deployment_id: DeploymentId) -> InFlightGuard {
+        let mut map = self.traffic.lock().unwrap();
+        let traffic = map.entry(service.clone()).or_insert_with(|| Traffic {
+            in_flight: HashMap::new(),
+            last_request: Instant::now(),
+        });
+        traffic.last_request = Instant::now();
+        let in_flight = traffic.in_flight.entry(deployment_id).or_default();
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(in_flight.clone())
+    }
+}
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
+#[derive(Clone)]
 pub struct BalancerHandle {
     pub addrs: Arc<Mutex<HashMap<ServiceId, InstanceBag>>>,
+    pub traffic: TrafficMap,
+    pub scale_to_zero: Arc<Mutex<HashSet<ServiceId>>>,
+    pub added: Arc<Notify>,
 }
 
 impl BalancerHandle {
-    #[allow(dead_code)]
-    pub fn add_instance(&self, id: ServiceId, instance_id: InstanceId, addr: IpAddr) {
+    /// Returns the amount of requests to the given service which are being
+    /// handled, optionally only by the given deployment's instances.
+    pub fn in_flight_requests(&self, id: &ServiceId, deployment_id: Option<DeploymentId>) -> usize {
+        let map = self.traffic.lock().unwrap();
+        let Some(traffic) = map.get(id) else {
+            return 0;
+        };
+        traffic
+            .in_flight
+            .iter()
+            .filter(|(d, _)| deployment_id.is_none_or(|id| id == **d))
+            .map(|(_, in_flight)| in_flight.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns when the given service last received a request, if ever.
+    pub fn last_request(&self, id: &ServiceId) -> Option<Instant> {
+        let map = self.traffic.lock().unwrap();
+        map.get(id).map(|traffic| traffic.last_request)
+    }
+
+    pub fn add_instance(
+        &self,
+        id: ServiceId,
+        deployment_id: DeploymentId,
+        instance_id: InstanceId,
+        addr: IpAddr,
+    ) {
         let mut map = self.addrs.lock().unwrap();
         let bag = map.entry(id).or_default();
-        bag.instances.push((instance_id, addr));
+        bag.group_mut(deployment_id)
+            .instances
+            .push((instance_id, addr));
+        self.added.notify_waiters();
     }
 
-    #[allow(dead_code)]
     pub fn drop_instance(&self, id: &ServiceId, instance_id: InstanceId) {
         let mut map = self.addrs.lock().unwrap();
         let Some(bag) = map.get_mut(id) else {
@@ -139,6 +358,94 @@ impl BalancerHandle {
             return;
         };
         // Remove the instance (keep all except this one)
-        bag.instances.retain(|(inst, _)| inst != &instance_id);
+        for group in &mut bag.groups {
+            group.instances.retain(|(inst, _)| inst != &instance_id);
+        }
+        // Weighted groups are kept until their weight is explicitly unset.
+        bag.groups
+            .retain(|g| !g.instances.is_empty() || g.weight.is_some());
+    }
+
+    /// Removes all instances which live in the given worker.
+    pub fn drop_worker(&self, worker_addr: IpAddr) {
+        let mut map = self.addrs.lock().unwrap();
+        for bag in map.values_mut() {
+            for group in &mut bag.groups {
+                group.instances.retain(|(_, addr)| *addr != worker_addr);
+            }
+            bag.groups
+                .retain(|g| !g.instances.is_empty() || g.weight.is_some());
+        }
+    }
+
+    /// Atomically replaces all instances of the given service by the provided
+    /// deployment's instances.
+    pub fn replace_instances(
+        &self,
+        id: ServiceId,
+        deployment_id: DeploymentId,
+        instances: Vec<(InstanceId, IpAddr)>,
+    ) {
+        let mut map = self.addrs.lock().unwrap();
+        let mut group = InstanceGroup::new(deployment_id);
+        group.instances = instances;
+        map.insert(
+            id,
+            InstanceBag {
+                groups: vec![group],
+                count: AtomicUsize::new(0),
+            },
+        );
+        self.added.notify_waiters();
+    }
+
+    /// Sets whether requests to the given service, once it has no instances,
+    /// must wake it up (see [`cold_start`]).
+    pub fn set_scale_to_zero(&self, id: ServiceId, enabled: bool) {
+        let mut set = self.scale_to_zero.lock().unwrap();
+        if enabled {
+            set.insert(id);
+        } else {
+            set.remove(&id);
+        }
+    }
+
+    /// Forgets everything about a terminated service.
+    pub fn drop_service(&self, id: &ServiceId) {
+        self.addrs.lock().unwrap().remove(id);
+        self.traffic.lock().unwrap().remove(id);
+        self.scale_to_zero.lock().unwrap().remove(id);
+    }
+
+    /// Sets the percentage of the service's traffic which is sent to the
+    /// given deployment's instances.
+    ///
+    /// If `weight` is `None`, the deployment is balanced as usual.
+    pub fn set_weight(&self, id: ServiceId, deployment_id: DeploymentId, weight: Option<u32>) {
+        let mut map = self.addrs.lock().unwrap();
+        let bag = map.entry(id).or_default();
+        bag.group_mut(deployment_id).weight = weight;
+        bag.groups
+            .retain(|g| !g.instances.is_empty() || g.weight.is_some());
+    }
+}
+
+/// Stops routing traffic to workers as soon as they are removed from the pool.
+///
+/// The deployer eventually removes each of the lost instances as well, but
+/// this way the balancer doesn't have to wait for it.
+pub async fn watch_workers(handle: BalancerHandle, mut events: broadcast::Receiver<WorkerEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if let Some(addr) = event.removed_worker() {
+                    trace!(?addr, "dropping instances of removed worker");
+                    handle.drop_worker(addr);
+                }
+            }
+            // Lost events are still handled by the deployer.
+            Err(RecvError::Lagged(n)) => warn!(n, "balancer lagged behind worker events"),
+            Err(RecvError::Closed) => break,
+        }
     }
 }
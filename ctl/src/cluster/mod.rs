@@ -0,0 +1,1081 @@
+//! Replication of the controller's state across multiple controllers.
+//!
+//! Controllers replicate the changes to the store (see [`crate::store`])
+//! through a Raft log. Only the elected leader runs the control plane (i.e.,
+//! the deployer, the worker manager, the balancer and the HTTP APIs). The other
+//! controllers replicate the leader's changes, and one of them takes over once
+//! the leader fails. Workers and clients fail over by trying each controller's
+//! address in turn, as only the leader serves them.
+//!
+//! A leader only announces its leadership once an entry of its own term is
+//! committed, which guarantees that its state holds every committed change.
+//! It steps down, stopping its control plane, once it loses contact with a
+//! majority of the controllers.
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    ops::Range,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use eyre::Context as _;
+use rand::Rng as _;
+use tokio::{
+    runtime, select,
+    sync::{mpsc, oneshot, watch},
+    time::{self, Instant},
+};
+use tracing::{info, instrument, trace, warn};
+
+use crate::{
+    cluster::{
+        rpc::{
+            AppendEntriesReq, AppendEntriesRes, InstallSnapshotReq, InstallSnapshotRes, PeerClient,
+            RequestVoteReq, RequestVoteRes,
+        },
+        storage::{Command, HardState, LogEntry, LogSnapshot, Storage},
+    },
+    store::{self, StoreHandle},
+};
+
+pub mod rpc;
+mod storage;
+
+pub type NodeId = u64;
+
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Election timeouts are randomized within this range (in milliseconds), so
+/// that split votes are unlikely to repeat.
+const ELECTION_TIMEOUT: Range<u64> = 1500..3000;
+
+/// Leaders which haven't heard from a majority within this long step down, as
+/// the others may have elected a new leader meanwhile.
+const QUORUM_TIMEOUT: Duration = Duration::from_millis(ELECTION_TIMEOUT.start);
+
+/// The maximum amount of entries sent to a peer in a single request.
+const MAX_BATCH: usize = 64;
+
+/// The amount of entries after which the applied ones are compacted into a
+/// snapshot.
+const COMPACTION_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    pub node_id: NodeId,
+    /// The replication address of every controller, including this one.
+    pub peers: HashMap<NodeId, SocketAddr>,
+}
+
+/// Published by the node whenever its leadership changes. While leading, holds
+/// the state from which the control plane must be restored.
+pub type Leadership = Option<Arc<store::Snapshot>>;
+
+pub struct Node {
+    rx: mpsc::Receiver<Msg>,
+    /// Changes proposed by the control plane, while this node leads.
+    proposals: mpsc::UnboundedReceiver<store::Entry>,
+    handle: NodeHandle,
+    client: PeerClient,
+    config: ClusterConfig,
+    storage: Storage,
+    hard_state: HardState,
+    role: Role,
+    /// The compacted prefix of the log.
+    snapshot: LogSnapshot,
+    /// The entries which follow the snapshot.
+    log: Vec<LogEntry>,
+    commit_index: u64,
+    last_applied: u64,
+    /// The state resulting from applying every entry up to `last_applied`.
+    state: store::Snapshot,
+    election_deadline: Instant,
+    leadership: watch::Sender<Leadership>,
+}
+
+enum Role {
+    Follower,
+    Candidate { votes: HashSet<NodeId> },
+    Leader(Replication),
+}
+
+/// The leader's view of each peer's replica.
+struct Replication {
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    /// Peers with an outstanding request. These aren't sent other requests
+    /// until it's answered, or found to have failed.
+    in_flight: HashSet<NodeId>,
+    /// When each peer last answered a request of the leader's term.
+    last_ack: HashMap<NodeId, Instant>,
+    last_heartbeat: Instant,
+    /// The index of the leader's first entry of its term.
+    term_start: u64,
+}
+
+impl Node {
+    /// Opens the node's replica of the log, stored in the given directory.
+    ///
+    /// Also returns the handle through which the control plane proposes
+    /// changes, and the receiver of the node's leadership changes.
+    pub fn open(
+        config: ClusterConfig,
+        dir: &Path,
+    ) -> eyre::Result<(Node, NodeHandle, StoreHandle, watch::Receiver<Leadership>)> {
+        let (storage, hard_state, snapshot, log) = Storage::open(dir)?;
+        info!(
+            term = hard_state.term,
+            snapshot_index = snapshot.last_index,
+            entries = log.len(),
+            "opened replicated log"
+        );
+        let (tx, rx) = mpsc::channel(16);
+        let handle = NodeHandle(tx);
+        let (proposals_tx, proposals) = mpsc::unbounded_channel();
+        let (leadership, leadership_rx) = watch::channel(None);
+        let node = Node {
+            rx,
+            proposals,
+            handle: handle.clone(),
+            client: PeerClient::new(),
+            config,
+            storage,
+            hard_state,
+            role: Role::Follower,
+            commit_index: snapshot.last_index,
+            last_applied: snapshot.last_index,
+            state: snapshot.state.clone(),
+            snapshot,
+            log,
+            election_deadline: election_deadline(),
+            leadership,
+        };
+        Ok((node, handle, StoreHandle::new(proposals_tx), leadership_rx))
+    }
+
+    /// Runs the node on a dedicated thread.
+    ///
+    /// The node synchronously persists its log and votes before replying to
+    /// its peers, so that disk writes don't stall the control plane's async
+    /// runtime.
+    pub fn spawn(self) -> eyre::Result<()> {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .wrap_err("failed to build cluster node runtime")?;
+        thread::Builder::new()
+            .name("cluster-node".into())
+            .spawn(move || rt.block_on(self.run()))
+            .wrap_err("failed to spawn cluster node thread")?;
+        Ok(())
+    }
+
+    pub async fn run(mut self) {
+        let mut ticker = time::interval(TICK_INTERVAL);
+        loop {
+            select! {
+                Some(msg) = self.rx.recv() => {
+                    self.handle_msg(msg);
+                }
+                Some(entry) = self.proposals.recv() => {
+                    self.propose(entry);
+                }
+                _ = ticker.tick() => {
+                    self.tick();
+                }
+            }
+        }
+    }
+
+    fn handle_msg(&mut self, msg: Msg) {
+        match msg {
+            Msg::RequestVote(req, reply) => {
+                _ = reply.send(self.handle_request_vote(&req));
+            }
+            Msg::AppendEntries(req, reply) => {
+                _ = reply.send(self.handle_append_entries(req));
+            }
+            Msg::InstallSnapshot(req, reply) => {
+                _ = reply.send(self.handle_install_snapshot(req));
+            }
+            Msg::VoteRes { peer, term, res } => {
+                self.handle_vote_res(peer, term, &res);
+            }
+            Msg::AppendRes { peer, term, res } => {
+                self.handle_append_res(peer, term, &res);
+            }
+            Msg::SnapshotRes {
+                peer,
+                term,
+                last_index,
+                res,
+            } => {
+                self.handle_snapshot_res(peer, term, last_index, &res);
+            }
+            Msg::Unreachable { peer } => {
+                if let Role::Leader(r) = &mut self.role {
+                    r.in_flight.remove(&peer);
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        match &self.role {
+            Role::Leader(r) => {
+                let acks = r
+                    .last_ack
+                    .values()
+                    .filter(|ack| ack.elapsed() < QUORUM_TIMEOUT)
+                    .count();
+                if 1 + acks < self.majority() {
+                    warn!("lost contact with a majority of the controllers");
+                    self.step_down(self.hard_state.term);
+                } else if r.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                    self.broadcast();
+                }
+            }
+            Role::Follower | Role::Candidate { .. } => {
+                if Instant::now() >= self.election_deadline {
+                    self.start_election();
+                }
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    fn propose(&mut self, entry: store::Entry) {
+        if !matches!(self.role, Role::Leader(_)) {
+            warn!("dropping change proposed while not the leader");
+            return;
+        }
+        let entry = LogEntry {
+            term: self.hard_state.term,
+            command: Command::Apply {
+                entry: Box::new(entry),
+            },
+        };
+        self.storage
+            .append(std::slice::from_ref(&entry))
+            .expect("failed to persist log");
+        self.log.push(entry);
+        for peer in self.peer_ids() {
+            self.replicate(peer);
+        }
+        // Single-controller clusters commit right away.
+        self.advance_commit();
+    }
+
+    #[instrument(skip(self))]
+    fn start_election(&mut self) {
+        self.hard_state.term += 1;
+        self.hard_state.voted_for = Some(self.config.node_id);
+        self.save_hard_state();
+        self.election_deadline = election_deadline();
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.config.node_id]),
+        };
+        info!(term = self.hard_state.term, "starting election");
+        if self.majority() == 1 {
+            self.become_leader();
+            return;
+        }
+
+        let req = Arc::new(RequestVoteReq {
+            term: self.hard_state.term,
+            candidate_id: self.config.node_id,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        });
+        for peer in self.peer_ids() {
+            let req = req.clone();
+            self.spawn_rpc(peer, move |client, addr| async move {
+                let res = client.request_vote(addr, &req).await?;
+                let term = req.term;
+                Ok(Msg::VoteRes { peer, term, res })
+            });
+        }
+    }
+
+    fn handle_request_vote(&mut self, req: &RequestVoteReq) -> RequestVoteRes {
+        if req.term > self.hard_state.term {
+            self.step_down(req.term);
+        }
+        // Candidates must hold every committed entry, which is only
+        // guaranteed if their log is at least as up-to-date as the voter's.
+        let up_to_date =
+            (req.last_log_term, req.last_log_index) >= (self.last_term(), self.last_index());
+        let can_vote = self
+            .hard_state
+            .voted_for
+            .is_none_or(|id| id == req.candidate_id);
+        let granted = req.term == self.hard_state.term && can_vote && up_to_date;
+        if granted {
+            self.hard_state.voted_for = Some(req.candidate_id);
+            self.save_hard_state();
+            self.election_deadline = election_deadline();
+        }
+        RequestVoteRes {
+            term: self.hard_state.term,
+            granted,
+        }
+    }
+
+    fn handle_vote_res(&mut self, peer: NodeId, term: u64, res: &RequestVoteRes) {
+        if res.term > self.hard_state.term {
+            self.step_down(res.term);
+            return;
+        }
+        let majority = self.majority();
+        let Role::Candidate { votes } = &mut self.role else {
+            return;
+        };
+        if term != self.hard_state.term || !res.granted {
+            return;
+        }
+        votes.insert(peer);
+        if votes.len() >= majority {
+            self.become_leader();
+        }
+    }
+
+    fn become_leader(&mut self) {
+        let term = self.hard_state.term;
+        info!(term, "elected leader");
+        let noop = LogEntry {
+            term,
+            command: Command::Noop,
+        };
+        self.storage
+            .append(std::slice::from_ref(&noop))
+            .expect("failed to persist log");
+        self.log.push(noop);
+
+        let term_start = self.last_index();
+        let peers = self.peer_ids();
+        self.role = Role::Leader(Replication {
+            next_index: peers.iter().map(|&peer| (peer, term_start)).collect(),
+            match_index: peers.iter().map(|&peer| (peer, 0)).collect(),
+            in_flight: HashSet::new(),
+            last_ack: peers.iter().map(|&peer| (peer, Instant::now())).collect(),
+            last_heartbeat: Instant::now(),
+            term_start,
+        });
+        self.broadcast();
+        self.advance_commit();
+    }
+
+    /// Becomes a follower, adopting the given term if it's newer.
+    fn step_down(&mut self, term: u64) {
+        if term > self.hard_state.term {
+            self.hard_state = HardState {
+                term,
+                voted_for: None,
+            };
+            self.save_hard_state();
+        }
+        if let Role::Leader(_) = self.role {
+            warn!(term, "stepping down as leader");
+            self.leadership.send_replace(None);
+        }
+        self.role = Role::Follower;
+        self.election_deadline = election_deadline();
+    }
+
+    /// Sends the entries (or heartbeats) which each peer is missing.
+    fn broadcast(&mut self) {
+        if let Role::Leader(r) = &mut self.role {
+            r.last_heartbeat = Instant::now();
+        }
+        for peer in self.peer_ids() {
+            self.replicate(peer);
+        }
+    }
+
+    fn replicate(&mut self, peer: NodeId) {
+        let Role::Leader(r) = &mut self.role else {
+            return;
+        };
+        if !r.in_flight.insert(peer) {
+            return;
+        }
+        let next = r.next_index[&peer];
+        let term = self.hard_state.term;
+        let leader_id = self.config.node_id;
+
+        // Peers which lag behind the snapshot must install it.
+        if next <= self.snapshot.last_index {
+            let last_index = self.snapshot.last_index;
+            let req = InstallSnapshotReq {
+                term,
+                leader_id,
+                snapshot: self.snapshot.clone(),
+            };
+            self.spawn_rpc(peer, move |client, addr| async move {
+                let res = client.install_snapshot(addr, &req).await?;
+                Ok(Msg::SnapshotRes {
+                    peer,
+                    term,
+                    last_index,
+                    res,
+                })
+            });
+            return;
+        }
+
+        let prev_log_index = next - 1;
+        let entries = self.log[self.offset(next)..]
+            .iter()
+            .take(MAX_BATCH)
+            .cloned()
+            .collect();
+        let req = AppendEntriesReq {
+            term,
+            leader_id,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap(),
+            entries,
+            leader_commit: self.commit_index,
+        };
+        self.spawn_rpc(peer, move |client, addr| async move {
+            let res = client.append_entries(addr, &req).await?;
+            Ok(Msg::AppendRes { peer, term, res })
+        });
+    }
+
+    fn handle_append_entries(&mut self, req: AppendEntriesReq) -> AppendEntriesRes {
+        if req.term < self.hard_state.term {
+            return AppendEntriesRes {
+                term: self.hard_state.term,
+                success: false,
+                index: 0,
+            };
+        }
+        if req.term > self.hard_state.term || !matches!(self.role, Role::Follower) {
+            self.step_down(req.term);
+        }
+        self.election_deadline = election_deadline();
+        let term = self.hard_state.term;
+
+        // Entries up to the snapshot are committed, hence match the leader's.
+        let prev_matches = req.prev_log_index < self.snapshot.last_index
+            || self.term_at(req.prev_log_index) == Some(req.prev_log_term);
+        if !prev_matches {
+            // Committed entries are known to match, so the leader may resume
+            // right after them.
+            return AppendEntriesRes {
+                term,
+                success: false,
+                index: self.commit_index + 1,
+            };
+        }
+
+        let before = self.log.len();
+        let mut truncated = false;
+        let mut index = req.prev_log_index;
+        for entry in req.entries {
+            index += 1;
+            if index <= self.snapshot.last_index {
+                continue;
+            }
+            match self.term_at(index) {
+                Some(existing) if existing == entry.term => continue,
+                Some(_) => {
+                    warn!(index, "truncating conflicting entries");
+                    let offset = self.offset(index);
+                    self.log.truncate(offset);
+                    truncated = true;
+                }
+                None => (),
+            }
+            self.log.push(entry);
+        }
+        if truncated {
+            self.storage.rewrite(&self.log)
+        } else {
+            self.storage.append(&self.log[before..])
+        }
+        .expect("failed to persist log");
+
+        let commit = req.leader_commit.min(index);
+        if commit > self.commit_index {
+            self.commit_index = commit;
+            self.apply();
+        }
+        AppendEntriesRes {
+            term,
+            success: true,
+            index,
+        }
+    }
+
+    fn handle_append_res(&mut self, peer: NodeId, term: u64, res: &AppendEntriesRes) {
+        if res.term > self.hard_state.term {
+            self.step_down(res.term);
+            return;
+        }
+        let Role::Leader(r) = &mut self.role else {
+            return;
+        };
+        r.in_flight.remove(&peer);
+        if term != self.hard_state.term {
+            return;
+        }
+        r.last_ack.insert(peer, Instant::now());
+        let next = r.next_index.get_mut(&peer).unwrap();
+        if res.success {
+            let matched = r.match_index.get_mut(&peer).unwrap();
+            *matched = (*matched).max(res.index);
+            *next = *matched + 1;
+            self.advance_commit();
+        } else {
+            *next = res.index.min(*next - 1).max(1);
+        }
+        self.replicate_if_behind(peer);
+    }
+
+    fn handle_install_snapshot(&mut self, req: InstallSnapshotReq) -> InstallSnapshotRes {
+        if req.term < self.hard_state.term {
+            return InstallSnapshotRes {
+                term: self.hard_state.term,
+            };
+        }
+        if req.term > self.hard_state.term || !matches!(self.role, Role::Follower) {
+            self.step_down(req.term);
+        }
+        self.election_deadline = election_deadline();
+
+        let snapshot = req.snapshot;
+        if snapshot.last_index > self.commit_index {
+            info!(
+                last_index = snapshot.last_index,
+                "installing leader's snapshot"
+            );
+            // Entries which follow the snapshot are kept if they match.
+            if self.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+                let offset = self.offset(snapshot.last_index);
+                self.log.drain(..=offset);
+            } else {
+                self.log.clear();
+            }
+            self.state = snapshot.state.clone();
+            self.commit_index = snapshot.last_index;
+            self.last_applied = snapshot.last_index;
+            self.snapshot = snapshot;
+            self.storage
+                .save_snapshot(&self.snapshot, &self.log)
+                .expect("failed to persist snapshot");
+        }
+        InstallSnapshotRes {
+            term: self.hard_state.term,
+        }
+    }
+
+    fn handle_snapshot_res(
+        &mut self,
+        peer: NodeId,
+        term: u64,
+        last_index: u64,
+        res: &InstallSnapshotRes,
+    ) {
+        if res.term > self.hard_state.term {
+            self.step_down(res.term);
+            return;
+        }
+        let Role::Leader(r) = &mut self.role else {
+            return;
+        };
+        r.in_flight.remove(&peer);
+        if term != self.hard_state.term {
+            return;
+        }
+        r.last_ack.insert(peer, Instant::now());
+        let matched = r.match_index.get_mut(&peer).unwrap();
+        *matched = (*matched).max(last_index);
+        r.next_index.insert(peer, *matched + 1);
+        self.advance_commit();
+        self.replicate_if_behind(peer);
+    }
+
+    fn replicate_if_behind(&mut self, peer: NodeId) {
+        let Role::Leader(r) = &self.role else {
+            return;
+        };
+        if r.next_index[&peer] <= self.last_index() {
+            self.replicate(peer);
+        }
+    }
+
+    /// Commits the entries which were replicated by a majority.
+    fn advance_commit(&mut self) {
+        let Role::Leader(r) = &self.role else {
+            return;
+        };
+        let majority = self.majority();
+        let mut commit = self.commit_index;
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            // Entries of previous terms are only committed along with an
+            // entry of the current term.
+            if self.term_at(index) != Some(self.hard_state.term) {
+                break;
+            }
+            let replicas = 1 + r.match_index.values().filter(|&&m| m >= index).count();
+            if replicas >= majority {
+                commit = index;
+                break;
+            }
+        }
+        if commit > self.commit_index {
+            self.commit_index = commit;
+            self.apply();
+        }
+    }
+
+    /// Applies the committed entries to the node's state.
+    fn apply(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let command = self.log[self.offset(self.last_applied)].command.clone();
+            match command {
+                Command::Noop => (),
+                Command::Apply { entry } => self.state.apply(*entry),
+            }
+        }
+        if let Role::Leader(r) = &self.role {
+            if self.last_applied >= r.term_start && self.leadership.borrow().is_none() {
+                info!(term = self.hard_state.term, "announcing leadership");
+                self.leadership
+                    .send_replace(Some(Arc::new(self.state.clone())));
+            }
+        }
+        self.maybe_compact();
+    }
+
+    fn maybe_compact(&mut self) {
+        if self.log.len() < COMPACTION_THRESHOLD || self.last_applied <= self.snapshot.last_index {
+            return;
+        }
+        trace!(last_applied = self.last_applied, "compacting log");
+        let last_term = self.term_at(self.last_applied).unwrap();
+        let offset = self.offset(self.last_applied);
+        self.log.drain(..=offset);
+        self.snapshot = LogSnapshot {
+            last_index: self.last_applied,
+            last_term,
+            state: self.state.clone(),
+        };
+        self.storage
+            .save_snapshot(&self.snapshot, &self.log)
+            .expect("failed to persist snapshot");
+    }
+
+    fn save_hard_state(&self) {
+        self.storage
+            .save_hard_state(&self.hard_state)
+            .expect("failed to persist hard state");
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap()
+    }
+
+    /// Returns the term of the entry at the given index, if it's known.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        let offset = index.checked_sub(self.snapshot.last_index + 1)?;
+        let offset = usize::try_from(offset).ok()?;
+        self.log.get(offset).map(|entry| entry.term)
+    }
+
+    /// Returns the position in `self.log` of the entry at the given index,
+    /// which must follow the snapshot.
+    fn offset(&self, index: u64) -> usize {
+        usize::try_from(index - self.snapshot.last_index - 1).unwrap()
+    }
+
+    fn majority(&self) -> usize {
+        self.config.peers.len() / 2 + 1
+    }
+
+    fn peer_ids(&self) -> Vec<NodeId> {
+        let id = self.config.node_id;
+        self.config
+            .peers
+            .keys()
+            .copied()
+            .filter(|&p| p != id)
+            .collect()
+    }
+
+    /// Spawns a task which sends a request to the given peer.
+    ///
+    /// The message returned by the function is sent to the node. If it fails,
+    /// the peer is reported as unreachable.
+    fn spawn_rpc<F, Fut>(&self, peer: NodeId, f: F)
+    where
+        F: FnOnce(PeerClient, SocketAddr) -> Fut + Send + 'static,
+        Fut: Future<Output = eyre::Result<Msg>> + Send,
+    {
+        let addr = self.config.peers[&peer];
+        let client = self.client.clone();
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            let msg = f(client, addr).await.unwrap_or_else(|error| {
+                trace!(?error, peer, "peer is unreachable");
+                Msg::Unreachable { peer }
+            });
+            handle.send(msg).await;
+        });
+    }
+}
+
+fn election_deadline() -> Instant {
+    let timeout = rand::thread_rng().gen_range(ELECTION_TIMEOUT);
+    Instant::now() + Duration::from_millis(timeout)
+}
+
+#[derive(Clone)]
+pub struct NodeHandle(mpsc::Sender<Msg>);
+
+impl NodeHandle {
+    async fn send(&self, msg: Msg) {
+        _ = self.0.send(msg).await;
+    }
+
+    /// Sends a message and waits for a reply.
+    async fn send_wait<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(oneshot::Sender<R>) -> Msg,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.send(f(tx)).await;
+        rx.await.expect("actor must be alive")
+    }
+
+    pub async fn request_vote(&self, req: RequestVoteReq) -> RequestVoteRes {
+        self.send_wait(|r| Msg::RequestVote(req, r)).await
+    }
+
+    pub async fn append_entries(&self, req: AppendEntriesReq) -> AppendEntriesRes {
+        self.send_wait(|r| Msg::AppendEntries(req, r)).await
+    }
+
+    pub async fn install_snapshot(&self, req: InstallSnapshotReq) -> InstallSnapshotRes {
+        self.send_wait(|r| Msg::InstallSnapshot(req, r)).await
+    }
+}
+
+#[derive(Debug)]
+enum Msg {
+    RequestVote(RequestVoteReq, oneshot::Sender<RequestVoteRes>),
+    AppendEntries(AppendEntriesReq, oneshot::Sender<AppendEntriesRes>),
+    InstallSnapshot(InstallSnapshotReq, oneshot::Sender<InstallSnapshotRes>),
+    // Internal messages
+    VoteRes {
+        peer: NodeId,
+        /// The term in which the request was sent.
+        term: u64,
+        res: RequestVoteRes,
+    },
+    AppendRes {
+        peer: NodeId,
+        term: u64,
+        res: AppendEntriesRes,
+    },
+    SnapshotRes {
+        peer: NodeId,
+        term: u64,
+        last_index: u64,
+        res: InstallSnapshotRes,
+    },
+    Unreachable {
+        peer: NodeId,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        path::PathBuf,
+    };
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::cluster::rpc::LocalNetwork;
+
+    /// A data directory which is removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            TempDir(std::env::temp_dir().join(format!("tucano-cluster-{}", Uuid::now_v7())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn addr(id: NodeId) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 9000 + u16::try_from(id).unwrap()))
+    }
+
+    /// Opens a node of a cluster with the given amount of controllers, which
+    /// are reachable through the given network.
+    fn open(
+        id: NodeId,
+        size: u64,
+        network: &LocalNetwork,
+        dir: &TempDir,
+    ) -> (Node, StoreHandle, watch::Receiver<Leadership>) {
+        let config = ClusterConfig {
+            node_id: id,
+            peers: (1..=size).map(|peer| (peer, addr(peer))).collect(),
+        };
+        let (mut node, handle, store, leadership) = Node::open(config, &dir.0).unwrap();
+        node.client = network.client(addr(id));
+        network.connect(addr(id), handle);
+        (node, store, leadership)
+    }
+
+    fn worker_joined(n: u8) -> store::Entry {
+        store::Entry::WorkerJoined {
+            addr: Ipv4Addr::new(10, 0, 0, n).into(),
+            labels: HashMap::new(),
+        }
+    }
+
+    fn apply(term: u64, n: u8) -> LogEntry {
+        LogEntry {
+            term,
+            command: Command::Apply {
+                entry: Box::new(worker_joined(n)),
+            },
+        }
+    }
+
+    fn terms(node: &Node) -> Vec<u64> {
+        node.log.iter().map(|e| e.term).collect()
+    }
+
+    fn append(
+        term: u64,
+        prev: (u64, u64),
+        entries: Vec<LogEntry>,
+        commit: u64,
+    ) -> AppendEntriesReq {
+        AppendEntriesReq {
+            term,
+            leader_id: 1,
+            prev_log_index: prev.0,
+            prev_log_term: prev.1,
+            entries,
+            leader_commit: commit,
+        }
+    }
+
+    #[tokio::test]
+    async fn followers_match_the_leaders_log() {
+        let network = LocalNetwork::default();
+        let dir = TempDir::new();
+        let (mut node, _, _) = open(2, 3, &network, &dir);
+
+        let res = node.handle_append_entries(append(1, (0, 0), vec![apply(1, 1), apply(1, 2)], 1));
+        assert!(res.success);
+        assert_eq!(res.index, 2);
+        assert_eq!(node.commit_index, 1);
+        assert_eq!(node.state.workers.len(), 1);
+
+        // Entries are only accepted if the previous one matches.
+        let res = node.handle_append_entries(append(2, (3, 2), vec![apply(2, 4)], 1));
+        assert!(!res.success);
+        assert_eq!(res.index, 2);
+
+        // Conflicting entries are replaced by the leader's.
+        let res = node.handle_append_entries(append(2, (1, 1), vec![apply(2, 3), apply(2, 4)], 1));
+        assert!(res.success);
+        assert_eq!(res.index, 3);
+        assert_eq!(terms(&node), [1, 2, 2]);
+
+        // Duplicated requests don't truncate the entries which follow them.
+        let res = node.handle_append_entries(append(2, (1, 1), vec![apply(2, 3)], 3));
+        assert!(res.success);
+        assert_eq!(terms(&node), [1, 2, 2]);
+        assert_eq!(node.commit_index, 2);
+
+        // Requests of previous terms are rejected.
+        let res = node.handle_append_entries(append(1, (3, 2), vec![apply(1, 5)], 3));
+        assert!(!res.success);
+        assert_eq!(res.term, 2);
+
+        drop(node);
+        let (node, _, _) = open(2, 3, &network, &dir);
+        assert_eq!(node.hard_state.term, 2);
+        assert_eq!(terms(&node), [1, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn leaders_commit_entries_replicated_by_a_majority() {
+        let network = LocalNetwork::default();
+        let dir = TempDir::new();
+        let (mut node, _, leadership) = open(1, 3, &network, &dir);
+
+        node.start_election();
+        assert!(matches!(node.role, Role::Candidate { .. }));
+        let vote = RequestVoteRes {
+            term: 1,
+            granted: true,
+        };
+        node.handle_vote_res(2, 1, &vote);
+        assert!(matches!(node.role, Role::Leader(_)));
+        // The leader's noop isn't committed until a peer acknowledges it.
+        assert_eq!(node.commit_index, 0);
+        assert!(leadership.borrow().is_none());
+
+        let ack = |index| AppendEntriesRes {
+            term: 1,
+            success: true,
+            index,
+        };
+        node.handle_append_res(2, 1, &ack(1));
+        assert_eq!(node.commit_index, 1);
+        assert!(leadership.borrow().is_some());
+
+        node.propose(worker_joined(1));
+        node.propose(worker_joined(2));
+        assert_eq!(node.last_index(), 3);
+        assert_eq!(node.commit_index, 1);
+
+        // Acknowledgements of stale terms are ignored.
+        node.handle_append_res(3, 0, &ack(3));
+        assert_eq!(node.commit_index, 1);
+
+        node.handle_append_res(3, 1, &ack(2));
+        assert_eq!(node.commit_index, 2);
+        assert_eq!(node.state.workers.len(), 1);
+
+        // A higher term deposes the leader.
+        let res = AppendEntriesRes {
+            term: 2,
+            success: false,
+            index: 0,
+        };
+        node.handle_append_res(2, 1, &res);
+        assert!(matches!(node.role, Role::Follower));
+        assert!(leadership.borrow().is_none());
+    }
+
+    #[tokio::test]
+    async fn lagging_followers_install_the_leaders_snapshot() {
+        let network = LocalNetwork::default();
+        let dir = TempDir::new();
+        let (mut node, _, _) = open(2, 3, &network, &dir);
+        node.handle_append_entries(append(1, (0, 0), vec![apply(1, 1), apply(1, 2)], 0));
+
+        let mut state = store::Snapshot::default();
+        for n in 1..=5 {
+            state.apply(worker_joined(n));
+        }
+        let req = InstallSnapshotReq {
+            term: 2,
+            leader_id: 1,
+            snapshot: LogSnapshot {
+                last_index: 5,
+                last_term: 2,
+                state,
+            },
+        };
+        let res = node.handle_install_snapshot(req);
+        assert_eq!(res.term, 2);
+        assert_eq!(node.commit_index, 5);
+        assert_eq!(node.last_applied, 5);
+        assert_eq!(node.state.workers.len(), 5);
+        // Its entries conflicted with the snapshot, so they're discarded.
+        assert!(node.log.is_empty());
+
+        let res = node.handle_append_entries(append(2, (5, 2), vec![apply(2, 6)], 6));
+        assert!(res.success);
+        assert_eq!(node.state.workers.len(), 6);
+
+        drop(node);
+        let (node, _, _) = open(2, 3, &network, &dir);
+        assert_eq!(node.snapshot.last_index, 5);
+        assert_eq!(node.snapshot.state.workers.len(), 5);
+        assert_eq!(terms(&node), [2]);
+    }
+
+    /// Waits until one of the given nodes announces its leadership.
+    async fn wait_for_leader(
+        leaderships: &HashMap<NodeId, watch::Receiver<Leadership>>,
+    ) -> (NodeId, Arc<store::Snapshot>) {
+        time::timeout(Duration::from_secs(30), async {
+            loop {
+                for (&id, leadership) in leaderships {
+                    if let Some(state) = &*leadership.borrow() {
+                        return (id, state.clone());
+                    }
+                }
+                time::sleep(TICK_INTERVAL).await;
+            }
+        })
+        .await
+        .expect("no leader was elected")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_leader_takes_over_once_leader_fails() {
+        let network = LocalNetwork::default();
+        let dirs: Vec<_> = (0..3).map(|_| TempDir::new()).collect();
+        let mut stores = HashMap::new();
+        let mut leaderships = HashMap::new();
+        for (id, dir) in (1..=3).zip(&dirs) {
+            let (node, store, leadership) = open(id, 3, &network, dir);
+            tokio::spawn(node.run());
+            stores.insert(id, store);
+            leaderships.insert(id, leadership);
+        }
+
+        let (leader, _) = wait_for_leader(&leaderships).await;
+        stores[&leader].append(worker_joined(1));
+        time::sleep(HEARTBEAT_INTERVAL * 4).await;
+
+        // The isolated leader steps down, while the others elect a new one
+        // which holds every committed change.
+        network.isolate(addr(leader));
+        let mut old_leadership = leaderships.remove(&leader).unwrap();
+        time::timeout(
+            Duration::from_secs(30),
+            old_leadership.wait_for(Option::is_none),
+        )
+        .await
+        .expect("isolated leader didn't step down")
+        .unwrap();
+        let (new_leader, state) = wait_for_leader(&leaderships).await;
+        assert_ne!(new_leader, leader);
+        assert_eq!(state.workers.len(), 1);
+
+        // Once it's reachable again, the cluster settles on a single leader.
+        network.heal(addr(leader));
+        leaderships.insert(leader, old_leadership);
+        time::sleep(Duration::from_millis(ELECTION_TIMEOUT.end) * 4).await;
+        let leaders = leaderships.values().filter(|l| l.borrow().is_some());
+        assert_eq!(leaders.count(), 1);
+    }
+}
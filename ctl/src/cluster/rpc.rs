@@ -0,0 +1,239 @@
+//! Messages exchanged between the controllers of a cluster.
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{extract::State, routing::post, Json, Router};
+use eyre::Context as _;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::cluster::{
+    storage::{LogEntry, LogSnapshot},
+    NodeHandle, NodeId,
+};
+
+/// Replication messages are frequent and small, so peers which don't answer
+/// them quickly are considered unreachable.
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+const REQUEST_VOTE: &str = "/cluster/request-vote";
+const APPEND_ENTRIES: &str = "/cluster/append-entries";
+const INSTALL_SNAPSHOT: &str = "/cluster/install-snapshot";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestVoteReq {
+    pub term: u64,
+    pub candidate_id: NodeId,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestVoteRes {
+    pub term: u64,
+    pub granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendEntriesReq {
+    pub term: u64,
+    pub leader_id: NodeId,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendEntriesRes {
+    pub term: u64,
+    pub success: bool,
+    /// On success, the index of the last entry known to match the leader's.
+    /// Otherwise, the index from which the leader should retry.
+    pub index: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstallSnapshotReq {
+    pub term: u64,
+    pub leader_id: NodeId,
+    pub snapshot: LogSnapshot,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstallSnapshotRes {
+    pub term: u64,
+}
+
+pub fn mk_app(node: NodeHandle) -> Router {
+    Router::new()
+        .route(REQUEST_VOTE, post(request_vote))
+        .route(APPEND_ENTRIES, post(append_entries))
+        .route(INSTALL_SNAPSHOT, post(install_snapshot))
+        .with_state(node)
+}
+
+async fn request_vote(
+    State(node): State<NodeHandle>,
+    Json(req): Json<RequestVoteReq>,
+) -> Json<RequestVoteRes> {
+    Json(node.request_vote(req).await)
+}
+
+async fn append_entries(
+    State(node): State<NodeHandle>,
+    Json(req): Json<AppendEntriesReq>,
+) -> Json<AppendEntriesRes> {
+    Json(node.append_entries(req).await)
+}
+
+async fn install_snapshot(
+    State(node): State<NodeHandle>,
+    Json(req): Json<InstallSnapshotReq>,
+) -> Json<InstallSnapshotRes> {
+    Json(node.install_snapshot(req).await)
+}
+
+#[derive(Clone)]
+pub struct PeerClient {
+    transport: Transport,
+}
+
+#[derive(Clone)]
+enum Transport {
+    Http(reqwest::Client),
+    /// Delivers requests to nodes of the same process, sending them from the
+    /// given address.
+    #[cfg(test)]
+    Local(LocalNetwork, SocketAddr),
+}
+
+impl PeerClient {
+    pub fn new() -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(RPC_TIMEOUT)
+            .build()
+            .unwrap();
+        PeerClient {
+            transport: Transport::Http(client),
+        }
+    }
+
+    pub async fn request_vote(
+        &self,
+        peer: SocketAddr,
+        req: &RequestVoteReq,
+    ) -> eyre::Result<RequestVoteRes> {
+        self.send(peer, REQUEST_VOTE, req).await
+    }
+
+    pub async fn append_entries(
+        &self,
+        peer: SocketAddr,
+        req: &AppendEntriesReq,
+    ) -> eyre::Result<AppendEntriesRes> {
+        self.send(peer, APPEND_ENTRIES, req).await
+    }
+
+    pub async fn install_snapshot(
+        &self,
+        peer: SocketAddr,
+        req: &InstallSnapshotReq,
+    ) -> eyre::Result<InstallSnapshotRes> {
+        self.send(peer, INSTALL_SNAPSHOT, req).await
+    }
+
+    async fn send<Req, Res>(&self, peer: SocketAddr, path: &str, body: &Req) -> eyre::Result<Res>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        match &self.transport {
+            Transport::Http(client) => {
+                let res = client
+                    .post(format!("http://{peer}{path}"))
+                    .json(body)
+                    .send()
+                    .await
+                    .wrap_err("failed to send request to peer")?
+                    .error_for_status()
+                    .wrap_err("http request failed")?
+                    .json::<Res>()
+                    .await
+                    .wrap_err("failed to parse response from peer")?;
+                Ok(res)
+            }
+            #[cfg(test)]
+            Transport::Local(network, from) => network.deliver(*from, peer, path, body).await,
+        }
+    }
+}
+
+/// Connects nodes of the same process, as if they were reachable through the
+/// given addresses.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct LocalNetwork(std::sync::Arc<std::sync::Mutex<LocalNetworkInner>>);
+
+#[cfg(test)]
+#[derive(Default)]
+struct LocalNetworkInner {
+    nodes: std::collections::HashMap<SocketAddr, NodeHandle>,
+    /// Nodes which can neither send nor receive requests.
+    isolated: std::collections::HashSet<SocketAddr>,
+}
+
+#[cfg(test)]
+impl LocalNetwork {
+    /// Returns a client which sends requests from the given address.
+    pub fn client(&self, addr: SocketAddr) -> PeerClient {
+        PeerClient {
+            transport: Transport::Local(self.clone(), addr),
+        }
+    }
+
+    pub fn connect(&self, addr: SocketAddr, node: NodeHandle) {
+        self.0.lock().unwrap().nodes.insert(addr, node);
+    }
+
+    pub fn isolate(&self, addr: SocketAddr) {
+        self.0.lock().unwrap().isolated.insert(addr);
+    }
+
+    pub fn heal(&self, addr: SocketAddr) {
+        self.0.lock().unwrap().isolated.remove(&addr);
+    }
+
+    /// Delivers the request, encoded as it would be sent over HTTP.
+    async fn deliver<Req, Res>(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+        path: &str,
+        body: &Req,
+    ) -> eyre::Result<Res>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
+        let node = {
+            let inner = self.0.lock().unwrap();
+            let reachable = !inner.isolated.contains(&from) && !inner.isolated.contains(&to);
+            inner.nodes.get(&to).filter(|_| reachable).cloned()
+        };
+        let node = node.ok_or_else(|| eyre::eyre!("peer is unreachable"))?;
+        let body = serde_json::to_value(body)?;
+        let res = match path {
+            REQUEST_VOTE => {
+                serde_json::to_value(node.request_vote(serde_json::from_value(body)?).await)
+            }
+            APPEND_ENTRIES => {
+                serde_json::to_value(node.append_entries(serde_json::from_value(body)?).await)
+            }
+            INSTALL_SNAPSHOT => {
+                serde_json::to_value(node.install_snapshot(serde_json::from_value(body)?).await)
+            }
+            _ => unreachable!("unknown path {path}"),
+        }?;
+        Ok(serde_json::from_value(res)?)
+    }
+}
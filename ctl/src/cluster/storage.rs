@@ -0,0 +1,308 @@
+//! Durable storage of a controller's replica of the log.
+//!
+//! A replica must never forget its votes nor the entries it has acknowledged,
+//! so these are written to disk before the controller replies to its peers.
+//! Files are only ever replaced by renaming a fully written copy over them, so
+//! that a crash leaves either the old or the new version in place.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead as _, BufReader, ErrorKind, Write as _},
+    path::{Path, PathBuf},
+};
+
+use eyre::Context as _;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{cluster::NodeId, store};
+
+const HARD_STATE_FILE: &str = "raft-state.json";
+const SNAPSHOT_FILE: &str = "raft-snapshot.json";
+const LOG_FILE: &str = "raft-log.jsonl";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Command {
+    /// Appended by each new leader, so that it may commit the entries of the
+    /// previous terms.
+    Noop,
+    Apply {
+        entry: Box<store::Entry>,
+    },
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+}
+
+/// The state resulting from applying every entry up to (and including) the
+/// entry at `last_index`, whose term is `last_term`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LogSnapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub state: store::Snapshot,
+}
+
+/// The first line of the log, identifying the snapshot its entries follow.
+///
+/// The snapshot is replaced before the log, so a crash in between leaves a log
+/// which still follows an older snapshot. Its stale entries are then skipped
+/// when the log is opened.
+#[derive(Debug, Serialize, Deserialize)]
+struct LogHeader {
+    snapshot_index: u64,
+}
+
+pub struct Storage {
+    dir: PathBuf,
+    log: File,
+    /// The index of the snapshot which the log currently follows.
+    snapshot_index: u64,
+}
+
+impl Storage {
+    /// Opens the storage in the given directory, creating it if needed.
+    ///
+    /// Returns the stored hard state, snapshot and the entries that follow the
+    /// snapshot.
+    pub fn open(dir: &Path) -> eyre::Result<(Storage, HardState, LogSnapshot, Vec<LogEntry>)> {
+        fs::create_dir_all(dir).wrap_err("failed to create data directory")?;
+        let hard_state = read_json(&dir.join(HARD_STATE_FILE))?.unwrap_or_default();
+        let snapshot: LogSnapshot = read_json(&dir.join(SNAPSHOT_FILE))?.unwrap_or_default();
+
+        let log = read_log(&dir.join(LOG_FILE))?;
+        let mut storage = Storage {
+            dir: dir.to_owned(),
+            log: open_log(dir)?,
+            snapshot_index: snapshot.last_index,
+        };
+        let Some((header, mut entries)) = log else {
+            storage.rewrite(&[])?;
+            return Ok((storage, hard_state, snapshot, Vec::new()));
+        };
+
+        let stale = snapshot
+            .last_index
+            .checked_sub(header.snapshot_index)
+            .ok_or_else(|| eyre::eyre!("log follows a snapshot newer than the stored one"))?;
+        if stale > 0 {
+            warn!(stale, "skipping log entries covered by the snapshot");
+            // Same as when installing a snapshot, the entries which follow it
+            // are only kept if they match.
+            let stale = usize::try_from(stale)?;
+            if entries.get(stale - 1).map(|e| e.term) == Some(snapshot.last_term) {
+                entries.drain(..stale);
+            } else {
+                entries.clear();
+            }
+            storage.rewrite(&entries)?;
+        }
+        Ok((storage, hard_state, snapshot, entries))
+    }
+
+    pub fn save_hard_state(&self, hard_state: &HardState) -> eyre::Result<()> {
+        write_atomically(&self.dir.join(HARD_STATE_FILE), hard_state)
+    }
+
+    /// Appends the given entries to the end of the log.
+    pub fn append(&mut self, entries: &[LogEntry]) -> eyre::Result<()> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        self.log.write_all(&buf)?;
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the whole log, e.g., after conflicting entries are truncated.
+    pub fn rewrite(&mut self, entries: &[LogEntry]) -> eyre::Result<()> {
+        let header = LogHeader {
+            snapshot_index: self.snapshot_index,
+        };
+        let mut buf = serde_json::to_vec(&header)?;
+        buf.push(b'\n');
+        for entry in entries {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        replace_file(&self.dir.join(LOG_FILE), &buf)?;
+        // The old file was unlinked by the rename.
+        self.log = open_log(&self.dir)?;
+        Ok(())
+    }
+
+    /// Replaces the snapshot, along with the entries that follow it.
+    pub fn save_snapshot(
+        &mut self,
+        snapshot: &LogSnapshot,
+        entries: &[LogEntry],
+    ) -> eyre::Result<()> {
+        write_atomically(&self.dir.join(SNAPSHOT_FILE), snapshot)?;
+        self.snapshot_index = snapshot.last_index;
+        self.rewrite(entries)
+    }
+}
+
+/// Reads the log's header and entries, or `None` if there's no log yet.
+///
+/// Any torn entry at the end of the log is discarded.
+fn read_log(path: &Path) -> eyre::Result<Option<(LogHeader, Vec<LogEntry>)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error).wrap_err("failed to read log"),
+    };
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    // The log may have been created but not yet written.
+    if reader.read_line(&mut line).wrap_err("failed to read log")? == 0 {
+        return Ok(None);
+    }
+    let header = serde_json::from_str(&line).wrap_err("corrupted log header")?;
+
+    let mut entries = Vec::new();
+    let mut len = line.len() as u64;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).wrap_err("failed to read log")? == 0 {
+            break;
+        }
+        // The last entry may have been torn by a crash, in which case it was
+        // never acknowledged.
+        let entry = line
+            .strip_suffix('\n')
+            .and_then(|line| serde_json::from_str(line).ok());
+        let Some(entry) = entry else {
+            warn!("discarding torn log entry");
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(len).wrap_err("failed to truncate log")?;
+            file.sync_data()?;
+            break;
+        };
+        entries.push(entry);
+        len += line.len() as u64;
+    }
+    Ok(Some((header, entries)))
+}
+
+fn open_log(dir: &Path) -> eyre::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOG_FILE))
+        .wrap_err("failed to open log")
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> eyre::Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let value = serde_json::from_slice(&bytes)
+                .wrap_err_with(|| format!("corrupted {}", path.display()))?;
+            Ok(Some(value))
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error).wrap_err_with(|| format!("failed to read {}", path.display())),
+    }
+}
+
+fn write_atomically<T: Serialize>(path: &Path, value: &T) -> eyre::Result<()> {
+    replace_file(path, &serde_json::to_vec(value)?)
+}
+
+/// Replaces the file's contents by renaming a synced copy over it.
+fn replace_file(path: &Path, contents: &[u8]) -> eyre::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp).wrap_err("failed to create file")?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).wrap_err("failed to replace file")?;
+    // Persists the rename itself.
+    let dir = path.parent().expect("file must be within a directory");
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn noop(term: u64) -> LogEntry {
+        LogEntry {
+            term,
+            command: Command::Noop,
+        }
+    }
+
+    fn terms(entries: &[LogEntry]) -> Vec<u64> {
+        entries.iter().map(|e| e.term).collect()
+    }
+
+    #[test]
+    fn reopen_recovers_log_and_snapshot() {
+        let dir = std::env::temp_dir().join(format!("tucano-storage-{}", Uuid::now_v7()));
+
+        let (mut storage, _, _, entries) = Storage::open(&dir).unwrap();
+        assert!(entries.is_empty());
+        let hard_state = HardState {
+            term: 2,
+            voted_for: Some(1),
+        };
+        storage.save_hard_state(&hard_state).unwrap();
+        storage.append(&[noop(1), noop(1), noop(2)]).unwrap();
+        drop(storage);
+
+        // Reopening doesn't lose anything.
+        let (mut storage, hard_state, snapshot, entries) = Storage::open(&dir).unwrap();
+        assert_eq!(hard_state.term, 2);
+        assert_eq!(hard_state.voted_for, Some(1));
+        assert_eq!(snapshot.last_index, 0);
+        assert_eq!(terms(&entries), [1, 1, 2]);
+        storage.append(&[noop(2)]).unwrap();
+        drop(storage);
+
+        // An entry torn by a crash is discarded, and later appends follow the
+        // entries which were intact.
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(br#"{"term":3,"com"#).unwrap();
+        let (mut storage, _, _, entries) = Storage::open(&dir).unwrap();
+        assert_eq!(terms(&entries), [1, 1, 2, 2]);
+        storage.append(&[noop(3)]).unwrap();
+        drop(storage);
+        let (_, _, _, entries) = Storage::open(&dir).unwrap();
+        assert_eq!(terms(&entries), [1, 1, 2, 2, 3]);
+
+        // A crash after the snapshot is replaced but before the log is leaves
+        // stale entries, which are skipped.
+        let snapshot = LogSnapshot {
+            last_index: 3,
+            last_term: 2,
+            state: store::Snapshot::default(),
+        };
+        write_atomically(&dir.join(SNAPSHOT_FILE), &snapshot).unwrap();
+        let (storage, _, snapshot, entries) = Storage::open(&dir).unwrap();
+        assert_eq!(snapshot.last_index, 3);
+        assert_eq!(terms(&entries), [2, 3]);
+        drop(storage);
+        let (_, _, _, entries) = Storage::open(&dir).unwrap();
+        assert_eq!(terms(&entries), [2, 3]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
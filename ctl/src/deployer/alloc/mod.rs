@@ -0,0 +1,99 @@
+//! Worker allocation algorithms.
+//!
+//! Besides the simple allocation functions in this module, resource-aware
+//! allocations are made through the pluggable [`scheduler`].
+
+use std::{
+    net::IpAddr,
+    ops::AddAssign,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use proto::common::{instance::InstanceId, service::ResourceConfig};
+use rand::seq::SliceRandom;
+use uuid::Uuid;
+
+use crate::worker_mgr::WorkerDetails;
+
+pub mod scheduler;
+
+/// Randomly allocates, using an uniform distribution, instances for the give
+/// amount of instances and the provided pool of `workers`.
+#[allow(dead_code)]
+pub fn rand_many(
+    workers: &[WorkerDetails],
+    instances: u32,
+) -> impl Iterator<Item = (InstanceId, IpAddr)> + '_ {
+    let mut rng = rand::thread_rng();
+    (0..instances)
+        // Unwrap is safe since an eventual 0..0 wouldn't yield any iterations.
+        .map(move |_| workers.choose(&mut rng).unwrap())
+        .map(|w| (InstanceId(Uuid::now_v7()), w.addr))
+}
+
+/// Randomly allocates a single instance from the provided pool of `workers`.
+#[allow(dead_code)]
+pub fn rand_single(workers: &[WorkerDetails]) -> (InstanceId, IpAddr) {
+    rand_many(workers, 1).next().unwrap()
+}
+
+pub static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn rr_alloc_many(
+    workers: &[WorkerDetails],
+    instances: u32,
+) -> impl Iterator<Item = (InstanceId, IpAddr)> + '_ {
+    (0..instances)
+        .map(move |_| {
+            let i = COUNTER.fetch_add(1, Ordering::Relaxed);
+            &workers[i % workers.len()]
+        })
+        .map(|w| (InstanceId(Uuid::now_v7()), w.addr))
+}
+
+/// The amount of CPU shares which correspond to a single CPU (following the
+/// Docker convention).
+pub const CPU_SHARES_PER_CPU: u64 = 1024;
+
+/// An amount of resources, in the same units as [`ResourceConfig`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Resources {
+    pub cpu_shares: u64,
+    /// Memory, in bytes.
+    pub memory: u64,
+}
+
+impl Resources {
+    /// Returns the resources requested by the given config. Non-positive
+    /// values are considered unspecified, hence don't reserve any resources.
+    pub fn of(config: &ResourceConfig) -> Self {
+        Resources {
+            cpu_shares: u64::try_from(config.cpu_shares).unwrap_or(0),
+            memory: u64::try_from(config.memory_limit).unwrap_or(0),
+        }
+    }
+
+    /// Returns the total resources of the given worker, as last reported by
+    /// its metrics, or `None` if it hasn't reported any yet.
+    pub fn capacity(worker: &WorkerDetails) -> Option<Self> {
+        let metrics = worker.metrics.as_ref()?;
+        Some(Resources {
+            cpu_shares: u64::from(metrics.cpu_count) * CPU_SHARES_PER_CPU,
+            memory: metrics.mem_total,
+        })
+    }
+
+    fn checked_sub(self, rhs: Resources) -> Option<Resources> {
+        Some(Resources {
+            cpu_shares: self.cpu_shares.checked_sub(rhs.cpu_shares)?,
+            memory: self.memory.checked_sub(rhs.memory)?,
+        })
+    }
+}
+
+impl AddAssign for Resources {
+    fn add_assign(&mut self, rhs: Resources) {
+        self.cpu_shares += rhs.cpu_shares;
+        self.memory += rhs.memory;
+    }
+}
@@ -0,0 +1,394 @@
+//! Pluggable instance scheduler.
+//!
+//! Each instance is placed in two phases. First, [`Filter`] plugins remove the
+//! workers in which the instance can't be placed. Then, [`Scorer`] plugins rank
+//! the remaining workers, and the instance is placed into the worker with the
+//! greatest total score.
+//!
+//! Besides the configured plugins, the service's placement constraints (see
+//! [`Placement`]) are always enforced.
+
+use std::{collections::HashMap, fmt, net::IpAddr};
+
+use clap::ValueEnum;
+use proto::{
+    common::{
+        instance::InstanceId,
+        service::{AntiAffinity, Placement},
+    },
+    ctl::deployer::AllocStrategy,
+};
+use tracing::trace;
+use uuid::Uuid;
+
+use crate::{deployer::alloc::Resources, worker_mgr::WorkerDetails};
+
+/// The resources and instances currently placed in the cluster's workers.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// Resources reserved by the live instances of each worker.
+    pub reserved: HashMap<IpAddr, Resources>,
+    /// The amount of live instances of the service being scheduled, for each
+    /// worker.
+    pub service_instances: HashMap<IpAddr, u32>,
+}
+
+/// An instance which is being scheduled.
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub resources: Resources,
+    pub placement: &'a Placement,
+}
+
+/// A worker which is being considered for an instance.
+///
+/// Workers which haven't reported their metrics yet (e.g., which have just
+/// joined the cluster) are of unknown capacity, rather than of no capacity.
+#[derive(Debug)]
+pub struct Node<'a> {
+    pub worker: &'a WorkerDetails,
+    pub capacity: Option<Resources>,
+    pub free: Option<Resources>,
+    /// The amount of instances of the service being scheduled.
+    pub service_instances: u32,
+}
+
+impl Node<'_> {
+    /// Returns the fraction (from 0 to 1) of the worker's most used resource,
+    /// after `request` is taken from the free resources, or `None` if the
+    /// worker's capacity is unknown.
+    #[allow(clippy::cast_precision_loss)]
+    fn load_after(&self, request: Resources) -> Option<f64> {
+        let (capacity, free) = self.capacity.zip(self.free)?;
+        let used = |free: u64, request: u64, capacity: u64| {
+            if capacity == 0 {
+                return 1.0;
+            }
+            1.0 - free.saturating_sub(request) as f64 / capacity as f64
+        };
+        Some(f64::max(
+            used(free.cpu_shares, request.cpu_shares, capacity.cpu_shares),
+            used(free.memory, request.memory, capacity.memory),
+        ))
+    }
+}
+
+/// Removes the workers in which an instance can't be placed.
+pub trait Filter: fmt::Debug + Send + Sync {
+    fn feasible(&self, request: &Request, node: &Node) -> bool;
+}
+
+/// Ranks the workers in which an instance may be placed.
+pub trait Scorer: fmt::Debug + Send + Sync {
+    /// Returns the worker's score, from 0 (worst) to 1 (best). Workers which
+    /// can't be ranked (e.g., due to unknown metrics) get [`NEUTRAL_SCORE`].
+    fn score(&self, request: &Request, node: &Node) -> f64;
+}
+
+/// The score of workers which a scorer can't rank.
+pub const NEUTRAL_SCORE: f64 = 0.5;
+
+/// Only keeps workers with enough free resources for the instance.
+///
+/// Workers of unknown capacity are kept, since their metrics will soon be
+/// reported.
+#[derive(Debug)]
+pub struct CapacityFilter;
+
+impl Filter for CapacityFilter {
+    fn feasible(&self, request: &Request, node: &Node) -> bool {
+        node.free
+            .is_none_or(|free| free.checked_sub(request.resources).is_some())
+    }
+}
+
+/// Only keeps workers whose labels match the service's node selector.
+#[derive(Debug)]
+pub struct NodeSelectorFilter;
+
+impl Filter for NodeSelectorFilter {
+    fn feasible(&self, request: &Request, node: &Node) -> bool {
+        request
+            .placement
+            .node_selector
+            .iter()
+            .all(|(key, value)| node.worker.labels.get(key) == Some(value))
+    }
+}
+
+/// Only keeps workers which satisfy the service's anti-affinity rule.
+///
+/// Label spreading also requires the worker to have the spread label. The
+/// spread itself is enforced by the scheduler, since it depends on which
+/// workers are feasible.
+#[derive(Debug)]
+pub struct AntiAffinityFilter;
+
+impl Filter for AntiAffinityFilter {
+    fn feasible(&self, request: &Request, node: &Node) -> bool {
+        match &request.placement.anti_affinity {
+            None => true,
+            Some(AntiAffinity::MaxPerWorker { max }) => node.service_instances < *max,
+            Some(AntiAffinity::SpreadByLabel { key }) => node.worker.labels.contains_key(key),
+        }
+    }
+}
+
+/// Prefers workers with fewer instances of the same service.
+#[derive(Debug)]
+pub struct SpreadScorer;
+
+impl Scorer for SpreadScorer {
+    fn score(&self, _: &Request, node: &Node) -> f64 {
+        1.0 / f64::from(node.service_instances + 1)
+    }
+}
+
+/// Prefers workers with the lowest reported CPU usage.
+#[derive(Debug)]
+pub struct LeastCpuUsageScorer;
+
+impl Scorer for LeastCpuUsageScorer {
+    fn score(&self, _: &Request, node: &Node) -> f64 {
+        node.worker.metrics.as_ref().map_or(NEUTRAL_SCORE, |m| {
+            1.0 - (m.cpu_usage / 100.0).clamp(0.0, 1.0)
+        })
+    }
+}
+
+/// Prefers workers which would be left with the least free resources, packing
+/// instances together.
+#[derive(Debug)]
+pub struct BestFitScorer;
+
+impl Scorer for BestFitScorer {
+    fn score(&self, request: &Request, node: &Node) -> f64 {
+        node.load_after(request.resources).unwrap_or(NEUTRAL_SCORE)
+    }
+}
+
+/// Prefers workers with the most free resources, spreading load apart.
+#[derive(Debug)]
+pub struct LeastLoadedScorer;
+
+impl Scorer for LeastLoadedScorer {
+    fn score(&self, request: &Request, node: &Node) -> f64 {
+        node.load_after(request.resources)
+            .map_or(NEUTRAL_SCORE, |load| 1.0 - load)
+    }
+}
+
+/// The built-in filter plugins.
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum FilterKind {
+    Capacity,
+}
+
+/// The built-in scorer plugins.
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum ScorerKind {
+    Spread,
+    LeastCpuUsage,
+    BestFit,
+    LeastLoaded,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    filters: Vec<Box<dyn Filter>>,
+    scorers: Vec<Box<dyn Scorer>>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler with the given chain of built-in plugins.
+    #[must_use]
+    pub fn new(filters: &[FilterKind], scorers: &[ScorerKind]) -> Self {
+        let filters = filters
+            .iter()
+            .map(|kind| match kind {
+                FilterKind::Capacity => Box::new(CapacityFilter) as Box<dyn Filter>,
+            })
+            .collect();
+        let scorers = scorers
+            .iter()
+            .map(|kind| match kind {
+                ScorerKind::Spread => Box::new(SpreadScorer) as Box<dyn Scorer>,
+                ScorerKind::LeastCpuUsage => Box::new(LeastCpuUsageScorer),
+                ScorerKind::BestFit => Box::new(BestFitScorer),
+                ScorerKind::LeastLoaded => Box::new(LeastLoadedScorer),
+            })
+            .collect();
+        Scheduler { filters, scorers }
+    }
+
+    /// Places up to the given amount of instances of the given request.
+    ///
+    /// Returns fewer instances if some of them don't fit into any worker.
+    pub fn schedule_many(
+        &self,
+        workers: &[WorkerDetails],
+        snapshot: &Snapshot,
+        request: &Request,
+        instances: u32,
+    ) -> Vec<(InstanceId, IpAddr)> {
+        let mut nodes: Vec<_> = workers
+            .iter()
+            .map(|worker| {
+                let capacity = Resources::capacity(worker);
+                let reserved = snapshot
+                    .reserved
+                    .get(&worker.addr)
+                    .copied()
+                    .unwrap_or_default();
+                Node {
+                    worker,
+                    capacity,
+                    // Workers may be overcommitted if they report smaller
+                    // capacities.
+                    free: capacity.map(|c| c.checked_sub(reserved).unwrap_or_default()),
+                    service_instances: snapshot
+                        .service_instances
+                        .get(&worker.addr)
+                        .copied()
+                        .unwrap_or(0),
+                }
+            })
+            .collect();
+
+        let mut allocated = Vec::with_capacity(instances as usize);
+        for _ in 0..instances {
+            let Some(node) = self.pick(request, &mut nodes) else {
+                break;
+            };
+            // Subsequent instances must see this placement.
+            node.free = node
+                .free
+                .map(|free| free.checked_sub(request.resources).unwrap_or_default());
+            node.service_instances += 1;
+            allocated.push((InstanceId(Uuid::now_v7()), node.worker.addr));
+        }
+        allocated
+    }
+
+    fn pick<'n, 'w>(
+        &self,
+        request: &Request,
+        nodes: &'n mut [Node<'w>],
+    ) -> Option<&'n mut Node<'w>> {
+        let spread_key = match &request.placement.anti_affinity {
+            Some(AntiAffinity::SpreadByLabel { key }) => Some(key.as_str()),
+            _ => None,
+        };
+        let domains = spread_key.map(|key| domain_instances(key, nodes));
+
+        let mut feasible: Vec<_> = nodes
+            .iter_mut()
+            .filter(|node| {
+                let mut filters = CONSTRAINT_FILTERS
+                    .iter()
+                    .copied()
+                    .chain(self.filters.iter().map(AsRef::as_ref));
+                filters.all(|f| f.feasible(request, node))
+            })
+            .collect();
+        if let (Some(key), Some(domains)) = (spread_key, domains) {
+            // Only keep the feasible workers whose domain (i.e., label value)
+            // has the least instances of the service.
+            let domain = |node: &Node| domains[node.worker.labels[key].as_str()];
+            let min = feasible.iter().map(|node| domain(node)).min();
+            feasible.retain(|node| Some(domain(node)) == min);
+        }
+
+        feasible
+            .into_iter()
+            .map(|node| {
+                let score: f64 = self.scorers.iter().map(|s| s.score(request, node)).sum();
+                trace!(addr = ?node.worker.addr, score, "scored worker");
+                (score, node)
+            })
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, node)| node)
+    }
+}
+
+/// The scheduler of each [`AllocStrategy`], built once.
+#[derive(Debug)]
+pub struct Schedulers {
+    /// The scheduler with the controller's configured plugins.
+    configured: Scheduler,
+    spread: Scheduler,
+    best_fit: Scheduler,
+    least_loaded: Scheduler,
+}
+
+impl Schedulers {
+    pub fn new(configured: Scheduler) -> Self {
+        Schedulers {
+            configured,
+            spread: Scheduler::new(&[], &[ScorerKind::Spread]),
+            best_fit: Scheduler::new(&[FilterKind::Capacity], &[ScorerKind::BestFit]),
+            least_loaded: Scheduler::new(&[FilterKind::Capacity], &[ScorerKind::LeastLoaded]),
+        }
+    }
+
+    /// Returns the scheduler of the given strategy, or `None` if instances
+    /// must be placed in a round-robin fashion.
+    pub fn get(&self, strategy: AllocStrategy, placement: &Placement) -> Option<&Scheduler> {
+        match strategy {
+            AllocStrategy::RoundRobin if placement.is_unconstrained() => None,
+            // Round-robin can't enforce placement constraints, hence spreading
+            // is used instead.
+            AllocStrategy::RoundRobin => Some(&self.spread),
+            AllocStrategy::BestFit => Some(&self.best_fit),
+            AllocStrategy::LeastLoaded => Some(&self.least_loaded),
+            AllocStrategy::Scheduler => Some(&self.configured),
+        }
+    }
+}
+
+/// Filters which enforce the service's placement constraints.
+const CONSTRAINT_FILTERS: &[&dyn Filter] = &[&NodeSelectorFilter, &AntiAffinityFilter];
+
+/// Returns the amount of instances of the service in each value of the given
+/// worker label.
+fn domain_instances<'w>(key: &str, nodes: &[Node<'w>]) -> HashMap<&'w str, u32> {
+    let mut domains = HashMap::new();
+    for node in nodes {
+        if let Some(domain) = node.worker.labels.get(key) {
+            *domains.entry(domain.as_str()).or_default() += node.service_instances;
+        }
+    }
+    domains
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn worker(addr: [u8; 4]) -> WorkerDetails {
+        WorkerDetails {
+            addr: IpAddr::from(addr),
+            metrics: None,
+            collected_at: Instant::now(),
+            labels: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn workers_of_unknown_capacity_are_not_filtered_out() {
+        let scheduler = Scheduler::new(&[FilterKind::Capacity], &[ScorerKind::BestFit]);
+        let workers = [worker([10, 0, 0, 1])];
+        let request = Request {
+            resources: Resources {
+                cpu_shares: 1024,
+                memory: 128,
+            },
+            placement: &Placement::default(),
+        };
+
+        let allocated = scheduler.schedule_many(&workers, &Snapshot::default(), &request, 2);
+        assert_eq!(allocated.len(), 2);
+    }
+}
@@ -0,0 +1,128 @@
+//! Metrics-driven horizontal autoscaling.
+//!
+//! The deployer periodically compares the load of each autoscaled service
+//! against its target, and adjusts the amount of instances of the service's
+//! current deployment proportionally to how far off the load is.
+//!
+//! To keep the amount of instances from flapping, loads within a tolerance
+//! band around the target are ignored (hysteresis), and services which were
+//! recently scaled are left alone until their cooldown elapses.
+//!
+//! Services may also scale to zero instances once idle, regardless of their
+//! autoscaling policy. They are woken up by the balancer on their next request.
+
+use std::time::{Duration, Instant};
+
+use proto::common::service::{Autoscaling, ScalingTarget};
+
+/// Loads within this fraction of the target don't cause scaling.
+const TOLERANCE: f64 = 0.1;
+
+#[derive(Debug, Copy, Clone)]
+pub struct AutoscaleConfig {
+    /// Interval between autoscaling passes.
+    pub interval: Duration,
+    /// The minimum time between two scaling operations of a service.
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Autoscale {
+    last_scaled: Option<Instant>,
+    /// When the service was last deployed or woken up.
+    last_active: Option<Instant>,
+}
+
+impl Autoscale {
+    pub fn touch(&mut self) {
+        self.last_active = Some(Instant::now());
+    }
+
+    /// Whether the service has been idle for at least `timeout`, given when it
+    /// last received a request.
+    pub fn is_idle(&self, last_request: Option<Instant>, timeout: Duration) -> bool {
+        let since = [self.last_active, self.last_scaled, last_request]
+            .into_iter()
+            .flatten()
+            .max();
+        since.is_some_and(|t| t.elapsed() >= timeout)
+    }
+
+    pub fn in_cooldown(&self, cooldown: Duration) -> bool {
+        self.last_scaled.is_some_and(|t| t.elapsed() < cooldown)
+    }
+
+    pub fn record_scale(&mut self) {
+        self.last_scaled = Some(Instant::now());
+    }
+}
+
+/// Returns the target load per instance, in the same unit as observed loads.
+pub fn target_load(target: ScalingTarget) -> f64 {
+    match target {
+        ScalingTarget::Cpu { usage } => usage,
+        ScalingTarget::InFlightRequests { per_instance } => f64::from(per_instance),
+    }
+}
+
+/// Returns the amount of instances which would bring the observed load per
+/// instance to the policy's target, or `None` if `current` should be kept.
+///
+/// If the load is unknown (e.g., no instance has started yet), the amount of
+/// instances is only brought within the policy's bounds.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+pub fn desired_instances(policy: &Autoscaling, current: u32, load: Option<f64>) -> Option<u32> {
+    let target = target_load(policy.target);
+    let desired = match load {
+        Some(load) if current > 0 && target > 0.0 => {
+            let ratio = load / target;
+            if (ratio - 1.0).abs() <= TOLERANCE {
+                current
+            } else {
+                (f64::from(current) * ratio).ceil() as u32
+            }
+        }
+        _ => current,
+    };
+    let desired = desired.clamp(policy.min, policy.max);
+    (desired != current).then_some(desired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: Autoscaling = Autoscaling {
+        min: 1,
+        max: 10,
+        target: ScalingTarget::InFlightRequests { per_instance: 10 },
+    };
+
+    #[test]
+    fn scales_proportionally_to_the_load() {
+        assert_eq!(desired_instances(&POLICY, 2, Some(20.0)), Some(4));
+        assert_eq!(desired_instances(&POLICY, 4, Some(5.0)), Some(2));
+        // Partial instances are rounded up.
+        assert_eq!(desired_instances(&POLICY, 2, Some(12.5)), Some(3));
+    }
+
+    #[test]
+    fn loads_within_tolerance_are_ignored() {
+        assert_eq!(desired_instances(&POLICY, 2, Some(10.9)), None);
+        assert_eq!(desired_instances(&POLICY, 2, Some(9.1)), None);
+    }
+
+    #[test]
+    fn desired_instances_are_kept_within_bounds() {
+        assert_eq!(desired_instances(&POLICY, 5, Some(100.0)), Some(10));
+        assert_eq!(desired_instances(&POLICY, 2, Some(0.0)), Some(1));
+        assert_eq!(desired_instances(&POLICY, 10, Some(100.0)), None);
+    }
+
+    #[test]
+    fn unknown_loads_only_enforce_bounds() {
+        assert_eq!(desired_instances(&POLICY, 3, None), None);
+        assert_eq!(desired_instances(&POLICY, 0, None), Some(1));
+        assert_eq!(desired_instances(&POLICY, 20, Some(10.0)), Some(10));
+    }
+}
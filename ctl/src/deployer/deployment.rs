@@ -0,0 +1,381 @@
+//! Deployment-level state machine.
+//!
+//! Aggregates the deploy outcomes of all instances which were created by a
+//! given deployment. See `docs/statem.md` for its diagram.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use proto::{
+    common::{
+        instance::InstanceId,
+        service::{ServiceId, ServiceSpec},
+    },
+    ctl::deployer::{
+        AllocStrategy, DeploymentId, DeploymentInfo, DeploymentState, InstanceInfo, InstanceState,
+        QueryDeploymentStatusRes, RedeploymentPolicy,
+    },
+};
+use tracing::{instrument, trace};
+
+use crate::deployer::{
+    instance::{self, TerminalKind},
+    rollout::{Failure, Rollout},
+};
+
+#[derive(Debug)]
+pub struct StateCtx {
+    state: State,
+    id: DeploymentId,
+    service_id: Arc<ServiceId>,
+    spec: ServiceSpec,
+    policy: RedeploymentPolicy,
+    /// Also used to allocate instances which are created after the deployment
+    /// (e.g., replacements of failed instances).
+    alloc_strategy: AllocStrategy,
+    created_at: DateTime<Utc>,
+    /// Whether this deployment's instances have been superseded by a newer
+    /// deployment (or, for canaries, were aborted). Retired deployments are
+    /// never healed.
+    retired: bool,
+    /// Records of each of this deployment's instances, including their latest
+    /// known state.
+    ///
+    /// Notice that, unlike the deployer's instance state machines, these are
+    /// kept even after the instances reach a final state.
+    instances: HashMap<InstanceId, InstanceInfo>,
+    /// Instances whose deploy outcome is still unknown.
+    unsettled: HashSet<InstanceId>,
+    /// Only present for [`RedeploymentPolicy::Rolling`] deployments.
+    rollout: Option<Rollout>,
+}
+
+impl StateCtx {
+    pub fn new(
+        id: DeploymentId,
+        service_id: Arc<ServiceId>,
+        spec: ServiceSpec,
+        policy: RedeploymentPolicy,
+        alloc_strategy: AllocStrategy,
+        instances: impl IntoIterator<Item = (InstanceId, Option<IpAddr>)>,
+    ) -> Self {
+        let created_at = Utc::now();
+        let instances: HashMap<_, _> = instances
+            .into_iter()
+            .map(|(id, worker_addr)| {
+                let info = InstanceInfo {
+                    state: InstanceState::Init,
+                    worker_addr,
+                    created_at,
+                    updated_at: created_at,
+                    anomalies: Vec::new(),
+                };
+                (id, info)
+            })
+            .collect();
+        let unsettled = instances.keys().copied().collect::<HashSet<_>>();
+        let rollout = match policy {
+            RedeploymentPolicy::Rolling {
+                max_surge,
+                max_unavailable,
+            } => {
+                let queued = instances.iter().map(|(&id, i)| (id, i.worker_addr));
+                Some(Rollout::new(max_surge, max_unavailable, queued))
+            }
+            RedeploymentPolicy::None
+            | RedeploymentPolicy::Replace
+            | RedeploymentPolicy::BlueGreen
+            | RedeploymentPolicy::Canary { .. } => None,
+        };
+        let state = if unsettled.is_empty() {
+            State::CompleteRunningDeploy
+        } else {
+            State::Deploying
+        };
+        StateCtx {
+            state,
+            id,
+            service_id,
+            spec,
+            policy,
+            alloc_strategy,
+            created_at,
+            retired: false,
+            instances,
+            unsettled,
+            rollout,
+        }
+    }
+
+    /// Creates the record of a deployment restored from the store (see
+    /// `crate::store`), whose given instances have already started.
+    ///
+    /// Restored deployments are final, as their rollouts aren't stored.
+    pub fn restored(
+        id: DeploymentId,
+        service_id: Arc<ServiceId>,
+        spec: ServiceSpec,
+        policy: RedeploymentPolicy,
+        alloc_strategy: AllocStrategy,
+        instances: impl IntoIterator<Item = (InstanceId, Option<IpAddr>)>,
+    ) -> Self {
+        let mut ctx = Self::new(id, service_id, spec, policy, alloc_strategy, instances);
+        for info in ctx.instances.values_mut() {
+            info.state = InstanceState::Started;
+        }
+        ctx.unsettled.clear();
+        ctx.rollout = None;
+        ctx.state = State::CompleteRunningDeploy;
+        ctx
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn service_id(&self) -> &Arc<ServiceId> {
+        &self.service_id
+    }
+
+    pub fn spec(&self) -> &ServiceSpec {
+        &self.spec
+    }
+
+    /// Sets the desired amount of instances, e.g., when the service is scaled.
+    pub fn set_concurrency(&mut self, concurrency: u32) {
+        self.spec.concurrency = concurrency;
+    }
+
+    pub fn alloc_strategy(&self) -> AllocStrategy {
+        self.alloc_strategy
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired
+    }
+
+    pub fn retire(&mut self) {
+        self.retired = true;
+    }
+
+    /// Returns the instances whose deploy outcome is still unknown.
+    pub fn unsettled(&self) -> impl Iterator<Item = InstanceId> + '_ {
+        self.unsettled.iter().copied()
+    }
+
+    /// Retires this deployment in favor of a newer one, aborting its rollout,
+    /// if any.
+    ///
+    /// Returns `true` if, as a result, the deployment has just reached a final
+    /// state.
+    pub fn supersede(&mut self) -> bool {
+        self.retire();
+        let dropped = self.rollout.as_mut().map(Rollout::abort);
+        self.drop_never_started(dropped.unwrap_or_default())
+    }
+
+    pub fn policy(&self) -> RedeploymentPolicy {
+        self.policy
+    }
+
+    pub fn rollout(&self) -> Option<&Rollout> {
+        self.rollout.as_ref()
+    }
+
+    pub fn rollout_mut(&mut self) -> Option<&mut Rollout> {
+        self.rollout.as_mut()
+    }
+
+    /// Returns the number of instances which are currently running.
+    pub fn running_instances(&self) -> usize {
+        self.instances
+            .values()
+            .filter(|i| i.state == InstanceState::Started)
+            .count()
+    }
+
+    /// Returns the worker addresses of all instances which hold resources,
+    /// including the ones which were placed but not yet started.
+    pub fn live_instance_addrs(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.instances
+            .values()
+            .filter(|i| {
+                matches!(
+                    i.state,
+                    InstanceState::Init
+                        | InstanceState::Deploying
+                        | InstanceState::PreTerminating
+                        | InstanceState::Started
+                        | InstanceState::Terminating
+                )
+            })
+            .filter_map(|i| i.worker_addr)
+    }
+
+    /// Adds a record for an instance which was created after the deployment
+    /// has finished, e.g., to replace a crashed instance.
+    pub fn add_instance(&mut self, id: InstanceId, worker_addr: Option<IpAddr>) {
+        let now = Utc::now();
+        let info = InstanceInfo {
+            state: InstanceState::Init,
+            worker_addr,
+            created_at: now,
+            updated_at: now,
+            anomalies: Vec::new(),
+        };
+        self.instances.insert(id, info);
+    }
+
+    /// Records the new state of one of this deployment's instances.
+    ///
+    /// Returns `true` if, as a result, the deployment has just reached a final
+    /// state (i.e., all of its instances have reported their deploy outcome).
+    #[instrument(skip_all, fields(deployment_id = %self.id, instance_id = %instance.id()))]
+    pub fn record(&mut self, instance: &instance::StateCtx) -> bool {
+        let state = instance.state();
+        if let Some(info) = self.instances.get_mut(&instance.id()) {
+            info.state = state.public();
+            info.worker_addr = instance.worker_addr();
+            info.updated_at = Utc::now();
+        }
+
+        if !self.unsettled.contains(&instance.id()) {
+            return false;
+        }
+        let ok = match (state, state.kind()) {
+            (instance::State::Started, _) => true,
+            // An instance which reaches a final state without ever having
+            // started (e.g., it failed to start or was terminated while still
+            // deploying) has failed to deploy.
+            (_, TerminalKind::SuccessfulTerminal | TerminalKind::UnsuccessfulTerminal) => false,
+            (_, TerminalKind::NonTerminal) => return false,
+        };
+        let failure = match (ok, &mut self.rollout) {
+            (false, Some(rollout)) => Some(rollout.record_failure()),
+            _ => None,
+        };
+        if let Some(Failure::Replaced(replacement)) = failure {
+            // The replacement settles in place of the failed instance.
+            self.unsettled.remove(&instance.id());
+            self.unsettled.insert(replacement);
+            self.add_instance(replacement, None);
+            return false;
+        }
+        let done = self.settle(instance.id(), ok);
+
+        if let Some(Failure::Aborted(dropped)) = failure {
+            return self.drop_never_started(dropped) || done;
+        }
+        done
+    }
+
+    /// Settles the given instances, which were dropped from an aborted rollout
+    /// and thus will never be deployed, returning whether there are no more
+    /// unsettled instances.
+    fn drop_never_started(&mut self, dropped: Vec<InstanceId>) -> bool {
+        let mut done = false;
+        for id in dropped {
+            if let Some(info) = self.instances.get_mut(&id) {
+                info.state = InstanceState::NeverStarted;
+                info.updated_at = Utc::now();
+            }
+            done = self.settle(id, false);
+        }
+        done
+    }
+
+    /// Records the anomalies of the given instance, whose state was kept.
+    pub fn record_anomalies(&mut self, instance: &instance::StateCtx) {
+        if let Some(info) = self.instances.get_mut(&instance.id()) {
+            info.anomalies = instance.anomalies().to_vec();
+        }
+    }
+
+    /// Records the deploy outcome of an unsettled instance, returning whether
+    /// there are no more unsettled instances.
+    fn settle(&mut self, id: InstanceId, ok: bool) -> bool {
+        self.unsettled.remove(&id);
+        let done = self.unsettled.is_empty();
+        let next = next(self.state, ok, done);
+        trace!(from = ?self.state, to = ?next, "deployment transition");
+        self.state = next;
+        done
+    }
+
+    /// Returns the public status of this deployment.
+    pub fn status(&self) -> QueryDeploymentStatusRes {
+        QueryDeploymentStatusRes {
+            service_id: self.service_id.as_ref().clone(),
+            state: self.state.public(),
+            instances: self
+                .instances
+                .iter()
+                .map(|(&id, info)| (id, info.state))
+                .collect(),
+            rollout: self.rollout.as_ref().map(Rollout::status),
+        }
+    }
+
+    /// Returns the public records of this deployment.
+    pub fn info(&self) -> DeploymentInfo {
+        DeploymentInfo {
+            deployment_id: self.id,
+            state: self.state.public(),
+            created_at: self.created_at,
+            instances: self.instances.clone(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Deploying,
+    PartialDeployingOk,
+    PartialDeployingErr,
+    CompleteRunningDeploy,
+    IncompleteRunningDeploy,
+}
+
+impl State {
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            State::CompleteRunningDeploy | State::IncompleteRunningDeploy
+        )
+    }
+
+    /// Returns the public representation of this state.
+    pub fn public(self) -> DeploymentState {
+        match self {
+            State::Deploying => DeploymentState::Deploying,
+            State::PartialDeployingOk => DeploymentState::PartialDeployingOk,
+            State::PartialDeployingErr => DeploymentState::PartialDeployingErr,
+            State::CompleteRunningDeploy => DeploymentState::CompleteRunningDeploy,
+            State::IncompleteRunningDeploy => DeploymentState::IncompleteRunningDeploy,
+        }
+    }
+}
+
+/// Computes the next deployment state, given whether the last instance was
+/// successfully deployed and whether there are no more instances left to be
+/// deployed.
+fn next(current: State, ok: bool, done: bool) -> State {
+    use State::*;
+
+    let partial = match (current, ok) {
+        (Deploying | PartialDeployingOk, true) => PartialDeployingOk,
+        (Deploying | PartialDeployingOk, false) | (PartialDeployingErr, _) => PartialDeployingErr,
+        (s @ (CompleteRunningDeploy | IncompleteRunningDeploy), _) => {
+            unreachable!("unexpected instance outcome for final state `{s:?}`")
+        }
+    };
+    match (partial, done) {
+        (PartialDeployingOk, true) => CompleteRunningDeploy,
+        (PartialDeployingErr, true) => IncompleteRunningDeploy,
+        (s, _) => s,
+    }
+}
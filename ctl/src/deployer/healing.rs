@@ -0,0 +1,79 @@
+//! Self-healing of services.
+//!
+//! When an instance of a service unexpectedly stops, the deployer schedules a
+//! replacement instance for it. Replacements are delayed by an exponential
+//! backoff, based on the amount of recent failures of the service, so that a
+//! crash-looping service doesn't take over the cluster.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use proto::common::instance::InstanceId;
+
+/// The delay before replacing the first failed instance.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum delay before replacing a failed instance.
+const MAX_BACKOFF: Duration = Duration::from_mins(2);
+
+/// The amount of recent failures after which a service is considered to be
+/// crash-looping, i.e., degraded.
+const CRASH_LOOP_THRESHOLD: u32 = 5;
+
+/// How long a service must go without failures for its failure count to be
+/// reset.
+const FAILURE_WINDOW: Duration = Duration::from_mins(5);
+
+#[derive(Debug, Default)]
+pub struct Healing {
+    /// The amount of failures since the service last became stable.
+    failures: u32,
+    last_failure: Option<Instant>,
+    /// Until when replacements are held off.
+    backoff_until: Option<Instant>,
+    /// Instances which were created to replace failed ones.
+    replacements: HashSet<InstanceId>,
+}
+
+impl Healing {
+    /// Records a failure of one of the service's instances, returning how long
+    /// to wait before replacing it.
+    pub fn record_failure(&mut self) -> Duration {
+        if !self.is_recent() {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        self.last_failure = Some(Instant::now());
+
+        let factor = 2_u32.saturating_pow(self.failures - 1);
+        let backoff = BASE_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF);
+        self.backoff_until = Some(Instant::now() + backoff);
+        backoff
+    }
+
+    /// Whether a replacement is being held off by the service's backoff.
+    pub fn is_backing_off(&self) -> bool {
+        self.backoff_until.is_some_and(|t| t > Instant::now())
+    }
+
+    /// Whether the service is crash-looping.
+    pub fn is_degraded(&self) -> bool {
+        self.failures >= CRASH_LOOP_THRESHOLD && self.is_recent()
+    }
+
+    pub fn add_replacement(&mut self, id: InstanceId) {
+        self.replacements.insert(id);
+    }
+
+    /// Forgets the given replacement instance, returning whether it was one.
+    pub fn take_replacement(&mut self, id: InstanceId) -> bool {
+        self.replacements.remove(&id)
+    }
+
+    fn is_recent(&self) -> bool {
+        self.last_failure
+            .is_some_and(|t| t.elapsed() < FAILURE_WINDOW)
+    }
+}
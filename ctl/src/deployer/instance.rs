@@ -1,14 +1,16 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration};
 
+use chrono::Utc;
 use proto::{
     common::{
         instance::{self, InstanceId, InstanceSpec},
         service::ServiceId,
     },
-    ctl::deployer::DeploymentId,
+    ctl::deployer::{DeploymentId, InstanceAnomaly, InstanceState},
     well_known::{MAX_INSTANCE_DEPLOY_RETRIES, MAX_INSTANCE_TERMINATION_RETRIES},
     worker::runner::{DeployInstanceRes, TerminateInstanceRes},
 };
+use tokio::time;
 use tracing::{instrument, trace, warn};
 use utils::fmt::ElideDebug;
 
@@ -17,11 +19,22 @@ use crate::deployer::Deployer;
 // Notice that we use less than OR EQUAL, so we start with 1.
 const INITIAL_ATTEMPT: u8 = 1;
 
+/// The maximum amount of anomalies kept for each instance. Older ones are
+/// discarded first.
+const MAX_ANOMALIES: usize = 16;
+
+/// Interval between the termination attempts of an abandoned instance.
+const ABANDONED_TERMINATION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Computes the next state given the current state and a transition message.
 ///
 /// Before returning the next state, this function may also schedule some
 /// background worker task that will *eventually* produce another transition
 /// message.
+///
+/// Transitions which are unexpected in the current state (e.g., a duplicate or
+/// late report from a worker) are recorded as anomalies, and leave the state
+/// untouched.
 #[
     // Notice that through this span we log eventual errors.
     instrument(skip(d))
@@ -32,6 +45,11 @@ pub fn next(d: &mut Deployer, current: StateCtx, t: Transition) -> StateCtx {
     use Transition as t;
 
     match (current.state.clone(), t) {
+        (Init, t::Deploy { spec }) if current.worker_addr.is_none() => {
+            trace!("no worker to place instance, pending");
+            current.trans_into(Pending { spec })
+        }
+
         (Init, t::Deploy { spec }) => {
             schedule_instance_deployment(d, &current, spec.get().clone());
             current.trans_into(Deploying {
@@ -40,6 +58,21 @@ pub fn next(d: &mut Deployer, current: StateCtx, t: Transition) -> StateCtx {
             })
         }
 
+        (Pending { spec }, t::Place { worker_addr }) => {
+            let mut current = current;
+            current.worker_addr = Some(worker_addr);
+            schedule_instance_deployment(d, &current, spec.get().clone());
+            current.trans_into(Deploying {
+                attempt: INITIAL_ATTEMPT,
+                spec,
+            })
+        }
+
+        (Pending { .. }, t::Terminate) => {
+            //
+            current.trans_into(NeverStarted)
+        }
+
         (Deploying { attempt, spec }, t::FailedToDeploy(_error)) => {
             warn!("failed to deploy (deployment attempt #{attempt}");
             schedule_instance_deployment_reattempt(d, current, attempt, spec.get().clone())
@@ -51,17 +84,35 @@ pub fn next(d: &mut Deployer, current: StateCtx, t: Transition) -> StateCtx {
         }
 
         (Deploying { .. }, t::Status(s::Started)) => {
-            propagate_to_balancer(d, &current, Balancer::Include);
+            match current.routing {
+                Routing::Immediate => propagate_to_balancer(d, &current, Balancer::Include),
+                Routing::Deferred => trace!("deferring balancer inclusion"),
+            }
             current.trans_into(Started)
         }
 
+        (Deploying { attempt, spec }, t::TimedOut { .. }) => {
+            // The attempt may still be in progress at the worker. Since
+            // containers are named after their instances, retrying it under
+            // the same id can't leave two containers behind.
+            warn!("timed out deploying (deployment attempt #{attempt})");
+            let next =
+                schedule_instance_deployment_reattempt(d, current, attempt, spec.get().clone());
+            if matches!(next.state, FailedToStart) {
+                schedule_abandoned_instance_termination(d, next.placed_addr(), next.id);
+            }
+            next
+        }
+
         (Deploying { .. }, t::Terminate) => {
             //
             current.trans_into(PreTerminating)
         }
 
         (PreTerminating, t::Status(s::Started)) => {
-            // TODO
+            // The instance was never included in the balancer, so we may
+            // proceed straight to its termination.
+            schedule_instance_termination(d, &current);
             current.trans_into(Terminating {
                 attempt: INITIAL_ATTEMPT,
             })
@@ -72,13 +123,18 @@ pub fn next(d: &mut Deployer, current: StateCtx, t: Transition) -> StateCtx {
             current.trans_into(NeverStarted)
         }
 
+        (PreTerminating, t::TimedOut { .. }) => {
+            warn!("timed out waiting for instance to start");
+            current.trans_into(NeverStarted)
+        }
+
         (PreTerminating, t::Status(s::FailedToStart { .. })) => {
             warn!("failed to start instance");
             // TODO
             current.trans_into(NeverStarted)
         }
 
-        (Started, t::Status(s::Terminated { .. })) => {
+        (Started, t::Status(s::Terminated)) => {
             warn!("instance unexpectedly terminated");
             propagate_to_balancer(d, &current, Balancer::Remove);
             current.trans_into(UnexpectedTerminated)
@@ -109,6 +165,11 @@ pub fn next(d: &mut Deployer, current: StateCtx, t: Transition) -> StateCtx {
             schedule_instance_termination_reattempt(d, current.clone(), attempt)
         }
 
+        (Terminating { attempt }, t::TimedOut { .. }) => {
+            warn!("timed out terminating (termination attempt #{attempt})");
+            schedule_instance_termination_reattempt(d, current.clone(), attempt)
+        }
+
         (Terminating { .. }, t::Status(s::Terminated)) => {
             //
             current.trans_into(Terminated)
@@ -124,26 +185,122 @@ pub fn next(d: &mut Deployer, current: StateCtx, t: Transition) -> StateCtx {
             current.trans_into(Crashed)
         }
 
-        (s, t) => panic!("unexpected state transition `{t:?}` for current state `{s:?}`"),
+        (Started, t::WorkerLost) => {
+            warn!("instance's worker was lost");
+            propagate_to_balancer(d, &current, Balancer::Remove);
+            current.trans_into(Lost)
+        }
+
+        (Init | Deploying { .. } | PreTerminating | Terminating { .. }, t::WorkerLost) => {
+            warn!("instance's worker was lost");
+            current.trans_into(Lost)
+        }
+
+        (
+            Init,
+            unexpected @ (t::Place { .. }
+            | t::Terminate
+            | t::Status(_)
+            | t::FailedToTerminate(_)
+            | t::FailedToDeploy(_)
+            | t::TimedOut { .. }),
+        )
+        | (
+            Pending { .. },
+            unexpected @ (t::Deploy { .. }
+            | t::Status(_)
+            | t::FailedToTerminate(_)
+            | t::FailedToDeploy(_)
+            | t::WorkerLost
+            | t::TimedOut { .. }),
+        )
+        | (
+            Deploying { .. },
+            unexpected @ (t::Deploy { .. }
+            | t::Place { .. }
+            | t::Status(s::Terminated | s::Crashed { .. } | s::Killed { .. })
+            | t::FailedToTerminate(_)),
+        )
+        | (
+            PreTerminating,
+            unexpected @ (t::Deploy { .. }
+            | t::Place { .. }
+            | t::Terminate
+            | t::Status(s::Terminated | s::Crashed { .. } | s::Killed { .. })
+            | t::FailedToTerminate(_)),
+        )
+        | (
+            Started,
+            unexpected @ (t::Deploy { .. }
+            | t::Place { .. }
+            | t::Status(s::Started | s::FailedToStart { .. })
+            | t::FailedToTerminate(_)
+            | t::FailedToDeploy(_)
+            | t::TimedOut { .. }),
+        )
+        | (
+            Terminating { .. },
+            unexpected @ (t::Deploy { .. }
+            | t::Place { .. }
+            | t::Terminate
+            | t::Status(s::Started | s::FailedToStart { .. })
+            | t::FailedToDeploy(_)),
+        )
+        | (
+            FailedToStart | NeverStarted | UnexpectedTerminated | UnexpectedCrashed | Terminated
+            | Crashed | FailedToTerminate | Lost,
+            unexpected,
+        ) => record_anomaly(current, &unexpected),
     }
 }
 
+fn record_anomaly(mut current: StateCtx, t: &Transition) -> StateCtx {
+    warn!("unexpected state transition `{t:?}`");
+    if current.anomalies.len() == MAX_ANOMALIES {
+        current.anomalies.remove(0);
+    }
+    current.anomalies.push(InstanceAnomaly {
+        state: current.state.public(),
+        transition: format!("{t:?}"),
+        at: Utc::now(),
+    });
+    current
+}
+
 #[derive(Debug, Clone)]
 pub struct StateCtx {
     state: State,
     id: InstanceId,
-    /// The address of the worker in which this instance lives.
-    worker_addr: IpAddr,
+    /// The address of the worker in which this instance lives, if it has
+    /// already been placed.
+    worker_addr: Option<IpAddr>,
     service_id: Arc<ServiceId>,
     deployment_id: DeploymentId,
+    routing: Routing,
+    /// Incremented on every transition, so that watchdogs armed for previous
+    /// states may be told apart.
+    generation: u32,
+    anomalies: Vec<InstanceAnomaly>,
+    inbox: StatusInbox,
+}
+
+/// Describes when an instance is included in the balancer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Routing {
+    /// As soon as the instance starts.
+    Immediate,
+    /// Only when the deployer explicitly routes the instance, e.g., on a
+    /// blue/green cutover.
+    Deferred,
 }
 
 impl StateCtx {
     pub fn new_init(
         id: InstanceId,
-        worker_addr: IpAddr,
+        worker_addr: Option<IpAddr>,
         deployment_id: DeploymentId,
         service_id: Arc<ServiceId>,
+        routing: Routing,
     ) -> Self {
         StateCtx {
             state: State::Init,
@@ -151,20 +308,79 @@ impl StateCtx {
             worker_addr,
             deployment_id,
             service_id,
+            routing,
+            generation: 0,
+            anomalies: Vec::new(),
+            inbox: StatusInbox::default(),
         }
     }
 
+    /// Creates the state machine of an instance restored from the store (see
+    /// `crate::store`), which is known to have started.
+    pub fn restored_started(
+        id: InstanceId,
+        worker_addr: IpAddr,
+        deployment_id: DeploymentId,
+        service_id: Arc<ServiceId>,
+        status_seq: u64,
+    ) -> Self {
+        let mut ctx = Self::new_init(
+            id,
+            Some(worker_addr),
+            deployment_id,
+            service_id,
+            Routing::Immediate,
+        );
+        ctx.inbox = StatusInbox::resumed(status_seq);
+        ctx.trans_into(State::Started)
+    }
+
     pub fn state(&self) -> &State {
         &self.state
     }
 
-    #[allow(dead_code)]
+    pub fn id(&self) -> InstanceId {
+        self.id
+    }
+
+    pub fn service_id(&self) -> &ServiceId {
+        &self.service_id
+    }
+
+    pub fn worker_addr(&self) -> Option<IpAddr> {
+        self.worker_addr
+    }
+
+    /// Returns the address of the worker in which this instance was placed.
+    ///
+    /// Must only be called after the instance leaves the pending state.
+    fn placed_addr(&self) -> IpAddr {
+        self.worker_addr.expect("instance must have been placed")
+    }
+
     pub fn deployment_id(&self) -> DeploymentId {
         self.deployment_id
     }
 
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn anomalies(&self) -> &[InstanceAnomaly] {
+        &self.anomalies
+    }
+
+    pub fn inbox(&self) -> &StatusInbox {
+        &self.inbox
+    }
+
+    pub fn inbox_mut(&mut self) -> &mut StatusInbox {
+        &mut self.inbox
+    }
+
     fn trans_into(mut self, next: State) -> StateCtx {
         self.state = next;
+        self.generation = self.generation.wrapping_add(1);
         self
     }
 }
@@ -172,6 +388,11 @@ impl StateCtx {
 #[derive(Debug, Clone)]
 pub enum State {
     Init,
+    /// The instance is waiting in the scheduling queue for a worker with
+    /// enough capacity.
+    Pending {
+        spec: ElideDebug<InstanceSpec>,
+    },
     Deploying {
         attempt: u8,
         spec: ElideDebug<InstanceSpec>,
@@ -188,6 +409,7 @@ pub enum State {
     Terminated,
     Crashed,
     FailedToTerminate,
+    Lost,
 }
 
 impl State {
@@ -196,6 +418,7 @@ impl State {
         use TerminalKind::*;
         match self {
             State::Init => NonTerminal,
+            State::Pending { .. } => NonTerminal,
             State::Deploying { .. } => NonTerminal,
             State::FailedToStart => UnsuccessfulTerminal,
             State::PreTerminating => NonTerminal,
@@ -207,6 +430,101 @@ impl State {
             State::Terminated => SuccessfulTerminal,
             State::Crashed => UnsuccessfulTerminal,
             State::FailedToTerminate => UnsuccessfulTerminal,
+            State::Lost => UnsuccessfulTerminal,
+        }
+    }
+
+    /// Returns the public representation of this state.
+    pub fn public(&self) -> InstanceState {
+        match self {
+            State::Init => InstanceState::Init,
+            State::Pending { .. } => InstanceState::Pending,
+            State::Deploying { .. } => InstanceState::Deploying,
+            State::FailedToStart => InstanceState::FailedToStart,
+            State::PreTerminating => InstanceState::PreTerminating,
+            State::NeverStarted => InstanceState::NeverStarted,
+            State::Started => InstanceState::Started,
+            State::UnexpectedTerminated => InstanceState::UnexpectedTerminated,
+            State::UnexpectedCrashed => InstanceState::UnexpectedCrashed,
+            State::Terminating { .. } => InstanceState::Terminating,
+            State::Terminated => InstanceState::Terminated,
+            State::Crashed => InstanceState::Crashed,
+            State::FailedToTerminate => InstanceState::FailedToTerminate,
+            State::Lost => InstanceState::Lost,
+        }
+    }
+}
+
+/// Orders the status reports of an instance by their sequence numbers.
+///
+/// Workers deliver reports at least once, so reports may arrive more than once
+/// or, if retried, after later ones.
+#[derive(Debug, Clone, Default)]
+pub struct StatusInbox {
+    /// The sequence number of the last report applied in order.
+    applied: u64,
+    /// Reports which arrived before some of their predecessors.
+    early: BTreeMap<u64, instance::Status>,
+    /// Whether the inbox was restored, in which case the reports applied
+    /// after `applied` may have been lost, and the next report is taken as
+    /// the successor of the last applied one.
+    resuming: bool,
+}
+
+impl StatusInbox {
+    pub fn resumed(applied: u64) -> Self {
+        StatusInbox {
+            applied,
+            early: BTreeMap::new(),
+            resuming: true,
+        }
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Accepts a report, returning the reports which may now be applied, in
+    /// order.
+    pub fn accept(&mut self, seq: u64, status: instance::Status) -> Vec<instance::Status> {
+        if seq <= self.applied || self.early.contains_key(&seq) {
+            trace!(seq, "dropping duplicate status report");
+            return Vec::new();
+        }
+        if self.resuming {
+            self.resuming = false;
+            self.applied = seq - 1;
+        }
+        self.early.insert(seq, status);
+        let mut ready = Vec::new();
+        while let Some(status) = self.early.remove(&(self.applied + 1)) {
+            ready.push(status);
+            self.applied += 1;
+        }
+        ready
+    }
+}
+
+/// Deadlines on the states in which the deployer waits for a worker.
+///
+/// If an instance stays in one of these states for longer than its deadline
+/// (e.g., the worker crashed before reporting the instance's status), the
+/// deployer transitions it as if the worker had reported a failure.
+#[derive(Debug, Copy, Clone)]
+pub struct StateTimeouts {
+    pub deploying: Duration,
+    pub pre_terminating: Duration,
+    pub terminating: Duration,
+}
+
+impl StateTimeouts {
+    /// Returns the deadline of the given state, if it has one.
+    pub fn get(&self, state: &State) -> Option<Duration> {
+        match state {
+            State::Deploying { .. } => Some(self.deploying),
+            State::PreTerminating => Some(self.pre_terminating),
+            State::Terminating { .. } => Some(self.terminating),
+            _ => None,
         }
     }
 }
@@ -226,7 +544,10 @@ pub enum Transition {
     Deploy {
         spec: ElideDebug<InstanceSpec>,
     },
-    #[allow(dead_code)]
+    /// A worker was found for a pending instance.
+    Place {
+        worker_addr: IpAddr,
+    },
     Terminate,
     Status(instance::Status),
     // XX: For now, `FailedToTerminate` doesn't live in `instance::Status` since
@@ -238,10 +559,19 @@ pub enum Transition {
     // encounter).
     FailedToTerminate(eyre::Report),
     FailedToDeploy(eyre::Report),
+    /// The instance's worker was removed from the cluster pool, hence the
+    /// instance is assumed to be gone.
+    WorkerLost,
+    /// The instance stayed in its current state for longer than the state's
+    /// deadline (see [`StateTimeouts`]).
+    TimedOut {
+        /// The generation of the state in which the watchdog was armed.
+        generation: u32,
+    },
 }
 
 fn schedule_instance_deployment(d: &mut Deployer, ctx: &StateCtx, spec: InstanceSpec) {
-    let worker_addr = ctx.worker_addr;
+    let worker_addr = ctx.placed_addr();
     d.instance_task(ctx.id, move |h| async move {
         let result = h.worker_client.deploy_instance(worker_addr, spec).await;
         match result {
@@ -270,8 +600,34 @@ fn schedule_instance_deployment_reattempt(
     }
 }
 
+/// Terminates an instance which is no longer tracked, but which may still be
+/// running at the given worker (e.g., a given up deployment attempt which
+/// started late). Termination is retried until the worker acknowledges it.
+pub fn schedule_abandoned_instance_termination(
+    d: &mut Deployer,
+    worker_addr: IpAddr,
+    id: InstanceId,
+) {
+    d.instance_task(id, move |h| async move {
+        for attempt in INITIAL_ATTEMPT..=MAX_INSTANCE_TERMINATION_RETRIES + 1 {
+            match h.worker_client.terminate_instance(worker_addr, id).await {
+                Ok(TerminateInstanceRes {}) => return None,
+                Err(error) => {
+                    warn!(
+                        ?error,
+                        "failed to terminate abandoned instance (attempt #{attempt})"
+                    );
+                    time::sleep(ABANDONED_TERMINATION_RETRY_INTERVAL).await;
+                }
+            }
+        }
+        warn!("giving up terminating abandoned instance");
+        None
+    });
+}
+
 fn schedule_instance_termination(d: &mut Deployer, ctx: &StateCtx) {
-    let worker_addr = ctx.worker_addr;
+    let worker_addr = ctx.placed_addr();
     let id = ctx.id;
     d.instance_task(ctx.id, move |h| async move {
         let result = h.worker_client.terminate_instance(worker_addr, id).await;
@@ -308,9 +664,270 @@ enum Balancer {
 fn propagate_to_balancer(d: &mut Deployer, ctx: &StateCtx, action: Balancer) {
     trace!(?action, "propagating changes to balancer");
     let s_id = ctx.service_id.as_ref().clone();
-    let addr = ctx.worker_addr;
+    let addr = ctx.placed_addr();
     match action {
-        Balancer::Include => d.h.balancer.add_instance(s_id, ctx.id, addr),
+        Balancer::Include => {
+            d.h.balancer
+                .add_instance(s_id, ctx.deployment_id, ctx.id, addr);
+        }
         Balancer::Remove => d.h.balancer.drop_instance(&s_id, ctx.id),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use proto::common::service::{ResourceConfig, ServiceImage};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::deployer::test_util::deployer;
+
+    /// The length of the generated transition sequences.
+    const SEQUENCE_LEN: usize = 4;
+
+    const WORKER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn spec(id: InstanceId) -> ElideDebug<InstanceSpec> {
+        ElideDebug(InstanceSpec {
+            instance_id: id,
+            image: ServiceImage("image".into()),
+            public: true,
+            resource_config: ResourceConfig {
+                cpu_shares: 1024,
+                memory_limit: 1024,
+            },
+        })
+    }
+
+    fn ctx(worker_addr: Option<IpAddr>) -> StateCtx {
+        StateCtx::new_init(
+            InstanceId(Uuid::now_v7()),
+            worker_addr,
+            DeploymentId(Uuid::now_v7()),
+            Arc::new(ServiceId("service".into())),
+            Routing::Immediate,
+        )
+    }
+
+    /// Every kind of transition the machine may receive.
+    fn transitions(ctx: &StateCtx) -> Vec<Transition> {
+        use instance::Status as s;
+        vec![
+            Transition::Deploy { spec: spec(ctx.id) },
+            Transition::Place {
+                worker_addr: WORKER,
+            },
+            Transition::Terminate,
+            Transition::Status(s::Started),
+            Transition::Status(s::Terminated),
+            Transition::Status(s::Crashed { error: "x".into() }),
+            Transition::Status(s::Killed { reason: "x".into() }),
+            Transition::Status(s::FailedToStart { error: "x".into() }),
+            Transition::FailedToTerminate(eyre::eyre!("x")),
+            Transition::FailedToDeploy(eyre::eyre!("x")),
+            Transition::WorkerLost,
+            Transition::TimedOut {
+                generation: ctx.generation,
+            },
+        ]
+    }
+
+    /// Every state, in the context of a placed instance.
+    fn states(id: InstanceId) -> Vec<State> {
+        vec![
+            State::Init,
+            State::Pending { spec: spec(id) },
+            State::Deploying {
+                attempt: INITIAL_ATTEMPT,
+                spec: spec(id),
+            },
+            State::FailedToStart,
+            State::PreTerminating,
+            State::NeverStarted,
+            State::Started,
+            State::UnexpectedTerminated,
+            State::UnexpectedCrashed,
+            State::Terminating {
+                attempt: INITIAL_ATTEMPT,
+            },
+            State::Terminated,
+            State::Crashed,
+            State::FailedToTerminate,
+            State::Lost,
+        ]
+    }
+
+    /// Applies the transition, checking the machine's invariants.
+    fn step(d: &mut Deployer, current: StateCtx, t: Transition) -> StateCtx {
+        let prev_state = current.state.public();
+        let prev_kind = current.state.kind();
+        let prev_generation = current.generation;
+        let prev_anomalies = current.anomalies.len();
+
+        let next = next(d, current, t);
+
+        if next.generation == prev_generation {
+            assert_eq!(next.state.public(), prev_state);
+            let expected = (prev_anomalies + 1).min(MAX_ANOMALIES);
+            assert_eq!(next.anomalies.len(), expected);
+        } else {
+            assert_eq!(next.generation, prev_generation + 1);
+            assert_eq!(next.anomalies.len(), prev_anomalies);
+            assert_eq!(prev_kind, TerminalKind::NonTerminal, "left terminal state");
+        }
+        if matches!(
+            next.state,
+            State::Deploying { .. }
+                | State::PreTerminating
+                | State::Started
+                | State::Terminating { .. }
+        ) {
+            assert!(
+                next.worker_addr.is_some(),
+                "unplaced instance in {prev_state:?}"
+            );
+        }
+        if let State::Deploying { attempt, .. } = next.state {
+            assert!(attempt <= MAX_INSTANCE_DEPLOY_RETRIES + 1);
+        }
+        if let State::Terminating { attempt } = next.state {
+            assert!(attempt <= MAX_INSTANCE_TERMINATION_RETRIES + 1);
+        }
+        next
+    }
+
+    #[tokio::test]
+    async fn every_state_handles_every_transition() {
+        let mut d = deployer();
+        let id = ctx(None).id;
+        for state in states(id) {
+            let n = transitions(&ctx(None)).len();
+            for i in 0..n {
+                let current = ctx(Some(WORKER)).trans_into(state.clone());
+                let t = transitions(&current).swap_remove(i);
+                step(&mut d, current, t);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unexpected_transitions_are_recorded() {
+        let mut d = deployer();
+        let current = ctx(Some(WORKER)).trans_into(State::Started);
+        let generation = current.generation;
+
+        let next = step(
+            &mut d,
+            current,
+            Transition::Status(instance::Status::Started),
+        );
+        assert!(matches!(next.state, State::Started));
+        assert_eq!(next.generation, generation);
+        assert_eq!(next.anomalies.len(), 1);
+        assert_eq!(next.anomalies[0].state, InstanceState::Started);
+
+        let mut current = next;
+        for _ in 0..MAX_ANOMALIES * 2 {
+            let t = Transition::Deploy {
+                spec: spec(current.id),
+            };
+            current = step(&mut d, current, t);
+        }
+        assert_eq!(current.anomalies.len(), MAX_ANOMALIES);
+    }
+
+    #[test]
+    fn status_inbox_orders_and_deduplicates_reports() {
+        use instance::Status as s;
+        let mut inbox = StatusInbox::default();
+
+        assert!(inbox.accept(2, s::Terminated).is_empty());
+        assert!(inbox.accept(2, s::Terminated).is_empty());
+        assert_eq!(inbox.accept(1, s::Started), [s::Started, s::Terminated]);
+        assert!(inbox.accept(1, s::Started).is_empty());
+        assert_eq!(inbox.applied(), 2);
+    }
+
+    #[test]
+    fn resumed_status_inbox_takes_the_next_report_as_successor() {
+        use instance::Status as s;
+        // Reports after the third one may have been lost with the controller.
+        let mut inbox = StatusInbox::resumed(3);
+
+        assert!(inbox.accept(3, s::Started).is_empty());
+        assert_eq!(inbox.accept(6, s::Terminated), [s::Terminated]);
+        assert_eq!(inbox.applied(), 6);
+    }
+
+    #[tokio::test]
+    async fn timed_out_deployments_are_reattempted() {
+        let mut d = deployer();
+        let current = ctx(Some(WORKER));
+        let mut current = current.clone().trans_into(State::Deploying {
+            attempt: INITIAL_ATTEMPT,
+            spec: spec(current.id),
+        });
+        for attempt in INITIAL_ATTEMPT..=MAX_INSTANCE_DEPLOY_RETRIES {
+            let t = Transition::TimedOut {
+                generation: current.generation,
+            };
+            current = step(&mut d, current, t);
+            assert!(
+                matches!(current.state, State::Deploying { attempt: a, .. } if a == attempt + 1)
+            );
+        }
+        let t = Transition::TimedOut {
+            generation: current.generation,
+        };
+        current = step(&mut d, current, t);
+        assert!(matches!(current.state, State::FailedToStart));
+    }
+
+    #[tokio::test]
+    async fn restarted_workers_adopt_their_live_surviving_instances() {
+        let mut d = deployer();
+        let survivor = ctx(Some(WORKER)).trans_into(State::Started);
+        let lost = ctx(Some(WORKER)).trans_into(State::Started);
+        let unreported = ctx(Some(WORKER));
+        let unreported = unreported.clone().trans_into(State::Deploying {
+            attempt: INITIAL_ATTEMPT,
+            spec: spec(unreported.id),
+        });
+        let unknown = InstanceId(Uuid::now_v7());
+        let ids = (survivor.id, lost.id, unreported.id);
+        for statem in [survivor, lost, unreported] {
+            d.instance_statems.insert(statem.id, statem);
+        }
+        let (survivor, lost, unreported) = ids;
+
+        let adopted = d.handle_sync_worker_instances(WORKER, &[survivor, unreported, unknown]);
+
+        assert_eq!(adopted, HashMap::from([(survivor, 0), (unreported, 0)]));
+        assert!(!d.instance_statems.contains_key(&lost));
+        let state = d.instance_statems[&unreported].state();
+        assert!(matches!(state, State::Started));
+    }
+
+    /// Applies every sequence of `SEQUENCE_LEN` transitions to the instance.
+    fn apply_sequences(d: &mut Deployer, current: &StateCtx, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let n = transitions(current).len();
+        for i in 0..n {
+            let t = transitions(current).swap_remove(i);
+            let next = step(d, current.clone(), t);
+            apply_sequences(d, &next, len - 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn generated_sequences_keep_invariants() {
+        let mut d = deployer();
+        for worker_addr in [None, Some(WORKER)] {
+            apply_sequences(&mut d, &ctx(worker_addr), SEQUENCE_LEN);
+        }
+    }
+}
@@ -1,40 +1,112 @@
-use std::{collections::HashMap, future::Future, net::IpAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
-use eyre::bail;
+use axum::http::StatusCode;
+use chrono::Utc;
 use proto::{
     clients::WorkerClient,
     common::{
         instance::{self as proto_instance, InstanceId, InstanceSpec},
-        service::{ServiceId, ServiceSpec},
+        service::{ScaleToZero, ScalingTarget, ServiceId, ServiceSpec},
+    },
+    ctl::deployer::{
+        AbortCanaryRes, AllocStrategy, DeployServiceRes, DeploymentId, InstanceState,
+        ListRevisionsRes, ListServicesRes, PromoteCanaryRes, QueryDeploymentStatusRes,
+        RedeploymentPolicy, RevisionId, RevisionInfo, ScaleServiceRes, ServiceSummary,
+        ShowServiceRes, TerminateServiceRes, TerminationOutcome,
     },
-    ctl::deployer::{DeployServiceRes, DeploymentId},
 };
 use tokio::{
     select,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, broadcast::error::RecvError, mpsc, oneshot},
     task::JoinSet,
+    time,
 };
-use tracing::{instrument, trace, warn};
+use tracing::{info, instrument, trace, warn};
+use utils::http::{self, OptionExt as _};
 use uuid::Uuid;
 
 use crate::{
     balancer::BalancerHandle,
-    deployer::instance::{TerminalKind, Transition},
-    worker_mgr::WorkerMgrHandle,
+    deployer::{
+        alloc::{
+            scheduler::{Request, Scheduler, Schedulers, Snapshot},
+            Resources,
+        },
+        autoscale::AutoscaleConfig,
+        instance::{Routing, StateTimeouts, TerminalKind, Transition},
+        rebalance::{RebalanceConfig, Rebalancer, WorkerLoad},
+        rollout::{Census, Rollout},
+        service::{Canary, ServiceInfo, TerminationCtx, TerminationReply},
+    },
+    store::{self, Entry, InstanceRecord, ServiceRecord, StoreHandle},
+    worker_mgr::{WorkerDetails, WorkerEvent, WorkerMgrHandle},
 };
 
-mod alloc;
-mod instance;
+pub mod alloc;
+pub mod autoscale;
+mod deployment;
+mod healing;
+pub mod instance;
+pub mod rebalance;
+mod rollout;
+mod service;
+
+/// How often pending instances are retried, in case capacity is freed by other
+/// means than instances stopping (e.g., a worker reporting more resources).
+const PLACEMENT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait before rebalancing into a worker which has just joined,
+/// so that it gets to report its metrics.
+const JOINED_REBALANCE_DELAY: Duration = Duration::from_secs(5);
+
+/// The deployer's tunables (see the controller's arguments).
+#[derive(Debug, Copy, Clone)]
+pub struct DeployerConfig {
+    /// Only present if rebalancing is enabled.
+    pub rebalance: Option<RebalanceConfig>,
+    pub autoscale: AutoscaleConfig,
+    /// Interval between reconciliation passes.
+    pub reconcile_interval: Duration,
+    pub state_timeouts: StateTimeouts,
+}
 
 pub struct Deployer {
     rx: mpsc::Receiver<Msg>,
+    worker_events: broadcast::Receiver<WorkerEvent>,
     h: Arc<DeployerHandles>,
     /// Set of deployer-related background-running tasks.
     tasks: JoinSet<()>,
-    /// Pending deployment state machine contexts.
-    _deployment_statems: HashMap<DeploymentId, u8 /* todo */>,
+    /// Records of every service deployed in the cluster.
+    services: HashMap<ServiceId, ServiceInfo>,
+    /// Deployment state machine contexts.
+    ///
+    /// These are kept after the deployment finishes, so that they also act as
+    /// the deployment's records.
+    deployment_statems: HashMap<DeploymentId, deployment::StateCtx>,
     /// Instance state machine contexts.
     instance_statems: HashMap<InstanceId, instance::StateCtx>,
+    /// Service state machine contexts, for services that are being terminated.
+    service_terminations: HashMap<ServiceId, TerminationCtx>,
+    /// Pending instances, in the order in which they must be placed.
+    scheduling_queue: VecDeque<InstanceId>,
+    /// Whether a placement retry of the pending instances is scheduled.
+    placement_retry_scheduled: bool,
+    /// The schedulers of the allocation strategies.
+    schedulers: Schedulers,
+    /// Only present if rebalancing is enabled.
+    rebalancer: Option<Rebalancer>,
+    autoscale: AutoscaleConfig,
+    /// Interval between reconciliation passes (see [`Self::handle_reconcile`]).
+    reconcile_interval: Duration,
+    /// Deadlines of the instance states which wait for a worker.
+    state_timeouts: StateTimeouts,
+    store: StoreHandle,
     /// Whether the deployer actor is terminating.
     _terminating: bool,
 }
@@ -52,11 +124,15 @@ impl Deployer {
         balancer: BalancerHandle,
         worker_mgr: WorkerMgrHandle,
         worker_client: WorkerClient,
+        scheduler: Scheduler,
+        config: DeployerConfig,
+        store: StoreHandle,
     ) -> (Deployer, DeployerHandle) {
         let (tx, rx) = mpsc::channel(16);
         let handle = DeployerHandle(tx);
         let actor = Deployer {
             rx,
+            worker_events: worker_mgr.subscribe(),
             h: Arc::new(DeployerHandles {
                 deployer_handle: handle.clone(),
                 balancer,
@@ -64,20 +140,123 @@ impl Deployer {
                 worker_client,
             }),
             tasks: JoinSet::new(),
-            _deployment_statems: HashMap::new(),
+            services: HashMap::new(),
+            deployment_statems: HashMap::new(),
             instance_statems: HashMap::new(),
+            service_terminations: HashMap::new(),
+            scheduling_queue: VecDeque::new(),
+            placement_retry_scheduled: false,
+            schedulers: Schedulers::new(scheduler),
+            rebalancer: config.rebalance.map(Rebalancer::new),
+            autoscale: config.autoscale,
+            reconcile_interval: config.reconcile_interval,
+            state_timeouts: config.state_timeouts,
+            store,
             _terminating: false,
         };
         (actor, handle)
     }
 
+    /// Restores the services and instances stored by the controller's previous
+    /// run (see [`crate::store`]), converging them to their desired state.
+    ///
+    /// Only the instances which had started for their service's current
+    /// deployment are adopted. Other instances are terminated, as their
+    /// outcome is unknown, and replaced as services converge. Thus, restarts
+    /// abort rollouts and canaries which were in progress.
+    pub async fn restore(&mut self, snapshot: &store::Snapshot) {
+        for record in snapshot.services.values() {
+            let service_id = Arc::new(record.spec.service_id.clone());
+            let deployment_id = record.deployment_id;
+            let adopted: Vec<_> = snapshot
+                .instances
+                .iter()
+                .filter(|(_, i)| {
+                    i.deployment_id == deployment_id && i.state == InstanceState::Started
+                })
+                .filter_map(|(&id, i)| Some((id, i.worker_addr?, i.status_seq)))
+                .collect();
+
+            let deployment = deployment::StateCtx::restored(
+                deployment_id,
+                service_id.clone(),
+                record.spec.clone(),
+                record.policy,
+                record.alloc_strategy,
+                adopted.iter().map(|&(id, addr, _)| (id, Some(addr))),
+            );
+            self.deployment_statems.insert(deployment_id, deployment);
+            for &(id, addr, status_seq) in &adopted {
+                let statem = instance::StateCtx::restored_started(
+                    id,
+                    addr,
+                    deployment_id,
+                    service_id.clone(),
+                    status_seq,
+                );
+                self.instance_statems.insert(id, statem);
+                self.h
+                    .balancer
+                    .add_instance((*service_id).clone(), deployment_id, id, addr);
+            }
+
+            let mut service = ServiceInfo::new(record.spec.clone());
+            service.deployments.push(deployment_id);
+            service.revisions = record.revisions.iter().cloned().collect();
+            service.autoscale.touch();
+            self.h
+                .balancer
+                .set_scale_to_zero((*service_id).clone(), record.spec.scale_to_zero.is_some());
+            self.services.insert((*service_id).clone(), service);
+        }
+
+        let orphans: Vec<_> = snapshot
+            .instances
+            .iter()
+            .filter(|(id, _)| !self.instance_statems.contains_key(id))
+            .map(|(&id, i)| (id, i.worker_addr))
+            .collect();
+        for (id, worker_addr) in orphans {
+            self.store.append(Entry::InstanceRemoved { id });
+            let Some(worker_addr) = worker_addr else {
+                continue;
+            };
+            warn!(instance_id = %id, %worker_addr, "terminating instance of unknown outcome");
+            let h = self.h.clone();
+            self.tasks.spawn(async move {
+                if let Err(error) = h.worker_client.terminate_instance(worker_addr, id).await {
+                    warn!(?error, instance_id = %id, "failed to terminate instance");
+                }
+            });
+        }
+        info!(
+            services = self.services.len(),
+            instances = self.instance_statems.len(),
+            "restored state"
+        );
+
+        let workers = self.h.worker_mgr.query_workers().await;
+        let deployments: Vec<_> = self.deployment_statems.keys().copied().collect();
+        for id in deployments {
+            self.converge_deployment(id, &workers);
+        }
+    }
+
     #[allow(clippy::match_same_arms)]
     pub async fn run(mut self) {
+        if let Some(rebalancer) = &self.rebalancer {
+            self.delayed_msg(rebalancer.interval(), Msg::Rebalance { tick: true });
+        }
+        self.delayed_msg(self.autoscale.interval, Msg::Autoscale);
+        self.delayed_msg(self.reconcile_interval, Msg::Reconcile);
         loop {
             select! {
                 Some(msg) = self.rx.recv() => {
                     self.handle_msg(msg).await;
                 }
+                event = self.worker_events.recv() => {
+                    self.handle_worker_event(event).await;
+                }
                 // TODO: Implement graceful shutdown.
                 // N.B.: Don't forget to test with flavor `current-thread`.
                 //
@@ -101,55 +280,745 @@ impl Deployer {
     #[instrument(skip_all)]
     async fn handle_msg(&mut self, msg: Msg) {
         match msg {
-            Msg::DeployService(spec, reply) => {
-                _ = reply.send(self.handle_deploy_service(spec).await);
+            Msg::DeployService(spec, policy, strategy, reply) => {
+                let res = self.handle_deploy_service(spec, policy, strategy).await;
+                _ = reply.send(res);
+            }
+            Msg::ListServices(reply) => {
+                _ = reply.send(self.handle_list_services());
+            }
+            Msg::ShowService(id, reply) => {
+                _ = reply.send(self.handle_show_service(&id));
+            }
+            Msg::QueryDeploymentStatus(id, reply) => {
+                let status = self
+                    .deployment_statems
+                    .get(&id)
+                    .map(deployment::StateCtx::status);
+                _ = reply.send(status);
+            }
+            Msg::ListRevisions(id, reply) => {
+                let res = self.services.get(&id).map(|service| ListRevisionsRes {
+                    revisions: service.revisions.iter().cloned().collect(),
+                });
+                _ = reply.send(res);
+            }
+            Msg::RollbackService(id, revision_id, reply) => {
+                let res = self.handle_rollback_service(&id, revision_id).await;
+                _ = reply.send(res);
+            }
+            Msg::PromoteCanary(id, reply) => {
+                _ = reply.send(self.handle_promote_canary(&id));
+            }
+            Msg::AbortCanary(id, reply) => {
+                _ = reply.send(self.handle_abort_canary(&id));
+            }
+            Msg::WakeService(id, reply) => {
+                let res = self.handle_wake_service(&id).await;
+                _ = reply.send(res);
+            }
+            Msg::ScaleService(id, concurrency, reply) => {
+                let res = self.handle_scale_service(&id, concurrency).await;
+                _ = reply.send(res);
             }
             Msg::TerminateService(id, reply) => {
-                self.handle_terminate_service(&id);
-                _ = reply.send(Ok(()));
+                self.handle_terminate_service(id, reply);
+            }
+            Msg::ReportInstanceStatus(id, addr, seq, status) => {
+                self.handle_instance_status(id, addr, seq, status);
             }
-            Msg::ReportInstanceStatus(id, status) => {
-                self.trans_instance_state(id, instance::Transition::Status(status));
+            Msg::SyncWorkerInstances(addr, instances, reply) => {
+                _ = reply.send(self.handle_sync_worker_instances(addr, &instances));
             }
             Msg::InstanceTransition(id, t) => {
                 self.trans_instance_state(id, t);
             }
+            Msg::HealDeployment(id) => {
+                self.handle_heal_deployment(id).await;
+            }
+            Msg::PlacePending { retry } => {
+                self.handle_place_pending(retry).await;
+            }
+            Msg::Rebalance { tick } => {
+                self.handle_rebalance(tick).await;
+            }
+            Msg::Autoscale => {
+                self.handle_autoscale().await;
+            }
+            Msg::Reconcile => {
+                self.handle_reconcile().await;
+            }
         }
     }
 
-    async fn handle_deploy_service(&mut self, spec: ServiceSpec) -> eyre::Result<DeployServiceRes> {
-        trace!(?spec, "deploying service");
-        let workers = self.h.worker_mgr.query_workers().await;
-        if workers.is_empty() {
-            bail!("no workers on cluster pool");
+    #[instrument(skip(self))]
+    async fn handle_worker_event(&mut self, event: Result<WorkerEvent, RecvError>) {
+        match event {
+            Ok(WorkerEvent::Joined(addr)) => {
+                trace!(?addr, "worker joined");
+                self.handle_place_pending(false).await;
+                if self.rebalancer.is_some() {
+                    self.delayed_msg(JOINED_REBALANCE_DELAY, Msg::Rebalance { tick: false });
+                }
+            }
+            Ok(WorkerEvent::Left(addr) | WorkerEvent::Lost(addr)) => {
+                self.handle_lost_worker(|a| a == addr);
+            }
+            Err(RecvError::Lagged(n)) => {
+                // Some removals may have been missed, so we must check every
+                // instance against the current worker pool.
+                warn!(n, "lagged behind worker events, resynchronizing");
+                let workers = self.h.worker_mgr.query_workers().await;
+                self.handle_lost_worker(|a| !workers.iter().any(|w| w.addr == a));
+            }
+            // The worker manager never drops its own sender.
+            Err(RecvError::Closed) => unreachable!("worker events channel closed"),
         }
-        let instances = alloc::rr_alloc_many(&workers, spec.concurrency);
+    }
+
+    /// Marks as lost all live instances whose workers match the given
+    /// predicate. They are then rescheduled into the remaining workers (see
+    /// [`Self::heal_after_failure`]).
+    fn handle_lost_worker(&mut self, is_lost: impl Fn(IpAddr) -> bool) {
+        let lost: Vec<_> = self
+            .instance_statems
+            .values()
+            .filter(|statem| statem.worker_addr().is_some_and(&is_lost))
+            .map(instance::StateCtx::id)
+            .collect();
+        if lost.is_empty() {
+            return;
+        }
+        info!(
+            count = lost.len(),
+            "marking instances of removed workers as lost"
+        );
+        for instance_id in lost {
+            self.trans_instance_state(instance_id, Transition::WorkerLost);
+        }
+    }
+
+    async fn handle_deploy_service(
+        &mut self,
+        mut spec: ServiceSpec,
+        policy: RedeploymentPolicy,
+        strategy: AllocStrategy,
+    ) -> http::Result<DeployServiceRes> {
+        trace!(?spec, ?policy, ?strategy, "deploying service");
+        self.check_redeployment_policy(&spec.service_id, policy)?;
+        check_autoscaling(&mut spec)?;
+        self.supersede_stalled_deployments(&spec.service_id);
+
+        let workers = self.h.worker_mgr.query_workers().await;
+        let instances = self.allocate(&workers, &spec, strategy, spec.concurrency);
         let deployment_id = DeploymentId(Uuid::now_v7());
+        let revision_id = RevisionId(Uuid::now_v7());
         let service_id = Arc::new(spec.service_id.clone());
 
-        let instances = instances
+        let deployment = deployment::StateCtx::new(
+            deployment_id,
+            service_id.clone(),
+            spec.clone(),
+            policy,
+            strategy,
+            instances.clone(),
+        );
+        self.deployment_statems.insert(deployment_id, deployment);
+        if let RedeploymentPolicy::Rolling { .. } = policy {
+            // Previous instances are gradually replaced as the rollout
+            // advances, so they must not be healed.
+            self.retire_deployments(&service_id, Some(deployment_id));
+        }
+
+        let service = self
+            .services
+            .entry(spec.service_id.clone())
+            .or_insert_with(|| ServiceInfo::new(spec.clone()));
+        let previous_spec = std::mem::replace(&mut service.spec, spec.clone());
+        self.h
+            .balancer
+            .set_scale_to_zero(spec.service_id.clone(), spec.scale_to_zero.is_some());
+        service.deployments.push(deployment_id);
+        service.autoscale.touch();
+        service.record_revision(RevisionInfo {
+            revision_id,
+            service_spec: spec.clone(),
+            deployment_id,
+            alloc_strategy: strategy,
+            created_at: Utc::now(),
+        });
+
+        if let RedeploymentPolicy::Canary { weight } = policy {
+            service.canary = Some(Canary {
+                deployment_id,
+                previous_spec,
+            });
+            // Weights must be set before any of the canary instances start, so
+            // that they never receive more than their share of the traffic.
+            self.h
+                .balancer
+                .set_weight(spec.service_id.clone(), deployment_id, Some(weight));
+        }
+
+        if let RedeploymentPolicy::Rolling { .. } = policy {
+            // Rolling deployments start their instances in batches.
+            self.step_rollout(deployment_id);
+        } else {
             // For each allocated instance, schedule a deploy.
-            .inspect(|&(instance_id, worker_addr)| {
-                self.add_instance_init_state(
-                    instance_id,
-                    worker_addr,
-                    deployment_id,
-                    service_id.clone(),
-                );
+            for &(instance_id, worker_addr) in &instances {
+                self.start_instance(instance_id, worker_addr, deployment_id, &spec);
+            }
+        }
+
+        let rollout = self.deployment_statems[&deployment_id]
+            .rollout()
+            .map(Rollout::status);
+        let (instances, pending): (Vec<_>, Vec<_>) =
+            instances.into_iter().partition(|(_, addr)| addr.is_some());
+        self.prune_deployments(&spec.service_id);
+        self.persist_service(&spec.service_id);
+        Ok(DeployServiceRes {
+            deployment_id,
+            revision_id,
+            instances: instances
+                .into_iter()
+                .filter_map(|(id, addr)| Some((id, addr?)))
+                .collect(),
+            pending: pending.into_iter().map(|(id, _)| id).collect(),
+            rollout,
+        })
+    }
+
+    /// Redeploys the spec of one of the service's revisions, using the
+    /// redeployment policy of the service's latest deployment.
+    #[instrument(skip(self))]
+    async fn handle_rollback_service(
+        &mut self,
+        id: &ServiceId,
+        revision_id: RevisionId,
+    ) -> http::Result<DeployServiceRes> {
+        let service = self
+            .services
+            .get(id)
+            .or_http_error(StatusCode::NOT_FOUND, "service not found")?;
+        let revision = service
+            .revisions
+            .iter()
+            .find(|r| r.revision_id == revision_id)
+            .or_http_error(StatusCode::NOT_FOUND, "revision not found")?;
+        let latest = *service.deployments.last().unwrap();
+        let policy = self.deployment_statems[&latest].policy();
+        let strategy = revision.alloc_strategy;
+        let spec = revision.service_spec.clone();
+
+        info!(?policy, "rolling back service");
+        self.handle_deploy_service(spec, policy, strategy).await
+    }
+
+    /// Allocates the given amount of instances of `spec` into the provided
+    /// workers, following the allocation strategy.
+    ///
+    /// Instances which don't fit into any worker are returned without an
+    /// address, and must wait in the scheduling queue.
+    fn allocate(
+        &self,
+        workers: &[WorkerDetails],
+        spec: &ServiceSpec,
+        strategy: AllocStrategy,
+        instances: u32,
+    ) -> Vec<(InstanceId, Option<IpAddr>)> {
+        let mut allocated: Vec<_> = self
+            .place(workers, spec, strategy, instances)
+            .into_iter()
+            .map(|(id, addr)| (id, Some(addr)))
+            .collect();
+        let pending = instances as usize - allocated.len();
+        if pending > 0 {
+            info!(pending, "not enough capacity, instances will be pending");
+        }
+        allocated.extend((0..pending).map(|_| (InstanceId(Uuid::now_v7()), None)));
+        allocated
+    }
+
+    /// Places up to the given amount of instances of `spec` into the provided
+    /// workers, following the allocation strategy.
+    fn place(
+        &self,
+        workers: &[WorkerDetails],
+        spec: &ServiceSpec,
+        strategy: AllocStrategy,
+        instances: u32,
+    ) -> Vec<(InstanceId, IpAddr)> {
+        if workers.is_empty() {
+            return Vec::new();
+        }
+        let Some(scheduler) = self.schedulers.get(strategy, &spec.placement) else {
+            return alloc::rr_alloc_many(workers, instances).collect();
+        };
+        let request = Request {
+            resources: Resources::of(&spec.resource_config),
+            placement: &spec.placement,
+        };
+        let snapshot = self.snapshot(&spec.service_id);
+        scheduler.schedule_many(workers, &snapshot, &request, instances)
+    }
+
+    /// Returns the resources reserved by the live instances of each worker,
+    /// alongside how many of them belong to the given service.
+    fn snapshot(&self, service_id: &ServiceId) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for deployment in self.deployment_statems.values() {
+            let request = Resources::of(&deployment.spec().resource_config);
+            let same_service = &**deployment.service_id() == service_id;
+            for addr in deployment.live_instance_addrs() {
+                *snapshot.reserved.entry(addr).or_default() += request;
+                if same_service {
+                    *snapshot.service_instances.entry(addr).or_default() += 1;
+                }
+            }
+        }
+        snapshot
+    }
+
+    /// Creates the state machine of a new instance and schedules its deploy.
+    ///
+    /// Instances without a worker are queued until they can be placed.
+    fn start_instance(
+        &mut self,
+        instance_id: InstanceId,
+        worker_addr: Option<IpAddr>,
+        deployment_id: DeploymentId,
+        spec: &ServiceSpec,
+    ) {
+        let service_id = Arc::new(spec.service_id.clone());
+        let deployment = &self.deployment_statems[&deployment_id];
+        let routing = match deployment.policy() {
+            // Blue/green instances are only routed on cutover. Replacements of
+            // blue/green instances, which are created afterwards, are routed
+            // as usual.
+            RedeploymentPolicy::BlueGreen if !deployment.state().is_final() => Routing::Deferred,
+            _ => Routing::Immediate,
+        };
+        self.add_instance_init_state(instance_id, worker_addr, deployment_id, service_id, routing);
+
+        let spec = InstanceSpec::from_service_spec_cloned(spec, instance_id).into();
+        self.trans_instance_state(instance_id, Transition::Deploy { spec });
+        if worker_addr.is_none() {
+            self.scheduling_queue.push_back(instance_id);
+            self.schedule_placement_retry();
+        }
+    }
+
+    /// Tries to place the pending instances into the current workers, in the
+    /// order in which they were queued.
+    ///
+    /// Instances which still don't fit are kept in the queue, and retried
+    /// later.
+    #[instrument(skip(self))]
+    async fn handle_place_pending(&mut self, retry: bool) {
+        if retry {
+            self.placement_retry_scheduled = false;
+        }
+        if self.scheduling_queue.is_empty() {
+            return;
+        }
+        let workers = self.h.worker_mgr.query_workers().await;
+        let queue = std::mem::take(&mut self.scheduling_queue);
+        for instance_id in queue {
+            // Pending instances may have been terminated in the meantime.
+            let Some(statem) = self.instance_statems.get(&instance_id) else {
+                continue;
+            };
+            if !matches!(statem.state(), instance::State::Pending { .. }) {
+                continue;
+            }
+            let deployment = &self.deployment_statems[&statem.deployment_id()];
+            let spec = deployment.spec().clone();
+            let strategy = deployment.alloc_strategy();
+            match self.place(&workers, &spec, strategy, 1).first() {
+                Some(&(_, worker_addr)) => {
+                    info!(%instance_id, ?worker_addr, "placed pending instance");
+                    self.trans_instance_state(instance_id, Transition::Place { worker_addr });
+                }
+                None => self.scheduling_queue.push_back(instance_id),
+            }
+        }
+        if !self.scheduling_queue.is_empty() {
+            trace!(
+                count = self.scheduling_queue.len(),
+                "instances still pending"
+            );
+            self.schedule_placement_retry();
+        }
+    }
 
-                let spec = InstanceSpec::from_service_spec_cloned(&spec, instance_id).into();
-                self.trans_instance_state(instance_id, Transition::Deploy { spec });
+    fn schedule_placement_retry(&mut self) {
+        if !self.placement_retry_scheduled {
+            self.placement_retry_scheduled = true;
+            self.delayed_msg(PLACEMENT_RETRY_INTERVAL, Msg::PlacePending { retry: true });
+        }
+    }
+
+    /// Checks whether a new deployment of the given service is allowed under
+    /// the provided redeployment policy.
+    fn check_redeployment_policy(
+        &self,
+        id: &ServiceId,
+        policy: RedeploymentPolicy,
+    ) -> http::Result<()> {
+        let conflict = |msg| Err(http::Error::public(StatusCode::CONFLICT, msg));
+
+        if let RedeploymentPolicy::Rolling {
+            max_surge: 0,
+            max_unavailable: 0,
+        } = policy
+        {
+            let msg = "rolling policy requires either `max_surge` or `max_unavailable`";
+            return Err(http::Error::public(StatusCode::BAD_REQUEST, msg));
+        }
+        if let RedeploymentPolicy::Canary { weight: 101.. } = policy {
+            let msg = "canary weight must be a percentage, from 0 to 100";
+            return Err(http::Error::public(StatusCode::BAD_REQUEST, msg));
+        }
+
+        if self.service_terminations.contains_key(id) {
+            return conflict("service is being terminated");
+        }
+        let Some(service) = self.services.get(id) else {
+            return Ok(());
+        };
+        let stalled = self.stalled_deployments(id);
+        let in_progress = service
+            .deployments
+            .iter()
+            .filter(|d| !stalled.contains(d))
+            .filter_map(|d| self.deployment_statems.get(d))
+            .any(|d| !d.state().is_final());
+        if in_progress {
+            return conflict("service has a deployment in progress");
+        }
+        if service.canary.is_some() {
+            return conflict("service has a canary deployment which must be promoted or aborted");
+        }
+        match policy {
+            RedeploymentPolicy::None => {
+                // Instances of stalled deployments are superseded.
+                let running = self.instance_statems.values().any(|statem| {
+                    statem.service_id() == id && !stalled.contains(&statem.deployment_id())
+                });
+                if running {
+                    return conflict("service is already deployed with running instances");
+                }
+                Ok(())
+            }
+            RedeploymentPolicy::Replace
+            | RedeploymentPolicy::Rolling { .. }
+            | RedeploymentPolicy::BlueGreen
+            | RedeploymentPolicy::Canary { .. } => Ok(()),
+        }
+    }
+
+    /// Returns the unfinished deployments of the given service which can't make
+    /// any progress, since none of their unsettled instances could be placed
+    /// into a worker yet.
+    fn stalled_deployments(&self, id: &ServiceId) -> HashSet<DeploymentId> {
+        let Some(service) = self.services.get(id) else {
+            return HashSet::new();
+        };
+        service
+            .deployments
+            .iter()
+            .filter(|d| {
+                self.deployment_statems.get(d).is_some_and(|deployment| {
+                    !deployment.state().is_final()
+                        && deployment.unsettled().all(|instance_id| {
+                            // Instances queued by a rollout have no state
+                            // machine yet.
+                            self.instance_statems
+                                .get(&instance_id)
+                                .is_none_or(|statem| {
+                                    matches!(statem.state(), instance::State::Pending { .. })
+                                })
+                        })
+                })
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Gives up on the stalled deployments of the given service (see
+    /// [`Self::stalled_deployments`]), so that they don't block a newer
+    /// deployment. Their pending instances are terminated.
+    #[instrument(skip(self))]
+    fn supersede_stalled_deployments(&mut self, id: &ServiceId) {
+        for deployment_id in self.stalled_deployments(id) {
+            info!(%deployment_id, "superseding stalled deployment");
+            let deployment = self.deployment_statems.get_mut(&deployment_id).unwrap();
+            if deployment.supersede() {
+                self.handle_finished_deployment(deployment_id);
+            }
+            let pending: Vec<_> = self
+                .instance_statems
+                .values()
+                .filter(|statem| {
+                    statem.deployment_id() == deployment_id
+                        && matches!(statem.state(), instance::State::Pending { .. })
+                })
+                .map(instance::StateCtx::id)
+                .collect();
+            for instance_id in pending {
+                self.trans_instance_state(instance_id, Transition::Terminate);
+            }
+        }
+    }
+
+    /// Promotes the service's canary deployment, so that its instances receive
+    /// all of the service's traffic, and terminates the previous instances.
+    #[instrument(skip(self))]
+    fn handle_promote_canary(&mut self, id: &ServiceId) -> http::Result<PromoteCanaryRes> {
+        let deployment_id = self.active_canary(id)?;
+        if !self.deployment_statems[&deployment_id].state().is_final() {
+            let msg = "canary deployment is still in progress";
+            return Err(http::Error::public(StatusCode::CONFLICT, msg));
+        }
+
+        info!(%deployment_id, "promoting canary deployment");
+        self.services.get_mut(id).unwrap().canary = None;
+        self.h.balancer.set_weight(id.clone(), deployment_id, None);
+        self.terminate_previous_instances(id, deployment_id);
+        self.persist_service(id);
+        Ok(PromoteCanaryRes { deployment_id })
+    }
+
+    /// Aborts the service's canary deployment, terminating its instances.
+    #[instrument(skip(self))]
+    fn handle_abort_canary(&mut self, id: &ServiceId) -> http::Result<AbortCanaryRes> {
+        let deployment_id = self.active_canary(id)?;
+
+        info!(%deployment_id, "aborting canary deployment");
+        let service = self.services.get_mut(id).unwrap();
+        let canary = service.canary.take().unwrap();
+        service.spec = canary.previous_spec;
+        let scale_to_zero = service.spec.scale_to_zero.is_some();
+        self.h.balancer.set_scale_to_zero(id.clone(), scale_to_zero);
+        self.h.balancer.set_weight(id.clone(), deployment_id, None);
+        self.deployment_statems
+            .get_mut(&deployment_id)
+            .unwrap()
+            .retire();
+
+        let to_terminate: Vec<_> = self
+            .instance_statems
+            .values()
+            .filter(|statem| {
+                statem.deployment_id() == deployment_id
+                    && matches!(
+                        statem.state(),
+                        instance::State::Pending { .. }
+                            | instance::State::Deploying { .. }
+                            | instance::State::Started
+                    )
             })
+            .map(instance::StateCtx::id)
             .collect();
+        for instance_id in to_terminate {
+            self.trans_instance_state(instance_id, Transition::Terminate);
+        }
+        Ok(AbortCanaryRes { deployment_id })
+    }
 
-        Ok(DeployServiceRes {
+    /// Changes the amount of instances of the service's current deployment.
+    #[instrument(skip(self))]
+    async fn handle_scale_service(
+        &mut self,
+        id: &ServiceId,
+        concurrency: u32,
+    ) -> http::Result<ScaleServiceRes> {
+        let conflict = |msg| Err(http::Error::public(StatusCode::CONFLICT, msg));
+        let service = self
+            .services
+            .get(id)
+            .or_http_error(StatusCode::NOT_FOUND, "service not found")?;
+        if self.service_terminations.contains_key(id) {
+            return conflict("service is being terminated");
+        }
+        if service.canary.is_some() {
+            return conflict("service has a canary deployment which must be promoted or aborted");
+        }
+        let to_zero = concurrency == 0 && service.spec.scale_to_zero.is_some();
+        if let (Some(policy), false) = (service.spec.autoscaling, to_zero) {
+            if !(policy.min..=policy.max).contains(&concurrency) {
+                let msg = "concurrency must be within the service's autoscaling bounds";
+                return Err(http::Error::public(StatusCode::BAD_REQUEST, msg));
+            }
+        }
+        let deployment_id = *service.deployments.last().unwrap();
+        let deployment = &self.deployment_statems[&deployment_id];
+        if !deployment.state().is_final() {
+            return conflict("service has a deployment in progress");
+        }
+        if deployment.is_retired() {
+            return conflict("service has no running deployment");
+        }
+
+        info!(%deployment_id, "scaling service");
+        let workers = self.h.worker_mgr.query_workers().await;
+        let (started, terminating) = self.scale_deployment(deployment_id, concurrency, &workers);
+        // Manual scaling also holds off the autoscaler.
+        let service = self.services.get_mut(id).unwrap();
+        service.autoscale.record_scale();
+        Ok(ScaleServiceRes {
             deployment_id,
-            instances,
+            started,
+            terminating,
         })
     }
 
-    fn handle_terminate_service(&mut self, _id: &ServiceId) {
-        _ = self;
+    /// Starts an instance of a service which was scaled to zero, as requested by
+    /// the balancer.
+    #[instrument(skip(self))]
+    async fn handle_wake_service(&mut self, id: &ServiceId) -> http::Result<()> {
+        let unavailable = |msg| Err(http::Error::public(StatusCode::SERVICE_UNAVAILABLE, msg));
+        let service = self
+            .services
+            .get_mut(id)
+            .or_http_error(StatusCode::NOT_FOUND, "service not found")?;
+        if service.spec.scale_to_zero.is_none() {
+            return unavailable("service has no available instances");
+        }
+        if self.service_terminations.contains_key(id) {
+            return unavailable("service is being terminated");
+        }
+        let deployment_id = *service.deployments.last().unwrap();
+        let deployment = &self.deployment_statems[&deployment_id];
+        if deployment.is_retired() {
+            return unavailable("service has no running deployment");
+        }
+        service.autoscale.touch();
+        // Another request may have already woken the service up.
+        if deployment.spec().concurrency > 0 {
+            return Ok(());
+        }
+
+        let instances = service.spec.autoscaling.map_or(1, |p| p.min.max(1));
+        info!(%deployment_id, instances, "cold starting service");
+        let workers = self.h.worker_mgr.query_workers().await;
+        self.scale_deployment(deployment_id, instances, &workers);
+        Ok(())
+    }
+
+    /// Returns the service's active canary deployment.
+    fn active_canary(&self, id: &ServiceId) -> http::Result<DeploymentId> {
+        let service = self
+            .services
+            .get(id)
+            .or_http_error(StatusCode::NOT_FOUND, "service not found")?;
+        let canary = service
+            .canary
+            .as_ref()
+            .or_http_error(StatusCode::NOT_FOUND, "service has no canary deployment")?;
+        Ok(canary.deployment_id)
+    }
+
+    fn handle_list_services(&self) -> ListServicesRes {
+        let services = self
+            .services
+            .iter()
+            .map(|(id, service)| ServiceSummary {
+                service_id: id.clone(),
+                service_spec: service.spec.clone(),
+                deployments: service.deployments.len(),
+                running_instances: service
+                    .deployments
+                    .iter()
+                    .filter_map(|d| self.deployment_statems.get(d))
+                    .map(deployment::StateCtx::running_instances)
+                    .sum(),
+                degraded: service.healing.is_degraded(),
+                created_at: service.created_at,
+            })
+            .collect();
+        ListServicesRes { services }
+    }
+
+    fn handle_show_service(&self, id: &ServiceId) -> Option<ShowServiceRes> {
+        let service = self.services.get(id)?;
+        Some(ShowServiceRes {
+            service_spec: service.spec.clone(),
+            created_at: service.created_at,
+            degraded: service.healing.is_degraded(),
+            deployments: service
+                .deployments
+                .iter()
+                .filter_map(|d| self.deployment_statems.get(d))
+                .map(deployment::StateCtx::info)
+                .collect(),
+        })
+    }
+
+    #[instrument(skip(self, reply))]
+    fn handle_terminate_service(&mut self, id: ServiceId, reply: TerminationReply) {
+        if self.service_terminations.contains_key(&id) {
+            let msg = "service is already being terminated";
+            _ = reply.send(Err(http::Error::public(StatusCode::CONFLICT, msg)));
+            return;
+        }
+
+        let (instances, to_terminate): (Vec<_>, Vec<_>) = {
+            let live = self
+                .instance_statems
+                .values()
+                .filter(|statem| statem.service_id() == &id);
+            let to_terminate = live
+                .clone()
+                .filter(|statem| {
+                    use instance::State::*;
+                    // Instances that are already terminating need not to be
+                    // terminated again, but we still wait for them.
+                    matches!(statem.state(), Pending { .. } | Deploying { .. } | Started)
+                })
+                .map(instance::StateCtx::id)
+                .collect();
+            (live.map(instance::StateCtx::id).collect(), to_terminate)
+        };
+        let running = self
+            .services
+            .get(&id)
+            .and_then(|s| s.deployments.last())
+            .is_some_and(|d| !self.deployment_statems[d].is_retired());
+        if instances.is_empty() && !running {
+            let msg = "service not found";
+            _ = reply.send(Err(http::Error::public(StatusCode::NOT_FOUND, msg)));
+            return;
+        }
+        // Terminated services must be neither healed nor scaled back up.
+        self.retire_deployments(&id, None);
+        if let Some(canary) = self.services.get_mut(&id).and_then(|s| s.canary.take()) {
+            info!(deployment_id = %canary.deployment_id, "dropping canary of terminated service");
+            self.h
+                .balancer
+                .set_weight(id.clone(), canary.deployment_id, None);
+        }
+        self.store.append(Entry::ServiceRemoved {
+            service_id: id.clone(),
+        });
+        if instances.is_empty() {
+            info!("service was scaled to zero, nothing to terminate");
+            self.forget_service(&id);
+            _ = reply.send(Ok(TerminateServiceRes {
+                outcome: TerminationOutcome::Complete,
+                instances: HashMap::new(),
+            }));
+            return;
+        }
+
+        info!(count = instances.len(), "terminating service instances");
+        let ctx = TerminationCtx::new(id.clone(), instances, reply);
+        self.service_terminations.insert(id, ctx);
+
+        for instance_id in to_terminate {
+            self.trans_instance_state(instance_id, Transition::Terminate);
+        }
     }
 
     fn _lffg_todo_deploy_service(&mut self) {
@@ -159,11 +1028,12 @@ impl Deployer {
     fn add_instance_init_state(
         &mut self,
         id: InstanceId,
-        worker_addr: IpAddr,
+        worker_addr: Option<IpAddr>,
         d_id: DeploymentId,
         s_id: Arc<ServiceId>,
+        routing: Routing,
     ) {
-        let s = instance::StateCtx::new_init(id, worker_addr, d_id, s_id);
+        let s = instance::StateCtx::new_init(id, worker_addr, d_id, s_id, routing);
         let opt = self.instance_statems.insert(id, s);
 
         // We have just generated a new ID (in Self::handle_deploy_service), so
@@ -171,24 +1041,896 @@ impl Deployer {
         assert!(opt.is_none());
     }
 
+    /// Applies a worker's status report, in the order in which the worker sent
+    /// it. Duplicate reports are dropped.
+    #[instrument(skip(self, status))]
+    fn handle_instance_status(
+        &mut self,
+        id: InstanceId,
+        addr: IpAddr,
+        seq: u64,
+        status: proto_instance::Status,
+    ) {
+        let Some(statem) = self.instance_statems.get_mut(&id) else {
+            if let proto_instance::Status::Started = status {
+                // E.g., an instance which was given up (as failed to start)
+                // before its worker could report it.
+                warn!(
+                    ?addr,
+                    "terminating instance which started after it was finished"
+                );
+                instance::schedule_abandoned_instance_termination(self, addr, id);
+            } else {
+                trace!("dropping status report of finished instance");
+            }
+            return;
+        };
+        for status in statem.inbox_mut().accept(seq, status) {
+            self.trans_instance_state(id, instance::Transition::Status(status));
+        }
+    }
+
+    /// Reconciles the instances placed into a restarted worker with the ones
+    /// whose containers survived the restart.
+    ///
+    /// Survivors whose start wasn't reported yet are considered started, as
+    /// their report was lost along with the previous worker process. Instances
+    /// which didn't survive are lost. Survivors which aren't live anymore are
+    /// left out of the adopted ones, so that the worker terminates them.
+    #[instrument(skip(self, surviving))]
+    fn handle_sync_worker_instances(
+        &mut self,
+        addr: IpAddr,
+        surviving: &[InstanceId],
+    ) -> HashMap<InstanceId, u64> {
+        let placed: Vec<_> = self
+            .instance_statems
+            .values()
+            .filter(|statem| statem.worker_addr() == Some(addr))
+            .map(instance::StateCtx::id)
+            .collect();
+        info!(
+            placed = placed.len(),
+            surviving = surviving.len(),
+            "syncing instances of restarted worker"
+        );
+        for id in placed {
+            if !surviving.contains(&id) {
+                self.trans_instance_state(id, Transition::WorkerLost);
+                continue;
+            }
+            let unreported = matches!(
+                self.instance_statems[&id].state(),
+                instance::State::Deploying { .. } | instance::State::PreTerminating
+            );
+            if unreported {
+                let status = proto_instance::Status::Started;
+                self.trans_instance_state(id, Transition::Status(status));
+            }
+        }
+
+        surviving
+            .iter()
+            .filter_map(|id| {
+                let statem = self.instance_statems.get(id)?;
+                let live = statem.worker_addr() == Some(addr)
+                    && statem.state().kind() == TerminalKind::NonTerminal;
+                live.then(|| (*id, statem.inbox().applied()))
+            })
+            .collect()
+    }
+
     #[instrument(skip_all, fields(instance_id = ?id))]
     fn trans_instance_state(&mut self, id: InstanceId, t: instance::Transition) {
+        if let instance::Transition::TimedOut { generation } = t {
+            let current = self.instance_statems.get(&id);
+            if current.is_none_or(|statem| statem.generation() != generation) {
+                trace!("instance left watched state before its deadline");
+                return;
+            }
+        }
         let Some(statem) = self.instance_statems.remove(&id) else {
             warn!("tried to transition nonexistent instance machine");
             return;
         };
 
         trace!(state = ?statem.state(), "transitioned from");
+        let generation = statem.generation();
         let next = instance::next(self, statem, t);
         trace!(state = ?next.state(), "transitioned to");
 
+        // Unexpected transitions are only recorded, leaving the state as is.
+        if next.generation() == generation {
+            if let Some(deployment) = self.deployment_statems.get_mut(&next.deployment_id()) {
+                deployment.record_anomalies(&next);
+            }
+            self.instance_statems.insert(id, next);
+            return;
+        }
+        self.store.append(match next.state().kind() {
+            TerminalKind::NonTerminal => Entry::InstancePut {
+                id,
+                record: InstanceRecord {
+                    service_id: next.service_id().clone(),
+                    deployment_id: next.deployment_id(),
+                    worker_addr: next.worker_addr(),
+                    state: next.state().public(),
+                    status_seq: next.inbox().applied(),
+                },
+            },
+            TerminalKind::SuccessfulTerminal | TerminalKind::UnsuccessfulTerminal => {
+                Entry::InstanceRemoved { id }
+            }
+        });
+
+        if let Some(timeout) = self.state_timeouts.get(next.state()) {
+            let generation = next.generation();
+            let t = instance::Transition::TimedOut { generation };
+            self.delayed_msg(timeout, Msg::InstanceTransition(id, t));
+        }
+
+        let d_id = next.deployment_id();
+        let started = matches!(next.state(), instance::State::Started);
+        let terminal = next.state().kind() != TerminalKind::NonTerminal;
+        // Rollouts only advance once an instance reports its deploy outcome.
+        let deploy_outcome = matches!(next.state(), instance::State::Started)
+            || next.state().kind() != TerminalKind::NonTerminal;
+        let finished_deployment = self
+            .deployment_statems
+            .get_mut(&d_id)
+            .is_some_and(|deployment| deployment.record(&next));
+
         match next.state().kind() {
             TerminalKind::NonTerminal => {
                 self.instance_statems.insert(id, next);
             }
             // If the new state is terminal, we don't need to waste memory by
             // keeping track of it, so we don't add it again.
-            TerminalKind::SuccessfulTerminal | TerminalKind::UnsuccessfulTerminal => (),
+            TerminalKind::SuccessfulTerminal | TerminalKind::UnsuccessfulTerminal => {
+                self.trans_service_termination(&next);
+                self.heal_after_failure(&next);
+                // The instance may have freed capacity for pending ones.
+                if !self.scheduling_queue.is_empty() && next.worker_addr().is_some() {
+                    self.delayed_msg(Duration::ZERO, Msg::PlacePending { retry: false });
+                }
+            }
+        }
+
+        if started || terminal {
+            self.advance_migration(id, started);
+        }
+        if finished_deployment {
+            self.handle_finished_deployment(d_id);
+        }
+        if deploy_outcome {
+            self.step_rollout(d_id);
+        }
+    }
+
+    /// Applies the deployment's redeployment policy once all of its instances
+    /// have reported their deploy outcome.
+    #[instrument(skip(self))]
+    fn handle_finished_deployment(&mut self, id: DeploymentId) {
+        // The deployment's service may have been terminated meanwhile.
+        let Some(deployment) = self.deployment_statems.get(&id) else {
+            return;
+        };
+        let service_id = deployment.service_id().clone();
+        info!(state = ?deployment.state(), "finished deployment");
+
+        match deployment.policy() {
+            // Rolling deployments terminate previous instances as the rollout
+            // advances (see `Self::step_rollout`).
+            //
+            // Canary deployments keep previous instances until promoted.
+            RedeploymentPolicy::None
+            | RedeploymentPolicy::Rolling { .. }
+            | RedeploymentPolicy::Canary { .. } => (),
+            RedeploymentPolicy::Replace => {
+                if deployment.state() != deployment::State::CompleteRunningDeploy {
+                    warn!("not all new instances have started, keeping previous instances");
+                    return;
+                }
+                self.terminate_previous_instances(&service_id, id);
+            }
+            RedeploymentPolicy::BlueGreen => {
+                let new = self.deployment_instances(id);
+                if deployment.state() != deployment::State::CompleteRunningDeploy {
+                    warn!("not all new instances have started, aborting blue/green cutover");
+                    for (instance_id, _) in new {
+                        self.trans_instance_state(instance_id, Transition::Terminate);
+                    }
+                    return;
+                }
+                info!(count = new.len(), "switching balancer to new instances");
+                self.h
+                    .balancer
+                    .replace_instances((*service_id).clone(), id, new);
+                self.terminate_previous_instances(&service_id, id);
+            }
+        }
+    }
+
+    fn terminate_previous_instances(&mut self, service_id: &ServiceId, current: DeploymentId) {
+        self.retire_deployments(service_id, Some(current));
+        let previous = self.previous_instances(service_id, current);
+        info!(count = previous.len(), "terminating previous instances");
+        for instance_id in previous {
+            self.trans_instance_state(instance_id, Transition::Terminate);
+        }
+    }
+
+    /// Marks all deployments of the given service, except for `current`, as
+    /// retired, so that their instances are no longer healed.
+    fn retire_deployments(&mut self, service_id: &ServiceId, current: Option<DeploymentId>) {
+        let Some(service) = self.services.get(service_id) else {
+            return;
+        };
+        for id in &service.deployments {
+            if Some(*id) != current {
+                if let Some(deployment) = self.deployment_statems.get_mut(id) {
+                    deployment.retire();
+                }
+            }
+        }
+    }
+
+    /// Returns the started instances of the given deployment, alongside their
+    /// worker addresses.
+    fn deployment_instances(&self, id: DeploymentId) -> Vec<(InstanceId, IpAddr)> {
+        self.instance_statems
+            .values()
+            .filter(|statem| {
+                statem.deployment_id() == id && matches!(statem.state(), instance::State::Started)
+            })
+            .filter_map(|statem| Some((statem.id(), statem.worker_addr()?)))
+            .collect()
+    }
+
+    /// Advances the rollout of the given deployment, if it's a rolling one.
+    ///
+    /// Previous instances are terminated as long as the service keeps enough
+    /// available instances, after which the next batch of new instances is
+    /// started.
+    #[instrument(skip(self))]
+    fn step_rollout(&mut self, id: DeploymentId) {
+        let Some(deployment) = self.deployment_statems.get(&id) else {
+            return;
+        };
+        if !deployment.rollout().is_some_and(Rollout::is_progressing) {
+            return;
+        }
+        let service_id = deployment.service_id().clone();
+
+        let census = self.rollout_census(&service_id, id);
+        let rollout = deployment.rollout().unwrap();
+        let to_terminate = rollout.terminable(census) as usize;
+        let previous = self.previous_instances(&service_id, id);
+        for instance_id in previous.into_iter().take(to_terminate) {
+            self.trans_instance_state(instance_id, Transition::Terminate);
+        }
+
+        let census = self.rollout_census(&service_id, id);
+        let deployment = self.deployment_statems.get_mut(&id).unwrap();
+        let batch = deployment.rollout_mut().unwrap().next_batch(census);
+        if batch.is_empty() {
+            return;
+        }
+        info!(
+            ?census,
+            to_terminate,
+            to_start = batch.len(),
+            "rollout step"
+        );
+        let spec = self.deployment_statems[&id].spec().clone();
+        for (instance_id, worker_addr) in batch {
+            self.start_instance(instance_id, worker_addr, id, &spec);
+        }
+    }
+
+    fn rollout_census(&self, service_id: &ServiceId, current: DeploymentId) -> Census {
+        let mut census = Census {
+            old_started: 0,
+            new_started: 0,
+            new_deploying: 0,
+        };
+        let instances = self
+            .instance_statems
+            .values()
+            .filter(|i| i.service_id() == service_id);
+        for statem in instances {
+            let new = statem.deployment_id() == current;
+            match (new, statem.state()) {
+                (false, instance::State::Started) => census.old_started += 1,
+                (true, instance::State::Started) => census.new_started += 1,
+                (true, instance::State::Pending { .. } | instance::State::Deploying { .. }) => {
+                    census.new_deploying += 1;
+                }
+                _ => (),
+            }
+        }
+        census
+    }
+
+    /// Returns the live instances of the given service which don't belong to
+    /// the `current` deployment.
+    fn previous_instances(&self, service_id: &ServiceId, current: DeploymentId) -> Vec<InstanceId> {
+        self.instance_statems
+            .values()
+            .filter(|statem| {
+                statem.service_id() == service_id
+                    && statem.deployment_id() != current
+                    && matches!(
+                        statem.state(),
+                        instance::State::Pending { .. }
+                            | instance::State::Deploying { .. }
+                            | instance::State::Started
+                    )
+            })
+            .map(instance::StateCtx::id)
+            .collect()
+    }
+
+    /// Schedules the replacement of an instance which unexpectedly stopped.
+    ///
+    /// Replacements which fail to start are also replaced, so that the service
+    /// eventually gets back to its desired concurrency. Instances of lost
+    /// workers are replaced right away, since their failure isn't the
+    /// service's fault.
+    fn heal_after_failure(&mut self, instance: &instance::StateCtx) {
+        let service_id = instance.service_id();
+        let Some(service) = self.services.get_mut(service_id) else {
+            return;
+        };
+        let was_replacement = service.healing.take_replacement(instance.id());
+        if self.service_terminations.contains_key(service_id) {
+            return;
+        }
+        let deployment_id = instance.deployment_id();
+        match instance.state() {
+            instance::State::UnexpectedTerminated | instance::State::UnexpectedCrashed => {
+                self.schedule_healing(service_id.clone(), deployment_id);
+            }
+            instance::State::FailedToStart if was_replacement => {
+                self.schedule_healing(service_id.clone(), deployment_id);
+            }
+            instance::State::Lost => {
+                self.delayed_msg(Duration::ZERO, Msg::HealDeployment(deployment_id));
+            }
+            _ => (),
+        }
+    }
+
+    /// Records a failure of the given service and schedules the healing of the
+    /// given deployment once the service's backoff elapses.
+    #[instrument(skip(self))]
+    fn schedule_healing(&mut self, service_id: ServiceId, deployment_id: DeploymentId) {
+        let healing = &mut self.services.get_mut(&service_id).unwrap().healing;
+        let backoff = healing.record_failure();
+        if healing.is_degraded() {
+            warn!("service is crash-looping, marking it as degraded");
+        }
+        info!(?backoff, "scheduling instance replacement");
+        self.delayed_msg(backoff, Msg::HealDeployment(deployment_id));
+    }
+
+    /// Starts a replacement instance for the given deployment, if it's still
+    /// running below its desired concurrency.
+    #[instrument(skip(self))]
+    async fn handle_heal_deployment(&mut self, id: DeploymentId) {
+        let Some(deployment) = self.deployment_statems.get(&id) else {
+            return;
+        };
+        let service_id = deployment.service_id().clone();
+        // Progressing rollouts are still handled by the rollout itself, whereas
+        // retired deployments are being (or were) terminated.
+        if deployment.is_retired()
+            || deployment.rollout().is_some_and(Rollout::is_progressing)
+            || self.service_terminations.contains_key(&*service_id)
+        {
+            trace!("skipping healing");
+            return;
+        }
+        let live = self
+            .instance_statems
+            .values()
+            .filter(|statem| {
+                statem.deployment_id() == id
+                    && matches!(
+                        statem.state(),
+                        instance::State::Init
+                            | instance::State::Pending { .. }
+                            | instance::State::Deploying { .. }
+                            | instance::State::Started
+                    )
+            })
+            .count();
+        let spec = deployment.spec().clone();
+        let strategy = deployment.alloc_strategy();
+        if live >= spec.concurrency as usize {
+            trace!(live, "deployment is already at its desired concurrency");
+            return;
+        }
+
+        let workers = self.h.worker_mgr.query_workers().await;
+        let (instance_id, worker_addr) = self.allocate(&workers, &spec, strategy, 1)[0];
+        info!(%instance_id, live, "replacing failed instance");
+        let deployment = self.deployment_statems.get_mut(&id).unwrap();
+        deployment.add_instance(instance_id, worker_addr);
+        let service = self.services.get_mut(&*service_id).unwrap();
+        service.healing.add_replacement(instance_id);
+        self.start_instance(instance_id, worker_addr, id, &spec);
+    }
+
+    /// Moves instances from the most loaded workers into the least loaded ones,
+    /// within the rebalancer's move budget.
+    ///
+    /// Ticks start a new rebalancing interval, whereas other passes (e.g., due
+    /// to a worker joining) use what is left of the current interval's budget.
+    #[instrument(skip(self))]
+    async fn handle_rebalance(&mut self, tick: bool) {
+        let Some(rebalancer) = &mut self.rebalancer else {
+            return;
+        };
+        if tick {
+            rebalancer.reset_budget();
+            let interval = rebalancer.interval();
+            self.delayed_msg(interval, Msg::Rebalance { tick: true });
+        }
+        if !self.rebalancer.as_ref().is_some_and(Rebalancer::has_budget) {
+            return;
+        }
+
+        let workers = self.h.worker_mgr.query_workers().await;
+        let mut loads: HashMap<_, _> = workers
+            .iter()
+            .map(|w| {
+                let load = WorkerLoad {
+                    instances: 0,
+                    cpu_usage: w.metrics.as_ref().map(|m| m.cpu_usage),
+                };
+                (w.addr, load)
+            })
+            .collect();
+        // Instances which are being terminated will soon leave their workers.
+        let live = self.instance_statems.values().filter(|statem| {
+            matches!(
+                statem.state(),
+                instance::State::Deploying { .. } | instance::State::Started
+            )
+        });
+        for statem in live {
+            if let Some(load) = statem.worker_addr().and_then(|a| loads.get_mut(&a)) {
+                load.instances += 1;
+            }
+        }
+
+        while self.rebalancer.as_ref().is_some_and(Rebalancer::has_budget) {
+            let Some(mv) = rebalance::plan_move(&loads) else {
+                break;
+            };
+            let Some(target) = workers.iter().find(|w| w.addr == mv.to) else {
+                break;
+            };
+            let Some(instance_id) = self.movable_instance(mv.from, target) else {
+                trace!(?mv, "no instance may be moved");
+                break;
+            };
+            info!(%instance_id, ?mv, "migrating instance");
+            self.migrate_instance(instance_id, mv.to);
+            loads.get_mut(&mv.from).unwrap().instances -= 1;
+            loads.get_mut(&mv.to).unwrap().instances += 1;
+            // Metrics only reflect the move once the workers report them again.
+            if mv.by_metrics {
+                break;
+            }
+        }
+    }
+
+    /// Returns a started instance of the `from` worker which may be moved into
+    /// the `to` worker, respecting its service's placement.
+    fn movable_instance(&self, from: IpAddr, to: &WorkerDetails) -> Option<InstanceId> {
+        let rebalancer = self.rebalancer.as_ref()?;
+        self.instance_statems
+            .values()
+            .filter(|statem| {
+                statem.worker_addr() == Some(from)
+                    && matches!(statem.state(), instance::State::Started)
+                    && !rebalancer.is_migrating(statem.id())
+                    && !self.service_terminations.contains_key(statem.service_id())
+            })
+            .find(|statem| {
+                let deployment = &self.deployment_statems[&statem.deployment_id()];
+                // Deployments which are still in progress (or which are being
+                // replaced) manage their own instances.
+                let settled = deployment.state().is_final()
+                    && !deployment.is_retired()
+                    && !deployment.rollout().is_some_and(Rollout::is_progressing);
+                settled
+                    && !self
+                        .place(
+                            std::slice::from_ref(to),
+                            deployment.spec(),
+                            deployment.alloc_strategy(),
+                            1,
+                        )
+                        .is_empty()
+            })
+            .map(instance::StateCtx::id)
+    }
+
+    /// Starts a replacement of the given instance in the `to` worker. The
+    /// original instance is terminated once the replacement starts (see
+    /// [`Self::advance_migration`]).
+    fn migrate_instance(&mut self, original: InstanceId, to: IpAddr) {
+        let statem = &self.instance_statems[&original];
+        let deployment_id = statem.deployment_id();
+        let replacement = InstanceId(Uuid::now_v7());
+
+        let deployment = self.deployment_statems.get_mut(&deployment_id).unwrap();
+        deployment.add_instance(replacement, Some(to));
+        let spec = deployment.spec().clone();
+        if let Some(rebalancer) = &mut self.rebalancer {
+            rebalancer.start_migration(replacement, original);
+        }
+        self.start_instance(replacement, Some(to), deployment_id, &spec);
+    }
+
+    /// Completes the migration for which the given instance is the
+    /// replacement, if any, once it either starts or stops.
+    fn advance_migration(&mut self, id: InstanceId, started: bool) {
+        let Some(original) = self
+            .rebalancer
+            .as_mut()
+            .and_then(|r| r.finish_migration(id))
+        else {
+            return;
+        };
+        if !started {
+            warn!(%original, "migration replacement failed, keeping original instance");
+            return;
+        }
+        let still_started = self
+            .instance_statems
+            .get(&original)
+            .is_some_and(|statem| matches!(statem.state(), instance::State::Started));
+        if still_started {
+            info!(%original, "migration replacement started, terminating original instance");
+            self.trans_instance_state(original, Transition::Terminate);
+        }
+    }
+
+    /// Adjusts the amount of instances of each autoscaled service to its load,
+    /// through the service's current deployment. Idle services which scale to
+    /// zero have all of their instances terminated.
+    #[instrument(skip(self))]
+    async fn handle_autoscale(&mut self) {
+        self.delayed_msg(self.autoscale.interval, Msg::Autoscale);
+
+        let candidates: Vec<_> = self
+            .services
+            .iter()
+            .filter_map(|(id, service)| {
+                let ServiceSpec {
+                    autoscaling,
+                    scale_to_zero,
+                    ..
+                } = service.spec;
+                if autoscaling.is_none() && scale_to_zero.is_none() {
+                    return None;
+                }
+                // Canaries and terminations are handled by the operator.
+                if service.canary.is_some()
+                    || service.autoscale.in_cooldown(self.autoscale.cooldown)
+                    || self.service_terminations.contains_key(id)
+                {
+                    return None;
+                }
+                let deployment_id = *service.deployments.last()?;
+                let deployment = &self.deployment_statems[&deployment_id];
+                let settled = deployment.state().is_final()
+                    && !deployment.is_retired()
+                    && !deployment.rollout().is_some_and(Rollout::is_progressing);
+                settled.then(|| (id.clone(), deployment_id, autoscaling, scale_to_zero))
+            })
+            .collect();
+        if candidates.is_empty() {
+            return;
+        }
+
+        let workers = self.h.worker_mgr.query_workers().await;
+        for (service_id, deployment_id, autoscaling, scale_to_zero) in candidates {
+            let current = self.deployment_statems[&deployment_id].spec().concurrency;
+            let mut desired = None;
+            // Services scaled to zero are only woken up by requests.
+            if let (Some(policy), false) = (autoscaling, current == 0 && scale_to_zero.is_some()) {
+                let load = self.service_load(&service_id, deployment_id, policy.target, &workers);
+                desired = autoscale::desired_instances(&policy, current, load);
+            }
+            if let Some(ScaleToZero { idle_timeout }) = scale_to_zero {
+                let idle = self.h.balancer.in_flight_requests(&service_id, None) == 0
+                    && self.services[&service_id].autoscale.is_idle(
+                        self.h.balancer.last_request(&service_id),
+                        Duration::from_secs(idle_timeout),
+                    );
+                if current > 0 && idle {
+                    desired = Some(0);
+                }
+            }
+            let Some(desired) = desired else {
+                continue;
+            };
+            info!(%service_id, current, desired, "autoscaling service");
+            self.scale_deployment(deployment_id, desired, &workers);
+            let service = self.services.get_mut(&service_id).unwrap();
+            service.autoscale.record_scale();
+        }
+    }
+
+    /// Returns the load per started instance of the given deployment, in the
+    /// unit of the scaling target.
+    #[allow(clippy::cast_precision_loss)]
+    fn service_load(
+        &self,
+        service_id: &ServiceId,
+        deployment_id: DeploymentId,
+        target: ScalingTarget,
+        workers: &[WorkerDetails],
+    ) -> Option<f64> {
+        let started: Vec<_> = self
+            .deployment_instances(deployment_id)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        if started.is_empty() {
+            return None;
+        }
+        match target {
+            ScalingTarget::Cpu { .. } => {
+                // Instances don't report their own usage, hence the usage of
+                // their workers is used instead.
+                let usages: Vec<_> = started
+                    .iter()
+                    .filter_map(|addr| workers.iter().find(|w| w.addr == *addr))
+                    .filter_map(|w| w.metrics.as_ref().map(|m| m.cpu_usage))
+                    .collect();
+                if usages.is_empty() {
+                    return None;
+                }
+                Some(usages.iter().sum::<f64>() / usages.len() as f64)
+            }
+            ScalingTarget::InFlightRequests { .. } => {
+                let in_flight = self
+                    .h
+                    .balancer
+                    .in_flight_requests(service_id, Some(deployment_id));
+                Some(in_flight as f64 / started.len() as f64)
+            }
+        }
+    }
+
+    /// Sets the desired amount of instances of the given deployment, starting
+    /// or terminating instances to match it.
+    ///
+    /// Returns the started and the terminating instances. Surplus instances
+    /// are drained from the balancer before being terminated (see
+    /// [`Transition::Terminate`]).
+    fn scale_deployment(
+        &mut self,
+        id: DeploymentId,
+        instances: u32,
+        workers: &[WorkerDetails],
+    ) -> (Vec<InstanceId>, Vec<InstanceId>) {
+        let deployment = self.deployment_statems.get_mut(&id).unwrap();
+        deployment.set_concurrency(instances);
+        let service_id = deployment.service_id().clone();
+        if let Some(service) = self.services.get_mut(&*service_id) {
+            service.spec.concurrency = instances;
+        }
+        let res = self.converge_deployment(id, workers);
+        self.persist_service(&service_id);
+        res
+    }
+
+    /// Stores the desired state of the given service (see [`crate::store`]).
+    ///
+    /// Active canaries aren't stored, so that a restart aborts them.
+    fn persist_service(&self, id: &ServiceId) {
+        let Some(service) = self.services.get(id) else {
+            return;
+        };
+        let current = if service.canary.is_some() {
+            service.deployments.iter().nth_back(1)
+        } else {
+            service.deployments.last()
+        };
+        let Some(&deployment_id) = current else {
+            return;
+        };
+        let deployment = &self.deployment_statems[&deployment_id];
+        self.store.append(Entry::ServicePut {
+            record: ServiceRecord {
+                spec: deployment.spec().clone(),
+                deployment_id,
+                policy: deployment.policy(),
+                alloc_strategy: deployment.alloc_strategy(),
+                revisions: service.revisions.iter().cloned().collect(),
+            },
+        });
+    }
+
+    /// Starts or terminates instances of the given deployment, so that it
+    /// runs its desired amount of instances.
+    ///
+    /// Returns the started and the terminating instances.
+    fn converge_deployment(
+        &mut self,
+        id: DeploymentId,
+        workers: &[WorkerDetails],
+    ) -> (Vec<InstanceId>, Vec<InstanceId>) {
+        let deployment = &self.deployment_statems[&id];
+        let spec = deployment.spec().clone();
+        let strategy = deployment.alloc_strategy();
+
+        let mut live: Vec<_> = self
+            .instance_statems
+            .values()
+            .filter(|statem| {
+                // Migration replacements stand for the instances they replace.
+                let replacement = self
+                    .rebalancer
+                    .as_ref()
+                    .is_some_and(|r| r.is_replacement(statem.id()));
+                statem.deployment_id() == id
+                    && !replacement
+                    && matches!(
+                        statem.state(),
+                        instance::State::Pending { .. }
+                            | instance::State::Deploying { .. }
+                            | instance::State::Started
+                    )
+            })
+            .collect();
+        let desired = spec.concurrency as usize;
+        let mut started = Vec::new();
+        let mut terminating = Vec::new();
+        if live.len() < desired {
+            let missing = u32::try_from(desired - live.len()).unwrap();
+            for (instance_id, worker_addr) in self.allocate(workers, &spec, strategy, missing) {
+                let deployment = self.deployment_statems.get_mut(&id).unwrap();
+                deployment.add_instance(instance_id, worker_addr);
+                self.start_instance(instance_id, worker_addr, id, &spec);
+                started.push(instance_id);
+            }
+        } else if live.len() > desired {
+            // Instances which aren't serving traffic yet are terminated first.
+            live.sort_by_key(|statem| match statem.state() {
+                instance::State::Pending { .. } => 0,
+                instance::State::Deploying { .. } => 1,
+                _ => 2,
+            });
+            terminating = live[..live.len() - desired]
+                .iter()
+                .map(|statem| statem.id())
+                .collect();
+            for &instance_id in &terminating {
+                self.trans_instance_state(instance_id, Transition::Terminate);
+            }
+        }
+        (started, terminating)
+    }
+
+    /// Converges the cluster's observed state to the desired state of each
+    /// service, i.e., the spec and amount of instances of its current
+    /// deployments.
+    ///
+    /// Most drift is corrected as soon as it's observed (e.g., see
+    /// [`Self::heal_after_failure`]), but reconciling also corrects the drift
+    /// whose events were missed or whose correction failed.
+    #[instrument(skip(self))]
+    async fn handle_reconcile(&mut self) {
+        self.delayed_msg(self.reconcile_interval, Msg::Reconcile);
+
+        let workers = self.h.worker_mgr.query_workers().await;
+        self.handle_lost_worker(|a| !workers.iter().any(|w| w.addr == a));
+
+        // Only the deployments which the services serve are converged, as
+        // superseded ones (e.g., kept by the `None` policy) must not be healed
+        // back next to their successors. Deployments which are in progress or
+        // retired, and services which are being terminated or backing off from
+        // failures, are left to their own mechanisms.
+        let drifting: Vec<_> = self
+            .services
+            .iter()
+            .filter(|(service_id, service)| {
+                !self.service_terminations.contains_key(*service_id)
+                    && !service.healing.is_backing_off()
+            })
+            .flat_map(|(service_id, service)| {
+                service
+                    .serving_deployments()
+                    .map(move |deployment_id| (deployment_id, service_id.clone()))
+            })
+            .filter(|(deployment_id, _)| {
+                self.deployment_statems
+                    .get(deployment_id)
+                    .is_some_and(|deployment| {
+                        deployment.state().is_final()
+                            && !deployment.is_retired()
+                            && !deployment.rollout().is_some_and(Rollout::is_progressing)
+                    })
+            })
+            .collect();
+        for (deployment_id, service_id) in drifting {
+            let (started, terminating) = self.converge_deployment(deployment_id, &workers);
+            if started.is_empty() && terminating.is_empty() {
+                continue;
+            }
+            info!(
+                %deployment_id,
+                started = started.len(),
+                terminating = terminating.len(),
+                "reconciled drifted deployment"
+            );
+            // Failed replacements are backed off as the healing ones.
+            let service = self.services.get_mut(&service_id).unwrap();
+            for instance_id in started {
+                service.healing.add_replacement(instance_id);
+            }
+        }
+
+        self.handle_place_pending(false).await;
+    }
+
+    /// Propagates an instance's final state to its service state machine, if
+    /// the corresponding service is being terminated.
+    fn trans_service_termination(&mut self, instance: &instance::StateCtx) {
+        let service_id = instance.service_id();
+        let Some(ctx) = self.service_terminations.get_mut(service_id) else {
+            return;
+        };
+        ctx.record(instance.id(), instance.state().public());
+        if ctx.is_done() {
+            let ctx = self.service_terminations.remove(service_id).unwrap();
+            info!(%service_id, "finished service termination");
+            self.forget_service(service_id);
+            ctx.finish();
+        }
+    }
+
+    /// Forgets the service's retired deployments which have no instances left,
+    /// unless one of its retained revisions refers to them.
+    fn prune_deployments(&mut self, id: &ServiceId) {
+        let Some(service) = self.services.get_mut(id) else {
+            return;
+        };
+        let referenced: HashSet<_> = service.revisions.iter().map(|r| r.deployment_id).collect();
+        let deployments = &mut self.deployment_statems;
+        let instances = &self.instance_statems;
+        service.deployments.retain(|deployment_id| {
+            let keep = referenced.contains(deployment_id)
+                || deployments
+                    .get(deployment_id)
+                    .is_some_and(|d| !d.is_retired())
+                || instances
+                    .values()
+                    .any(|i| i.deployment_id() == *deployment_id);
+            if !keep {
+                trace!(%deployment_id, "forgetting retired deployment");
+                deployments.remove(deployment_id);
+            }
+            keep
+        });
+    }
+
+    /// Forgets a terminated service, along with its deployments.
+    fn forget_service(&mut self, id: &ServiceId) {
+        let Some(service) = self.services.remove(id) else {
+            return;
+        };
+        self.h.balancer.drop_service(id);
+        for deployment_id in service.deployments {
+            self.deployment_statems.remove(&deployment_id);
         }
     }
 }
@@ -215,6 +1957,16 @@ impl Deployer {
                 .await;
         });
     }
+
+    /// Spawns a task which sends the given message to the deployer after the
+    /// provided delay.
+    fn delayed_msg(&mut self, delay: Duration, msg: Msg) {
+        let h = self.h.clone();
+        self.tasks.spawn(async move {
+            time::sleep(delay).await;
+            h.deployer_handle.send(msg).await;
+        });
+    }
 }
 
 #[derive(Clone)]
@@ -235,70 +1987,313 @@ impl DeployerHandle {
         rx.await.expect("actor must be alive")
     }
 
-    pub async fn deploy_service(&self, spec: ServiceSpec) -> eyre::Result<DeployServiceRes> {
-        self.send_wait(|r| Msg::DeployService(spec, r)).await
+    pub async fn deploy_service(
+        &self,
+        spec: ServiceSpec,
+        policy: RedeploymentPolicy,
+        strategy: AllocStrategy,
+    ) -> http::Result<DeployServiceRes> {
+        self.send_wait(|r| Msg::DeployService(spec, policy, strategy, r))
+            .await
+    }
+
+    pub async fn list_services(&self) -> ListServicesRes {
+        self.send_wait(Msg::ListServices).await
+    }
+
+    pub async fn show_service(&self, id: ServiceId) -> Option<ShowServiceRes> {
+        self.send_wait(|r| Msg::ShowService(id, r)).await
+    }
+
+    pub async fn deployment_status(&self, id: DeploymentId) -> Option<QueryDeploymentStatusRes> {
+        self.send_wait(|r| Msg::QueryDeploymentStatus(id, r)).await
+    }
+
+    pub async fn list_revisions(&self, id: ServiceId) -> Option<ListRevisionsRes> {
+        self.send_wait(|r| Msg::ListRevisions(id, r)).await
+    }
+
+    pub async fn rollback_service(
+        &self,
+        id: ServiceId,
+        revision_id: RevisionId,
+    ) -> http::Result<DeployServiceRes> {
+        self.send_wait(|r| Msg::RollbackService(id, revision_id, r))
+            .await
+    }
+
+    pub async fn promote_canary(&self, id: ServiceId) -> http::Result<PromoteCanaryRes> {
+        self.send_wait(|r| Msg::PromoteCanary(id, r)).await
+    }
+
+    pub async fn abort_canary(&self, id: ServiceId) -> http::Result<AbortCanaryRes> {
+        self.send_wait(|r| Msg::AbortCanary(id, r)).await
+    }
+
+    pub async fn scale_service(
+        &self,
+        id: ServiceId,
+        concurrency: u32,
+    ) -> http::Result<ScaleServiceRes> {
+        self.send_wait(|r| Msg::ScaleService(id, concurrency, r))
+            .await
+    }
+
+    /// Starts an instance of a service which was scaled to zero.
+    pub async fn wake_service(&self, id: ServiceId) -> http::Result<()> {
+        self.send_wait(|r| Msg::WakeService(id, r)).await
     }
 
-    pub async fn terminate_service(&self, id: ServiceId) -> eyre::Result<()> {
+    pub async fn terminate_service(&self, id: ServiceId) -> http::Result<TerminateServiceRes> {
         self.send_wait(|r| Msg::TerminateService(id, r)).await
     }
 
-    pub async fn report_instance_status(&self, id: InstanceId, status: proto_instance::Status) {
-        self.send(Msg::ReportInstanceStatus(id, status)).await;
+    /// Applies a status report sent by the instance's worker, at `addr`.
+    pub async fn report_instance_status(
+        &self,
+        id: InstanceId,
+        addr: IpAddr,
+        seq: u64,
+        status: proto_instance::Status,
+    ) {
+        self.send(Msg::ReportInstanceStatus(id, addr, seq, status))
+            .await;
+    }
+
+    /// Reconciles the instances of a restarted worker with the ones whose
+    /// containers survived, returning the adopted ones.
+    pub async fn sync_worker_instances(
+        &self,
+        addr: IpAddr,
+        instances: Vec<InstanceId>,
+    ) -> HashMap<InstanceId, u64> {
+        self.send_wait(|r| Msg::SyncWorkerInstances(addr, instances, r))
+            .await
     }
 }
 
 #[derive(Debug)]
 enum Msg {
-    DeployService(ServiceSpec, oneshot::Sender<eyre::Result<DeployServiceRes>>),
-    TerminateService(ServiceId, oneshot::Sender<eyre::Result<()>>),
-    ReportInstanceStatus(InstanceId, proto_instance::Status),
+    DeployService(
+        ServiceSpec,
+        RedeploymentPolicy,
+        AllocStrategy,
+        oneshot::Sender<http::Result<DeployServiceRes>>,
+    ),
+    ListServices(oneshot::Sender<ListServicesRes>),
+    ShowService(ServiceId, oneshot::Sender<Option<ShowServiceRes>>),
+    QueryDeploymentStatus(
+        DeploymentId,
+        oneshot::Sender<Option<QueryDeploymentStatusRes>>,
+    ),
+    ListRevisions(ServiceId, oneshot::Sender<Option<ListRevisionsRes>>),
+    RollbackService(
+        ServiceId,
+        RevisionId,
+        oneshot::Sender<http::Result<DeployServiceRes>>,
+    ),
+    PromoteCanary(ServiceId, oneshot::Sender<http::Result<PromoteCanaryRes>>),
+    AbortCanary(ServiceId, oneshot::Sender<http::Result<AbortCanaryRes>>),
+    ScaleService(
+        ServiceId,
+        u32,
+        oneshot::Sender<http::Result<ScaleServiceRes>>,
+    ),
+    WakeService(ServiceId, oneshot::Sender<http::Result<()>>),
+    TerminateService(ServiceId, TerminationReply),
+    ReportInstanceStatus(InstanceId, IpAddr, u64, proto_instance::Status),
+    SyncWorkerInstances(
+        IpAddr,
+        Vec<InstanceId>,
+        oneshot::Sender<HashMap<InstanceId, u64>>,
+    ),
     // Internal messages
     InstanceTransition(InstanceId, Transition),
+    HealDeployment(DeploymentId),
+    /// Tries to place the pending instances. Retries are scheduled by the
+    /// deployer itself, whereas other attempts are triggered by freed capacity.
+    PlacePending {
+        retry: bool,
+    },
+    /// Runs a rebalancing pass. Ticks also start a new rebalancing interval.
+    Rebalance {
+        tick: bool,
+    },
+    Autoscale,
+    Reconcile,
 }
 
-/*
-================================================================================
-(TODO: DATA RECORDS)
-
-    // data records {
-services: HashMap<ServiceId, ServiceInfo>,
-instances: HashMap<InstanceId, instance::State>,
-deployments: HashMap<DeploymentId, DeploymentInfo>,
-                    }
+/// Validates the service's autoscaling policy, if any, and brings its initial
+/// amount of instances within the policy's bounds.
+fn check_autoscaling(spec: &mut ServiceSpec) -> http::Result<()> {
+    let Some(policy) = spec.autoscaling else {
+        return Ok(());
+    };
+    let bad_request = |msg| Err(http::Error::public(StatusCode::BAD_REQUEST, msg));
+    if policy.max == 0 || policy.min > policy.max {
+        return bad_request("autoscaling requires `0 < max` and `min <= max`");
+    }
+    if autoscale::target_load(policy.target) <= 0.0 {
+        return bad_request("autoscaling target must be positive");
+    }
+    spec.concurrency = spec.concurrency.clamp(policy.min, policy.max);
+    Ok(())
+}
 
-lista de serviços rodando
+/// Fixtures shared by the deployer's tests.
+#[cfg(test)]
+mod test_util {
+    use super::*;
+    use crate::{balancer::BalancerState, worker_mgr::WorkerMgr};
 
-show (service id)
--> instâncias rodando (e os respectivos deployments)
-ShowResponse {
-  deployments: Vec<(DeploymentId, Vec<(InstanceId, InstanceState)>)>
+    /// Creates a deployer with an empty worker pool.
+    pub fn deployer() -> Deployer {
+        let (_, balancer) = BalancerState::new();
+        let (worker_mgr, worker_mgr_handle) =
+            WorkerMgr::new(Duration::from_secs(1), StoreHandle::disabled());
+        tokio::spawn(worker_mgr.run());
+        let config = DeployerConfig {
+            rebalance: None,
+            autoscale: AutoscaleConfig {
+                interval: Duration::from_secs(1),
+                cooldown: Duration::from_secs(1),
+            },
+            reconcile_interval: Duration::from_secs(1),
+            state_timeouts: StateTimeouts {
+                deploying: Duration::from_secs(1),
+                pre_terminating: Duration::from_secs(1),
+                terminating: Duration::from_secs(1),
+            },
+        };
+        let (deployer, _) = Deployer::new(
+            balancer,
+            worker_mgr_handle,
+            WorkerClient::new(),
+            Scheduler::new(&[], &[]),
+            config,
+            StoreHandle::disabled(),
+        );
+        deployer
+    }
 }
 
-struct DeploymentStateCtx {
-    service_id: ServiceId,
-    id: DeploymentId,
-    // instance_deployment_results: Vec<_>
-    // state: State,
-}
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse as _;
+    use proto::common::service::{Placement, ResourceConfig, ServiceImage};
 
-#[derive(Default)]
-pub struct ServiceInfo {
-    pub instances: Vec<InstanceId>,
-    pub deployments: Vec<DeploymentInfo>,
-}
+    use super::{test_util::deployer, *};
 
-impl ServiceInfo {
-    pub fn last_deployment(&self) -> Option<&DeploymentInfo> {
-        self.deployments.last()
+    fn spec(service_id: &ServiceId, image: &str) -> ServiceSpec {
+        ServiceSpec {
+            service_id: service_id.clone(),
+            image: ServiceImage(image.into()),
+            public: true,
+            concurrency: 1,
+            resource_config: ResourceConfig {
+                cpu_shares: 1024,
+                memory_limit: 128,
+            },
+            placement: Placement::default(),
+            autoscaling: None,
+            scale_to_zero: None,
+        }
     }
-}
 
-#[derive(Debug)]
-pub struct DeploymentInfo {
-    pub id: DeploymentId,
-    pub at: DateTime<Utc>,
-    pub alive_instances: InstanceId,
+    fn revision(service_id: &ServiceId, image: &str, strategy: AllocStrategy) -> RevisionInfo {
+        RevisionInfo {
+            revision_id: RevisionId(Uuid::now_v7()),
+            service_spec: spec(service_id, image),
+            deployment_id: DeploymentId(Uuid::now_v7()),
+            alloc_strategy: strategy,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rolls_back_to_revision_whose_deployment_was_not_restored() {
+        let mut deployer = deployer();
+        let service_id = ServiceId("service".into());
+        let old = revision(&service_id, "old", AllocStrategy::BestFit);
+        let current = revision(&service_id, "current", AllocStrategy::RoundRobin);
+        let record = ServiceRecord {
+            spec: current.service_spec.clone(),
+            deployment_id: current.deployment_id,
+            policy: RedeploymentPolicy::Replace,
+            alloc_strategy: current.alloc_strategy,
+            revisions: vec![old.clone(), current],
+        };
+        let snapshot = store::Snapshot {
+            services: HashMap::from([(service_id.clone(), record)]),
+            ..Default::default()
+        };
+        deployer.restore(&snapshot).await;
+
+        deployer
+            .handle_rollback_service(&service_id, old.revision_id)
+            .await
+            .unwrap();
+
+        let latest = *deployer.services[&service_id].deployments.last().unwrap();
+        let deployment = &deployer.deployment_statems[&latest];
+        assert_eq!(deployment.spec().image.0, "old");
+        assert_eq!(deployment.alloc_strategy(), AllocStrategy::BestFit);
+    }
+
+    #[tokio::test]
+    async fn stalled_deployments_are_superseded() {
+        let mut deployer = deployer();
+        let service_id = ServiceId("service".into());
+        // With an empty pool, the instances are left pending.
+        let first = deployer
+            .handle_deploy_service(
+                spec(&service_id, "first"),
+                RedeploymentPolicy::None,
+                AllocStrategy::RoundRobin,
+            )
+            .await
+            .unwrap();
+
+        deployer
+            .handle_deploy_service(
+                spec(&service_id, "second"),
+                RedeploymentPolicy::None,
+                AllocStrategy::RoundRobin,
+            )
+            .await
+            .unwrap();
+
+        let first = first.deployment_id;
+        assert!(deployer.deployment_statems[&first].state().is_final());
+        assert!(deployer.deployment_statems[&first].is_retired());
+        let mut pending = deployer.instance_statems.values();
+        assert!(pending.all(|statem| statem.deployment_id() != first));
+    }
+
+    async fn terminate(
+        deployer: &mut Deployer,
+        id: &ServiceId,
+    ) -> http::Result<TerminateServiceRes> {
+        let (tx, rx) = oneshot::channel();
+        deployer.handle_terminate_service(id.clone(), tx);
+        rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn terminated_services_are_forgotten() {
+        let mut deployer = deployer();
+        let service_id = ServiceId("service".into());
+        let spec = spec(&service_id, "image");
+        deployer
+            .handle_deploy_service(spec, RedeploymentPolicy::None, AllocStrategy::RoundRobin)
+            .await
+            .unwrap();
+
+        terminate(&mut deployer, &service_id).await.unwrap();
+        assert!(deployer.handle_list_services().services.is_empty());
+        assert!(deployer.deployment_statems.is_empty());
+
+        let error = terminate(&mut deployer, &service_id).await.unwrap_err();
+        assert_eq!(error.into_response().status(), StatusCode::NOT_FOUND);
+    }
 }
-================================================================================
-*/
@@ -0,0 +1,201 @@
+//! Rebalancing of instances across the cluster's workers.
+//!
+//! Workers which join the cluster after a service is deployed get none of its
+//! instances, so that earlier workers end up overloaded. The rebalancer
+//! periodically moves instances from the most loaded workers into the least
+//! loaded ones.
+//!
+//! Instances are migrated make-before-break: a replacement is started in the
+//! target worker, and the original instance is only terminated once the
+//! replacement has started (and thus was added to the balancer).
+
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use proto::common::instance::InstanceId;
+
+/// The minimum difference in CPU usage (in percentage points) between two
+/// workers for an instance to be moved, when both hold about the same amount
+/// of instances.
+const CPU_SKEW_THRESHOLD: f64 = 25.0;
+
+#[derive(Debug, Copy, Clone)]
+pub struct RebalanceConfig {
+    /// Interval between rebalancing passes.
+    pub interval: Duration,
+    /// The maximum amount of instances moved in each interval.
+    pub max_moves: u32,
+}
+
+#[derive(Debug)]
+pub struct Rebalancer {
+    config: RebalanceConfig,
+    /// Moves which may still be started in the current interval.
+    moves_left: u32,
+    /// In-flight migrations, from each replacement instance to the instance
+    /// it replaces.
+    migrations: HashMap<InstanceId, InstanceId>,
+}
+
+impl Rebalancer {
+    pub fn new(config: RebalanceConfig) -> Self {
+        Rebalancer {
+            config,
+            moves_left: config.max_moves,
+            migrations: HashMap::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Starts a new interval, resetting the move budget.
+    pub fn reset_budget(&mut self) {
+        self.moves_left = self.config.max_moves;
+    }
+
+    pub fn has_budget(&self) -> bool {
+        self.moves_left > 0
+    }
+
+    pub fn start_migration(&mut self, replacement: InstanceId, original: InstanceId) {
+        self.moves_left = self.moves_left.saturating_sub(1);
+        self.migrations.insert(replacement, original);
+    }
+
+    /// Whether the given instance is either being migrated or is the
+    /// replacement of a migrated instance.
+    pub fn is_migrating(&self, id: InstanceId) -> bool {
+        self.migrations.contains_key(&id) || self.migrations.values().any(|&o| o == id)
+    }
+
+    pub fn is_replacement(&self, id: InstanceId) -> bool {
+        self.migrations.contains_key(&id)
+    }
+
+    /// Forgets the migration of the given replacement instance, returning the
+    /// instance it replaces.
+    pub fn finish_migration(&mut self, replacement: InstanceId) -> Option<InstanceId> {
+        self.migrations.remove(&replacement)
+    }
+}
+
+/// The load of a worker, as seen by the rebalancer.
+#[derive(Debug, Copy, Clone)]
+pub struct WorkerLoad {
+    /// The amount of live instances placed into the worker.
+    pub instances: u32,
+    /// The worker's last reported CPU usage, in percentage, or `None` if it
+    /// hasn't reported any metrics yet.
+    pub cpu_usage: Option<f64>,
+}
+
+/// A planned instance move between two workers.
+#[derive(Debug, Copy, Clone)]
+pub struct Move {
+    pub from: IpAddr,
+    pub to: IpAddr,
+    /// Whether the move was planned due to CPU usage skew, rather than due to
+    /// instance count skew.
+    pub by_metrics: bool,
+}
+
+/// Plans the next move which reduces the skew between the given workers.
+///
+/// Instance count skew is considered first. Only if all workers hold about the
+/// same amount of instances is the CPU usage skew considered.
+pub fn plan_move(loads: &HashMap<IpAddr, WorkerLoad>) -> Option<Move> {
+    let busiest = loads.iter().max_by_key(|(_, l)| l.instances)?;
+    let idlest = loads.iter().min_by_key(|(_, l)| l.instances)?;
+    if busiest.1.instances > idlest.1.instances + 1 {
+        return Some(Move {
+            from: *busiest.0,
+            to: *idlest.0,
+            by_metrics: false,
+        });
+    }
+
+    // Workers of unknown usage can't be compared.
+    let usages = || {
+        loads
+            .iter()
+            .filter_map(|(addr, l)| Some((addr, l.instances, l.cpu_usage?)))
+    };
+    let hottest = usages()
+        .filter(|(_, instances, _)| *instances > 0)
+        .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b))?;
+    let coldest = usages().min_by(|(_, _, a), (_, _, b)| a.total_cmp(b))?;
+    // Moving an instance into a worker with more instances would only trade
+    // one skew for the other.
+    let skewed = hottest.2 - coldest.2 > CPU_SKEW_THRESHOLD && coldest.1 <= hottest.1;
+    skewed.then_some(Move {
+        from: *hottest.0,
+        to: *coldest.0,
+        by_metrics: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn pool(loads: &[(u8, u32, Option<f64>)]) -> HashMap<IpAddr, WorkerLoad> {
+        loads
+            .iter()
+            .map(|&(n, instances, cpu_usage)| {
+                let load = WorkerLoad {
+                    instances,
+                    cpu_usage,
+                };
+                (addr(n), load)
+            })
+            .collect()
+    }
+
+    fn addr(addr: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, addr])
+    }
+
+    #[test]
+    fn instance_count_skew_is_reduced_first() {
+        let loads = pool(&[(1, 4, Some(10.0)), (2, 1, Some(90.0))]);
+        let mv = plan_move(&loads).unwrap();
+        assert_eq!((mv.from, mv.to, mv.by_metrics), (addr(1), addr(2), false));
+    }
+
+    #[test]
+    fn cpu_usage_skew_is_reduced_once_counts_are_even() {
+        let loads = pool(&[(1, 2, Some(90.0)), (2, 2, Some(10.0))]);
+        let mv = plan_move(&loads).unwrap();
+        assert_eq!((mv.from, mv.to, mv.by_metrics), (addr(1), addr(2), true));
+
+        let loads = pool(&[(1, 2, Some(50.0)), (2, 2, Some(40.0))]);
+        assert!(plan_move(&loads).is_none());
+    }
+
+    #[test]
+    fn workers_of_unknown_usage_are_not_compared() {
+        let loads = pool(&[(1, 2, Some(90.0)), (2, 2, None)]);
+        assert!(plan_move(&loads).is_none());
+    }
+
+    #[test]
+    fn migration_budget_is_reset_every_interval() {
+        let mut rebalancer = Rebalancer::new(RebalanceConfig {
+            interval: Duration::from_secs(1),
+            max_moves: 1,
+        });
+        let original = InstanceId(Uuid::now_v7());
+        let replacement = InstanceId(Uuid::now_v7());
+        rebalancer.start_migration(replacement, original);
+        assert!(!rebalancer.has_budget());
+        assert!(rebalancer.is_migrating(original) && rebalancer.is_replacement(replacement));
+
+        assert_eq!(rebalancer.finish_migration(replacement), Some(original));
+        assert!(!rebalancer.is_migrating(original));
+        rebalancer.reset_budget();
+        assert!(rebalancer.has_budget());
+    }
+}
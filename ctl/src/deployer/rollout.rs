@@ -0,0 +1,218 @@
+//! Rolling update planning.
+//!
+//! A rollout gradually replaces the instances of a service's previous
+//! deployments by the instances of a new deployment. The deployer calls into
+//! the rollout every time one of the new instances reports its deploy outcome,
+//! so that the next batch may be started.
+
+use std::{collections::VecDeque, net::IpAddr};
+
+use proto::{
+    common::instance::InstanceId,
+    ctl::deployer::{RolloutState, RolloutStatus},
+};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct Rollout {
+    state: RolloutState,
+    /// The desired amount of new instances.
+    desired: u32,
+    max_surge: u32,
+    max_unavailable: u32,
+    /// New instances which were allocated (though possibly not placed) but not
+    /// yet started.
+    queued: VecDeque<(InstanceId, Option<IpAddr>)>,
+    /// The amount of new instances which have failed to start.
+    failed: u32,
+    /// The amount of failed new instances after which the rollout is aborted.
+    failure_budget: u32,
+}
+
+/// How a rollout handles the failure of one of its new instances.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The failed instance was replaced by the given one, which is queued for
+    /// a later batch.
+    Replaced(InstanceId),
+    /// The rollout was aborted. The returned queued instances won't ever be
+    /// started.
+    Aborted(Vec<InstanceId>),
+}
+
+/// A snapshot of the service's instances, used to plan the next rollout step.
+#[derive(Debug, Copy, Clone)]
+pub struct Census {
+    /// Started instances from previous deployments.
+    pub old_started: u32,
+    /// Started instances from the rolling deployment.
+    pub new_started: u32,
+    /// Instances from the rolling deployment which are still being deployed.
+    pub new_deploying: u32,
+}
+
+impl Rollout {
+    pub fn new(
+        max_surge: u32,
+        max_unavailable: u32,
+        instances: impl IntoIterator<Item = (InstanceId, Option<IpAddr>)>,
+    ) -> Self {
+        let queued: VecDeque<_> = instances.into_iter().collect();
+        Rollout {
+            state: RolloutState::Progressing,
+            desired: u32::try_from(queued.len()).unwrap(),
+            max_surge,
+            max_unavailable,
+            queued,
+            failed: 0,
+            failure_budget: max_surge.max(max_unavailable).max(1),
+        }
+    }
+
+    pub fn is_progressing(&self) -> bool {
+        self.state == RolloutState::Progressing
+    }
+
+    /// Records that one of the new instances has failed to start.
+    ///
+    /// The failed instance is replaced until the failure budget (the largest
+    /// of `max_surge` and `max_unavailable`, or one if both are zero) is
+    /// exceeded, after which the rollout is aborted.
+    pub fn record_failure(&mut self) -> Failure {
+        if !self.is_progressing() {
+            return Failure::Aborted(Vec::new());
+        }
+        self.failed += 1;
+        if self.failed <= self.failure_budget {
+            let replacement = InstanceId(Uuid::now_v7());
+            info!(failed = self.failed, %replacement, "replacing failed instance");
+            self.queued.push_back((replacement, None));
+            return Failure::Replaced(replacement);
+        }
+        warn!(
+            failed = self.failed,
+            "aborting rollout, too many failed instances"
+        );
+        Failure::Aborted(self.abort())
+    }
+
+    /// Aborts the rollout, returning its queued instances, which won't ever be
+    /// started.
+    pub fn abort(&mut self) -> Vec<InstanceId> {
+        self.state = RolloutState::Aborted;
+        self.queued.drain(..).map(|(id, _)| id).collect()
+    }
+
+    /// Returns how many of the old instances may be terminated without
+    /// dropping below the minimum amount of available instances.
+    ///
+    /// While the rollout is aborted, previous instances are kept.
+    pub fn terminable(&self, census: Census) -> u32 {
+        if self.state == RolloutState::Aborted {
+            return 0;
+        }
+        let available = census.old_started + census.new_started;
+        let min_available = self.desired.saturating_sub(self.max_unavailable);
+        available
+            .saturating_sub(min_available)
+            .min(census.old_started)
+    }
+
+    /// Returns the new instances which may be started without exceeding the
+    /// maximum amount of instances (desired plus surge).
+    pub fn next_batch(&mut self, census: Census) -> Vec<(InstanceId, Option<IpAddr>)> {
+        if !self.is_progressing() {
+            return Vec::new();
+        }
+        let total = census.old_started + census.new_started + census.new_deploying;
+        let can_start = (self.desired + self.max_surge).saturating_sub(total) as usize;
+        let n = can_start.min(self.queued.len());
+        let batch: Vec<_> = self.queued.drain(..n).collect();
+
+        if self.queued.is_empty() && batch.is_empty() && census.new_deploying == 0 {
+            info!(failed = self.failed, "finished rollout");
+            self.state = RolloutState::Completed;
+        }
+        batch
+    }
+
+    pub fn status(&self) -> RolloutStatus {
+        RolloutStatus {
+            state: self.state,
+            queued: u32::try_from(self.queued.len()).unwrap(),
+            failed: self.failed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rollout(max_surge: u32, max_unavailable: u32, desired: usize) -> Rollout {
+        let instances = (0..desired).map(|_| (InstanceId(Uuid::now_v7()), None));
+        Rollout::new(max_surge, max_unavailable, instances)
+    }
+
+    fn census(old_started: u32, new_started: u32, new_deploying: u32) -> Census {
+        Census {
+            old_started,
+            new_started,
+            new_deploying,
+        }
+    }
+
+    #[test]
+    fn failed_instances_are_replaced_within_budget() {
+        // Without surge, a single failure must not abort the rollout.
+        let mut rollout = rollout(0, 2, 2);
+        let batch = rollout.next_batch(census(0, 0, 0));
+        assert_eq!(batch.len(), 2);
+
+        let Failure::Replaced(first) = rollout.record_failure() else {
+            panic!("first failure must be replaced");
+        };
+        let Failure::Replaced(second) = rollout.record_failure() else {
+            panic!("second failure must be replaced");
+        };
+        assert!(rollout.is_progressing());
+        assert_eq!(rollout.status().failed, 2);
+
+        // Replacements are started in later batches.
+        let batch = rollout.next_batch(census(0, 0, 0));
+        let ids: Vec<_> = batch.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, [first, second]);
+    }
+
+    #[test]
+    fn rollout_is_aborted_past_the_failure_budget() {
+        let mut rollout = rollout(1, 0, 3);
+        assert_eq!(rollout.next_batch(census(3, 0, 0)).len(), 1);
+
+        assert!(matches!(rollout.record_failure(), Failure::Replaced(_)));
+        // The replacement and the remaining instances are dropped.
+        let Failure::Aborted(dropped) = rollout.record_failure() else {
+            panic!("rollout must be aborted");
+        };
+        assert_eq!(dropped.len(), 3);
+        assert_eq!(rollout.status().state, RolloutState::Aborted);
+        assert_eq!(rollout.status().queued, 0);
+
+        // Previous instances are kept, and no more instances are started.
+        assert_eq!(rollout.terminable(census(3, 0, 0)), 0);
+        assert!(rollout.next_batch(census(3, 0, 0)).is_empty());
+        assert_eq!(rollout.record_failure(), Failure::Aborted(Vec::new()));
+    }
+
+    #[test]
+    fn progressing_rollout_keeps_enough_available_instances() {
+        let mut rollout = rollout(1, 1, 3);
+        assert_eq!(rollout.terminable(census(3, 0, 0)), 1);
+        assert_eq!(rollout.next_batch(census(2, 0, 0)).len(), 2);
+        assert_eq!(rollout.terminable(census(2, 1, 1)), 1);
+        assert_eq!(rollout.next_batch(census(1, 1, 1)).len(), 1);
+        assert!(rollout.next_batch(census(0, 3, 0)).is_empty());
+        assert_eq!(rollout.status().state, RolloutState::Completed);
+    }
+}
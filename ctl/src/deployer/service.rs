@@ -0,0 +1,185 @@
+//! Service-level records and state machine.
+//!
+//! While the instance state machine (see [`super::instance`]) tracks a single
+//! instance, the service state machine aggregates the outcomes of all instances
+//! of a service which is being terminated. See `docs/statem.md` for its
+//! diagram.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chrono::{DateTime, Utc};
+use proto::{
+    common::{
+        instance::InstanceId,
+        service::{ServiceId, ServiceSpec},
+    },
+    ctl::deployer::{
+        DeploymentId, InstanceState, RevisionInfo, TerminateServiceRes, TerminationOutcome,
+    },
+};
+use tokio::sync::oneshot;
+use tracing::{instrument, trace};
+use utils::http;
+
+use crate::deployer::{autoscale::Autoscale, healing::Healing};
+
+/// The maximum amount of revisions kept for each service.
+const REVISION_RETENTION: usize = 10;
+
+/// The controller's records of a service.
+#[derive(Debug)]
+pub struct ServiceInfo {
+    /// The spec of the service's latest deployment.
+    pub spec: ServiceSpec,
+    /// The service's deployments, ordered from oldest to newest.
+    pub deployments: Vec<DeploymentId>,
+    /// The service's active canary deployment, if any.
+    pub canary: Option<Canary>,
+    /// The service's latest revisions, ordered from oldest to newest.
+    pub revisions: VecDeque<RevisionInfo>,
+    pub healing: Healing,
+    pub autoscale: Autoscale,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A canary deployment which is yet to be promoted or aborted.
+#[derive(Debug)]
+pub struct Canary {
+    pub deployment_id: DeploymentId,
+    /// The service's spec before the canary was deployed, restored if the
+    /// canary gets aborted.
+    pub previous_spec: ServiceSpec,
+}
+
+impl ServiceInfo {
+    /// Returns the deployments whose instances the service serves: the latest
+    /// one and, while a canary is active, the one it's compared against.
+    pub fn serving_deployments(&self) -> impl Iterator<Item = DeploymentId> + '_ {
+        let count = if self.canary.is_some() { 2 } else { 1 };
+        self.deployments.iter().rev().take(count).copied()
+    }
+
+    pub fn new(spec: ServiceSpec) -> Self {
+        ServiceInfo {
+            spec,
+            deployments: Vec::new(),
+            canary: None,
+            revisions: VecDeque::new(),
+            healing: Healing::default(),
+            autoscale: Autoscale::default(),
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Records a new revision, forgetting the oldest ones past the retention
+    /// limit.
+    pub fn record_revision(&mut self, revision: RevisionInfo) {
+        self.revisions.push_back(revision);
+        while self.revisions.len() > REVISION_RETENTION {
+            self.revisions.pop_front();
+        }
+    }
+}
+
+pub type TerminationReply = oneshot::Sender<http::Result<TerminateServiceRes>>;
+
+/// Tracks the termination of all instances of a given service.
+#[derive(Debug)]
+pub struct TerminationCtx {
+    state: TerminationState,
+    service_id: ServiceId,
+    /// Instances whose termination is still in progress.
+    pending: HashSet<InstanceId>,
+    /// The final state of each instance whose termination has finished.
+    finished: HashMap<InstanceId, InstanceState>,
+    reply: TerminationReply,
+}
+
+impl TerminationCtx {
+    pub fn new(
+        service_id: ServiceId,
+        instances: impl IntoIterator<Item = InstanceId>,
+        reply: TerminationReply,
+    ) -> Self {
+        TerminationCtx {
+            state: TerminationState::Terminating,
+            service_id,
+            pending: instances.into_iter().collect(),
+            finished: HashMap::new(),
+            reply,
+        }
+    }
+
+    /// Records the final state of one of the instances that are being
+    /// terminated.
+    #[instrument(skip(self), fields(service_id = %self.service_id))]
+    pub fn record(&mut self, id: InstanceId, final_state: InstanceState) {
+        if !self.pending.remove(&id) {
+            return;
+        }
+        self.finished.insert(id, final_state);
+
+        let ok = matches!(
+            final_state,
+            InstanceState::Terminated | InstanceState::NeverStarted
+        );
+        let done = self.pending.is_empty();
+        let next = next(self.state, ok, done);
+        trace!(from = ?self.state, to = ?next, "service termination transition");
+        self.state = next;
+    }
+
+    /// Whether all instances have reached a final state.
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self.state,
+            TerminationState::CompleteTermination | TerminationState::IncompleteTermination
+        )
+    }
+
+    /// Sends the termination outcome to the requester.
+    ///
+    /// Must only be called once [`Self::is_done`] holds.
+    pub fn finish(self) {
+        let outcome = match self.state {
+            TerminationState::CompleteTermination => TerminationOutcome::Complete,
+            TerminationState::IncompleteTermination => TerminationOutcome::Incomplete,
+            s => unreachable!("finished service termination in non-final state `{s:?}`"),
+        };
+        _ = self.reply.send(Ok(TerminateServiceRes {
+            outcome,
+            instances: self.finished,
+        }));
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TerminationState {
+    Terminating,
+    PartialTerminatingOk,
+    PartialTerminatingErr,
+    CompleteTermination,
+    IncompleteTermination,
+}
+
+/// Computes the next service termination state, given whether the last
+/// instance terminated successfully and whether there are no more instances
+/// left to terminate.
+fn next(current: TerminationState, ok: bool, done: bool) -> TerminationState {
+    use TerminationState::*;
+
+    let partial = match (current, ok) {
+        (Terminating | PartialTerminatingOk, true) => PartialTerminatingOk,
+        (Terminating | PartialTerminatingOk, false) | (PartialTerminatingErr, _) => {
+            PartialTerminatingErr
+        }
+        (s @ (CompleteTermination | IncompleteTermination), _) => {
+            unreachable!("unexpected instance outcome for final state `{s:?}`")
+        }
+    };
+    match (partial, done) {
+        (PartialTerminatingOk, true) => CompleteTermination,
+        (PartialTerminatingErr, true) => IncompleteTermination,
+        (s, _) => s,
+    }
+}
@@ -1,9 +1,19 @@
-use axum::{extract::State, Json};
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    Json,
+};
 use proto::ctl::deployer::{
-    DeployServiceReq, DeployServiceRes, ReportDeployInstanceStatusReq,
-    ReportDeployInstanceStatusRes, TerminateServiceReq, TerminateServiceRes,
+    AbortCanaryReq, AbortCanaryRes, DeployServiceReq, DeployServiceRes, ListRevisionsReq,
+    ListRevisionsRes, ListServicesReq, ListServicesRes, PromoteCanaryReq, PromoteCanaryRes,
+    QueryDeploymentStatusReq, QueryDeploymentStatusRes, ReportDeployInstanceStatusReq,
+    ReportDeployInstanceStatusRes, RollbackServiceReq, ScaleServiceReq, ScaleServiceRes,
+    ShowServiceReq, ShowServiceRes, SyncWorkerInstancesReq, SyncWorkerInstancesRes,
+    TerminateServiceReq, TerminateServiceRes,
 };
-use utils::http;
+use utils::http::{self, OptionExt as _};
 
 use crate::http::HttpState;
 
@@ -11,11 +21,101 @@ pub async fn deploy_service(
     State(state): State<HttpState>,
     Json(DeployServiceReq {
         service_spec,
-        // TODO: Use redeployment policy
-        redeployment_policy: _,
+        redeployment_policy,
+        alloc_strategy,
     }): Json<DeployServiceReq>,
 ) -> http::Result<Json<DeployServiceRes>> {
-    let res = state.deployer.deploy_service(service_spec).await?;
+    let res = state
+        .deployer
+        .deploy_service(service_spec, redeployment_policy, alloc_strategy)
+        .await?;
+    Ok(Json(res))
+}
+
+pub async fn list_services(
+    State(state): State<HttpState>,
+    Json(ListServicesReq {}): Json<ListServicesReq>,
+) -> Json<ListServicesRes> {
+    Json(state.deployer.list_services().await)
+}
+
+pub async fn show_service(
+    State(state): State<HttpState>,
+    Json(ShowServiceReq { service_id }): Json<ShowServiceReq>,
+) -> http::Result<Json<ShowServiceRes>> {
+    let res = state
+        .deployer
+        .show_service(service_id)
+        .await
+        .or_http_error(StatusCode::NOT_FOUND, "service not found")?;
+    Ok(Json(res))
+}
+
+pub async fn deployment_status(
+    State(state): State<HttpState>,
+    Json(QueryDeploymentStatusReq { deployment_id }): Json<QueryDeploymentStatusReq>,
+) -> http::Result<Json<QueryDeploymentStatusRes>> {
+    let res = state
+        .deployer
+        .deployment_status(deployment_id)
+        .await
+        .or_http_error(StatusCode::NOT_FOUND, "deployment not found")?;
+    Ok(Json(res))
+}
+
+pub async fn list_revisions(
+    State(state): State<HttpState>,
+    Json(ListRevisionsReq { service_id }): Json<ListRevisionsReq>,
+) -> http::Result<Json<ListRevisionsRes>> {
+    let res = state
+        .deployer
+        .list_revisions(service_id)
+        .await
+        .or_http_error(StatusCode::NOT_FOUND, "service not found")?;
+    Ok(Json(res))
+}
+
+pub async fn rollback_service(
+    State(state): State<HttpState>,
+    Json(RollbackServiceReq {
+        service_id,
+        revision_id,
+    }): Json<RollbackServiceReq>,
+) -> http::Result<Json<DeployServiceRes>> {
+    let res = state
+        .deployer
+        .rollback_service(service_id, revision_id)
+        .await?;
+    Ok(Json(res))
+}
+
+pub async fn promote_canary(
+    State(state): State<HttpState>,
+    Json(PromoteCanaryReq { service_id }): Json<PromoteCanaryReq>,
+) -> http::Result<Json<PromoteCanaryRes>> {
+    let res = state.deployer.promote_canary(service_id).await?;
+    Ok(Json(res))
+}
+
+pub async fn abort_canary(
+    State(state): State<HttpState>,
+    Json(AbortCanaryReq { service_id }): Json<AbortCanaryReq>,
+) -> http::Result<Json<AbortCanaryRes>> {
+    let res = state.deployer.abort_canary(service_id).await?;
+    Ok(Json(res))
+}
+
+pub async fn scale_service(
+    State(state): State<HttpState>,
+    Json(ScaleServiceReq {
+        service_id,
+        concurrency,
+    }): Json<ScaleServiceReq>,
+) -> http::Result<Json<ScaleServiceRes>> {
+    let res = state
+        .deployer
+        .scale_service(service_id, concurrency)
+        .await?;
     Ok(Json(res))
 }
 
@@ -23,20 +123,34 @@ pub async fn terminate_service(
     State(state): State<HttpState>,
     Json(TerminateServiceReq { service_id }): Json<TerminateServiceReq>,
 ) -> http::Result<Json<TerminateServiceRes>> {
-    state.deployer.terminate_service(service_id).await?;
-    Ok(Json(TerminateServiceRes {}))
+    let res = state.deployer.terminate_service(service_id).await?;
+    Ok(Json(res))
 }
 
 pub async fn report_instance_status(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<HttpState>,
     Json(ReportDeployInstanceStatusReq {
         instance_id,
+        seq,
         status,
     }): Json<ReportDeployInstanceStatusReq>,
 ) -> Json<ReportDeployInstanceStatusRes> {
     state
         .deployer
-        .report_instance_status(instance_id, status)
+        .report_instance_status(instance_id, addr.ip(), seq, status)
         .await;
     Json(ReportDeployInstanceStatusRes {})
 }
+
+pub async fn sync_worker_instances(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<HttpState>,
+    Json(SyncWorkerInstancesReq { instances }): Json<SyncWorkerInstancesReq>,
+) -> Json<SyncWorkerInstancesRes> {
+    let adopted = state
+        .deployer
+        .sync_worker_instances(addr.ip(), instances)
+        .await;
+    Json(SyncWorkerInstancesRes { adopted })
+}
@@ -25,8 +25,17 @@ pub fn mk_app(state: HttpState) -> Router {
             "/deployer",
             Router::new()
                 .route("/deploy-service", post(deployer::deploy_service))
+                .route("/list-services", post(deployer::list_services))
+                .route("/show-service", post(deployer::show_service))
+                .route("/deployment-status", post(deployer::deployment_status))
+                .route("/list-revisions", post(deployer::list_revisions))
+                .route("/rollback-service", post(deployer::rollback_service))
+                .route("/promote-canary", post(deployer::promote_canary))
+                .route("/abort-canary", post(deployer::abort_canary))
+                .route("/scale-service", post(deployer::scale_service))
                 .route("/terminate-service", post(deployer::terminate_service))
-                .route("/status", post(deployer::report_instance_status)),
+                .route("/status", post(deployer::report_instance_status))
+                .route("/sync-instances", post(deployer::sync_worker_instances)),
         )
         .with_state(state)
 }
@@ -13,10 +13,10 @@ use crate::http::HttpState;
 pub async fn hello(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<HttpState>,
-    Json(HelloReq {}): Json<HelloReq>,
+    Json(HelloReq { labels }): Json<HelloReq>,
 ) -> Json<HelloRes> {
     let addr = addr.ip();
-    let status = state.worker_mgr.hello(addr).await;
+    let status = state.worker_mgr.hello(addr, labels).await;
     Json(HelloRes { status })
 }
 
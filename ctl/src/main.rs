@@ -5,26 +5,34 @@ use std::{
 
 use axum::handler::Handler;
 use clap::Parser;
-use proto::{
-    clients::WorkerClient,
-    well_known::{CTL_BALANCER_PORT, CTL_HTTP_PORT},
-};
-use tokio::task::JoinSet;
-use tracing::info;
+use eyre::bail;
+use proto::clients::WorkerClient;
+use tokio::{select, task::JoinSet};
+use tracing::{info, warn};
 use utils::server::mk_listener;
 
 use crate::{
-    args::CtlArgs, balancer::BalancerState, deployer::Deployer, http::HttpState,
+    args::CtlArgs,
+    balancer::{BalancerState, ProxyState},
+    cluster::{ClusterConfig, Node, NodeId},
+    deployer::{
+        alloc::scheduler::Scheduler, autoscale::AutoscaleConfig, instance::StateTimeouts,
+        rebalance::RebalanceConfig, Deployer, DeployerConfig,
+    },
+    http::HttpState,
+    store::{Snapshot, Store, StoreHandle},
     worker_mgr::WorkerMgr,
 };
 
 mod args;
 mod balancer;
+mod cluster;
 mod deployer;
 mod http;
+mod store;
 mod worker_mgr;
 
-const ANY_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+const ANY_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -33,40 +41,142 @@ async fn main() -> eyre::Result<()> {
     let args = Arc::new(CtlArgs::parse());
     info!(?args, "started ctl");
 
+    match args.node_id {
+        Some(node_id) if !args.peers.is_empty() => run_replicated(args, node_id).await,
+        _ => run_standalone(args).await,
+    }
+}
+
+/// Runs the control plane alone, storing its state locally (if configured).
+async fn run_standalone(args: Arc<CtlArgs>) -> eyre::Result<()> {
+    let (store_handle, snapshot) = match &args.data_dir {
+        Some(dir) => {
+            let (store, handle, snapshot) = Store::open(dir)?;
+            store.spawn()?;
+            (handle, snapshot)
+        }
+        None => (StoreHandle::disabled(), Snapshot::default()),
+    };
+    run_control_plane(args, store_handle, snapshot).await
+}
+
+/// Runs this controller as a member of a replicated control plane, which
+/// runs the control plane only while it leads the cluster.
+async fn run_replicated(args: Arc<CtlArgs>, node_id: NodeId) -> eyre::Result<()> {
+    let config = ClusterConfig {
+        node_id,
+        peers: args.peers.iter().copied().collect(),
+    };
+    let Some(&addr) = config.peers.get(&node_id) else {
+        bail!("`--peers` must include this controller's id");
+    };
+    let dir = args.data_dir.as_deref().expect("required by `--peers`");
+    let (node, node_handle, store_handle, mut leadership) = Node::open(config, dir)?;
+
+    let cluster_listener = mk_listener(ANY_IP, addr.port()).await?;
+    node.spawn()?;
+    tokio::spawn(async move {
+        let app = cluster::rpc::mk_app(node_handle);
+        info!("cluster http listening at {ANY_IP}:{}", addr.port());
+        axum::serve(cluster_listener, app).await.unwrap();
+    });
+
+    loop {
+        let snapshot = leadership.wait_for(Option::is_some).await?.clone().unwrap();
+        info!("leading the cluster, starting control plane");
+        let mut control_plane = tokio::spawn(run_control_plane(
+            args.clone(),
+            store_handle.clone(),
+            (*snapshot).clone(),
+        ));
+        select! {
+            res = &mut control_plane => return res?,
+            res = leadership.wait_for(Option::is_none) => {
+                res?;
+                warn!("lost leadership, stopping control plane");
+                control_plane.abort();
+                // Waits for the listeners to be released.
+                _ = control_plane.await;
+            }
+        }
+    }
+}
+
+async fn run_control_plane(
+    args: Arc<CtlArgs>,
+    store_handle: StoreHandle,
+    snapshot: Snapshot,
+) -> eyre::Result<()> {
     let worker_client = WorkerClient::new();
 
-    let balancer_listener = mk_listener(ANY_IP, CTL_BALANCER_PORT).await?;
-    let http_listener = mk_listener(ANY_IP, CTL_HTTP_PORT).await?;
+    let balancer_listener = mk_listener(ANY_IP, args.balancer_port).await?;
+    let http_listener = mk_listener(ANY_IP, args.http_port).await?;
 
     let mut bag = JoinSet::new();
 
-    let (worker_mgr, worker_mgr_handle) = WorkerMgr::new(args.worker_liveness_timeout);
+    let (mut worker_mgr, worker_mgr_handle) =
+        WorkerMgr::new(args.worker_liveness_timeout, store_handle.clone());
+    worker_mgr.restore(&snapshot.workers);
     bag.spawn(async move {
         worker_mgr.run().await;
     });
 
     let (balancer, balancer_handle) = BalancerState::new();
+    let balancer_watcher = balancer_handle.clone();
+    let worker_events = worker_mgr_handle.subscribe();
     bag.spawn(async move {
-        let app = balancer::proxy
-            .with_state(balancer)
-            .into_make_service_with_connect_info::<SocketAddr>();
-        info!("balancer http listening at {ANY_IP}:{CTL_BALANCER_PORT}");
-        axum::serve(balancer_listener, app).await.unwrap();
+        balancer::watch_workers(balancer_watcher, worker_events).await;
     });
-
-    let (deployer, deployer_handle) =
-        Deployer::new(balancer_handle, worker_mgr_handle.clone(), worker_client);
+    let scheduler = Scheduler::new(&args.scheduler_filters, &args.scheduler_scorers);
+    let config = DeployerConfig {
+        rebalance: args.rebalance.then(|| RebalanceConfig {
+            interval: args.rebalance_interval,
+            max_moves: args.rebalance_max_moves,
+        }),
+        autoscale: AutoscaleConfig {
+            interval: args.autoscale_interval,
+            cooldown: args.autoscale_cooldown,
+        },
+        reconcile_interval: args.reconcile_interval,
+        state_timeouts: StateTimeouts {
+            deploying: args.deploying_timeout,
+            pre_terminating: args.pre_terminating_timeout,
+            terminating: args.terminating_timeout,
+        },
+    };
+    let (mut deployer, deployer_handle) = Deployer::new(
+        balancer_handle,
+        worker_mgr_handle.clone(),
+        worker_client,
+        scheduler,
+        config,
+        store_handle,
+    );
     bag.spawn(async move {
+        deployer.restore(&snapshot).await;
         deployer.run().await;
     });
 
+    let balancer_port = args.balancer_port;
+    let proxy_state = ProxyState {
+        balancer,
+        deployer: deployer_handle.clone(),
+    };
+    bag.spawn(async move {
+        let app = balancer::proxy
+            .with_state(proxy_state)
+            .into_make_service_with_connect_info::<SocketAddr>();
+        info!("balancer http listening at {ANY_IP}:{balancer_port}");
+        axum::serve(balancer_listener, app).await.unwrap();
+    });
+
     bag.spawn(async move {
         let state = HttpState {
             worker_mgr: worker_mgr_handle,
             deployer: deployer_handle,
         };
         let app = http::mk_app(state).into_make_service_with_connect_info::<SocketAddr>();
-        info!("ctl http listening at {ANY_IP}:{CTL_HTTP_PORT}");
+        info!("ctl http listening at {ANY_IP}:{}", args.http_port);
         axum::serve(http_listener, app).await.unwrap();
     });
 
@@ -0,0 +1,341 @@
+//! Embedded on-disk store of the controller's state.
+//!
+//! Changes are appended to a log, which is periodically compacted into a
+//! snapshot. On startup, the snapshot and the log are replayed so that the
+//! controller may restore the state of its previous run.
+//!
+//! Only what can't be recovered from the workers is stored: the worker
+//! registry, the desired state of each service and the placement of each
+//! instance.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead as _, BufReader, ErrorKind, Write as _},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use eyre::Context as _;
+use proto::{
+    common::{
+        instance::InstanceId,
+        service::{ServiceId, ServiceSpec},
+    },
+    ctl::deployer::{AllocStrategy, DeploymentId, InstanceState, RedeploymentPolicy, RevisionInfo},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{error, info, instrument, warn};
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const LOG_FILE: &str = "log.jsonl";
+
+/// The amount of logged changes after which the log is compacted.
+const COMPACTION_THRESHOLD: usize = 1024;
+
+/// The stored state of the controller.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The labels of each registered worker.
+    pub workers: HashMap<IpAddr, HashMap<String, String>>,
+    pub services: HashMap<ServiceId, ServiceRecord>,
+    /// Instances which are yet to reach a final state.
+    pub instances: HashMap<InstanceId, InstanceRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceRecord {
+    /// The spec of the service's current deployment, including its desired
+    /// amount of instances.
+    pub spec: ServiceSpec,
+    pub deployment_id: DeploymentId,
+    pub policy: RedeploymentPolicy,
+    pub alloc_strategy: AllocStrategy,
+    pub revisions: Vec<RevisionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceRecord {
+    pub service_id: ServiceId,
+    pub deployment_id: DeploymentId,
+    pub worker_addr: Option<IpAddr>,
+    pub state: InstanceState,
+    /// The sequence number of the instance's last applied status report.
+    #[serde(default)]
+    pub status_seq: u64,
+}
+
+/// A change to the stored state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Entry {
+    WorkerJoined {
+        addr: IpAddr,
+        labels: HashMap<String, String>,
+    },
+    WorkerRemoved {
+        addr: IpAddr,
+    },
+    ServicePut {
+        record: ServiceRecord,
+    },
+    ServiceRemoved {
+        service_id: ServiceId,
+    },
+    InstancePut {
+        id: InstanceId,
+        record: InstanceRecord,
+    },
+    InstanceRemoved {
+        id: InstanceId,
+    },
+}
+
+impl Snapshot {
+    pub fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::WorkerJoined { addr, labels } => {
+                self.workers.insert(addr, labels);
+            }
+            Entry::WorkerRemoved { addr } => {
+                self.workers.remove(&addr);
+            }
+            Entry::ServicePut { record } => {
+                self.services.insert(record.spec.service_id.clone(), record);
+            }
+            Entry::ServiceRemoved { service_id } => {
+                self.services.remove(&service_id);
+            }
+            Entry::InstancePut { id, record } => {
+                self.instances.insert(id, record);
+            }
+            Entry::InstanceRemoved { id } => {
+                self.instances.remove(&id);
+            }
+        }
+    }
+}
+
+pub struct Store {
+    rx: mpsc::UnboundedReceiver<Entry>,
+    dir: PathBuf,
+    log: File,
+    state: Snapshot,
+    /// The amount of changes logged since the last compaction.
+    logged: usize,
+}
+
+impl Store {
+    /// Opens the store in the given directory, creating it if needed.
+    ///
+    /// Returns the state stored by the previous run, which the caller must
+    /// restore.
+    #[instrument]
+    pub fn open(dir: &Path) -> eyre::Result<(Store, StoreHandle, Snapshot)> {
+        fs::create_dir_all(dir).wrap_err("failed to create data directory")?;
+
+        let mut state: Snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).wrap_err("corrupted snapshot")?,
+            Err(error) if error.kind() == ErrorKind::NotFound => Snapshot::default(),
+            Err(error) => return Err(error).wrap_err("failed to read snapshot"),
+        };
+        let mut replayed = 0;
+        match File::open(dir.join(LOG_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.wrap_err("failed to read log")?;
+                    // The last entry may have been torn by a crash.
+                    let Ok(entry) = serde_json::from_str(&line) else {
+                        warn!("discarding unreadable log entry");
+                        break;
+                    };
+                    state.apply(entry);
+                    replayed += 1;
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => (),
+            Err(error) => return Err(error).wrap_err("failed to read log"),
+        }
+        info!(
+            replayed,
+            workers = state.workers.len(),
+            services = state.services.len(),
+            instances = state.instances.len(),
+            "opened store"
+        );
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .wrap_err("failed to open log")?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut store = Store {
+            rx,
+            dir: dir.to_owned(),
+            log,
+            state,
+            logged: 0,
+        };
+        // Discards any torn entry at the end of the log.
+        store.compact()?;
+        let restored = store.state.clone();
+        Ok((store, StoreHandle(Some(tx)), restored))
+    }
+
+    /// Writes changes until every handle is dropped.
+    ///
+    /// Changes are synchronously written to disk, hence the store runs on a
+    /// dedicated thread, so that it doesn't stall the async runtime.
+    pub fn spawn(self) -> eyre::Result<()> {
+        thread::Builder::new()
+            .name("store".into())
+            .spawn(move || self.run())
+            .wrap_err("failed to spawn store thread")?;
+        Ok(())
+    }
+
+    fn run(mut self) {
+        while let Some(entry) = self.rx.blocking_recv() {
+            if let Err(error) = self.append(entry) {
+                error!(?error, "failed to store change");
+            }
+        }
+    }
+
+    fn append(&mut self, entry: Entry) -> eyre::Result<()> {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.log.write_all(&line)?;
+        self.log.sync_data()?;
+        self.state.apply(entry);
+
+        self.logged += 1;
+        if self.logged >= COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the current state into a new snapshot, and truncates the log.
+    fn compact(&mut self) -> eyre::Result<()> {
+        let tmp = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+        let mut file = File::create(&tmp).wrap_err("failed to create snapshot")?;
+        file.write_all(&serde_json::to_vec(&self.state)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE)).wrap_err("failed to replace snapshot")?;
+
+        // The log is opened in append mode, so that writes follow the
+        // truncation.
+        self.log.set_len(0).wrap_err("failed to truncate log")?;
+        self.logged = 0;
+        Ok(())
+    }
+}
+
+/// Handle to the store. Changes are written in the background.
+#[derive(Clone)]
+pub struct StoreHandle(Option<mpsc::UnboundedSender<Entry>>);
+
+impl StoreHandle {
+    /// Returns a handle which discards every change, used when the controller
+    /// runs without a data directory.
+    #[must_use]
+    pub fn disabled() -> Self {
+        StoreHandle(None)
+    }
+
+    /// Returns a handle which sends changes into the given channel, e.g., to
+    /// be replicated across the cluster (see `crate::cluster`).
+    #[must_use]
+    pub fn new(tx: mpsc::UnboundedSender<Entry>) -> Self {
+        StoreHandle(Some(tx))
+    }
+
+    pub fn append(&self, entry: Entry) {
+        if let Some(tx) = &self.0 {
+            // Sending only fails if the store has stopped.
+            _ = tx.send(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn joined(last: u8) -> Entry {
+        Entry::WorkerJoined {
+            addr: IpAddr::from([10, 0, 0, last]),
+            labels: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn reopen_replays_snapshot_and_log() {
+        let dir = std::env::temp_dir().join(format!("tucano-store-{}", Uuid::now_v7()));
+
+        let (mut store, _, restored) = Store::open(&dir).unwrap();
+        assert_eq!(restored.workers.len(), 0);
+        store.append(joined(1)).unwrap();
+        store.append(joined(2)).unwrap();
+        store
+            .append(Entry::WorkerRemoved {
+                addr: IpAddr::from([10, 0, 0, 1]),
+            })
+            .unwrap();
+        drop(store);
+
+        let (mut store, _, restored) = Store::open(&dir).unwrap();
+        assert_eq!(restored.workers.len(), 1);
+        assert!(restored.workers.contains_key(&IpAddr::from([10, 0, 0, 2])));
+        store.append(joined(3)).unwrap();
+        drop(store);
+
+        // An entry torn by a crash is discarded, and later appends follow the
+        // entries which were intact.
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(br#"{"type":"WORKER_JOI"#).unwrap();
+        let (mut store, _, restored) = Store::open(&dir).unwrap();
+        assert_eq!(restored.workers.len(), 2);
+        store.append(joined(4)).unwrap();
+        drop(store);
+        let (_, _, restored) = Store::open(&dir).unwrap();
+        assert_eq!(restored.workers.len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_truncates_the_log() {
+        let dir = std::env::temp_dir().join(format!("tucano-store-{}", Uuid::now_v7()));
+
+        let (mut store, _, _) = Store::open(&dir).unwrap();
+        for i in 0..COMPACTION_THRESHOLD {
+            store
+                .append(joined(u8::try_from(i % 256).unwrap()))
+                .unwrap();
+        }
+        assert_eq!(store.logged, 0);
+        assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), 0);
+        // Changes which follow the compaction are replayed over the snapshot.
+        store
+            .append(Entry::WorkerRemoved {
+                addr: IpAddr::from([10, 0, 0, 0]),
+            })
+            .unwrap();
+        drop(store);
+
+        let (_, _, restored) = Store::open(&dir).unwrap();
+        assert_eq!(restored.workers.len(), 255);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -10,39 +10,96 @@ use proto::{
 };
 use tokio::{
     select,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     time,
 };
 use tracing::{info, instrument, trace, warn};
 
+use crate::store::{self, StoreHandle};
+
 pub struct WorkerMgr {
     rx: mpsc::Receiver<Msg>,
-    handle: WorkerMgrHandle,
+    events: broadcast::Sender<WorkerEvent>,
     workers: HashMap<IpAddr, WorkerDetails>,
     liveness_timeout: Duration,
+    store: StoreHandle,
 }
 
 #[derive(Debug, Clone)]
 pub struct WorkerDetails {
     pub addr: IpAddr,
-    pub metrics: Metrics,
+    /// The worker's last reported metrics, or `None` if it hasn't pushed any
+    /// since joining the cluster.
+    pub metrics: Option<Metrics>,
     pub collected_at: Instant,
+    /// Labels advertised by the worker when joining the cluster.
+    pub labels: HashMap<String, String>,
+}
+
+/// Changes to the cluster's worker pool, broadcasted to interested parties
+/// (see [`WorkerMgrHandle::subscribe`]).
+#[derive(Debug, Copy, Clone)]
+pub enum WorkerEvent {
+    /// A worker has joined the pool.
+    Joined(IpAddr),
+    /// A worker has gracefully left the pool.
+    Left(IpAddr),
+    /// A worker has missed the liveness timeout and was removed from the pool.
+    Lost(IpAddr),
 }
 
+impl WorkerEvent {
+    /// Whether the event removed the worker from the pool.
+    pub fn removed_worker(self) -> Option<IpAddr> {
+        match self {
+            WorkerEvent::Joined(_) => None,
+            WorkerEvent::Left(addr) | WorkerEvent::Lost(addr) => Some(addr),
+        }
+    }
+}
+
+/// The capacity of the worker events channel. Lagging subscribers must
+/// resynchronize by querying the workers.
+const EVENTS_CAPACITY: usize = 64;
+
 impl WorkerMgr {
     #[must_use]
-    pub fn new(liveness_timeout: Duration) -> (WorkerMgr, WorkerMgrHandle) {
+    pub fn new(liveness_timeout: Duration, store: StoreHandle) -> (WorkerMgr, WorkerMgrHandle) {
         let (tx, rx) = mpsc::channel(16);
-        let handle = WorkerMgrHandle(tx);
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let handle = WorkerMgrHandle {
+            tx,
+            events: events.clone(),
+        };
         let actor = WorkerMgr {
             rx,
-            handle: handle.clone(),
+            events,
             workers: HashMap::default(),
             liveness_timeout,
+            store,
         };
         (actor, handle)
     }
 
+    /// Restores the workers registered by the controller's previous run.
+    ///
+    /// Restored workers have a full liveness timeout to push their metrics
+    /// again, after which they are considered lost. Until then, their
+    /// capacity is unknown.
+    pub fn restore(&mut self, workers: &HashMap<IpAddr, HashMap<String, String>>) {
+        for (&addr, labels) in workers {
+            self.workers.insert(
+                addr,
+                WorkerDetails {
+                    addr,
+                    metrics: None,
+                    collected_at: Instant::now(),
+                    labels: labels.clone(),
+                },
+            );
+        }
+    }
+
     pub async fn run(mut self) {
         let mut interval = time::interval(self.liveness_timeout);
         loop {
@@ -61,9 +118,9 @@ impl WorkerMgr {
     #[instrument(skip_all)]
     async fn handle_msg(&mut self, msg: Msg) {
         match msg {
-            Msg::Hello(worker_addr, reply) => {
+            Msg::Hello(worker_addr, labels, reply) => {
                 trace!(?worker_addr, "got hello");
-                _ = reply.send(self.handle_hello(worker_addr));
+                _ = reply.send(self.handle_hello(worker_addr, labels));
             }
             Msg::Bye(worker_addr) => {
                 trace!(?worker_addr, "got bye");
@@ -79,29 +136,34 @@ impl WorkerMgr {
             }
             Msg::Tick(instant) => {
                 trace!("got tick");
-                self.handle_tick(instant).await;
+                self.handle_tick(instant);
             }
         }
     }
 
     #[instrument(skip(self))]
-    fn handle_hello(&mut self, addr: IpAddr) -> HelloStatus {
+    fn handle_hello(&mut self, addr: IpAddr, labels: HashMap<String, String>) -> HelloStatus {
         match self.workers.entry(addr) {
             Entry::Occupied(_) => {
                 warn!("unnecessary hello operation");
                 HelloStatus::AlreadyRegistered
             }
             Entry::Vacant(entry) => {
-                info!("worker joined");
+                info!(?labels, "worker joined");
+                self.store.append(store::Entry::WorkerJoined {
+                    addr,
+                    labels: labels.clone(),
+                });
                 entry.insert(WorkerDetails {
                     addr,
-                    metrics: Metrics::default(),
+                    metrics: None,
                     collected_at: Instant::now(),
+                    labels,
                 });
+                self.notify(WorkerEvent::Joined(addr));
                 HelloStatus::Ok
             }
         }
-        // TODO: Notify interested parties
     }
 
     #[instrument(skip(self))]
@@ -110,7 +172,10 @@ impl WorkerMgr {
         info!("removed worker from ctl pool");
         if opt.is_none() {
             warn!("worker wasn't registered");
+            return;
         }
+        self.store.append(store::Entry::WorkerRemoved { addr });
+        self.notify(WorkerEvent::Left(addr));
     }
 
     #[instrument(skip(self, metrics))]
@@ -119,36 +184,60 @@ impl WorkerMgr {
             warn!("received metrics from removed worker");
             return PushMetricsStatus::Removed;
         };
-        details.metrics = metrics;
+        details.metrics = Some(metrics);
         details.collected_at = Instant::now();
         PushMetricsStatus::Ack
     }
 
-    async fn handle_tick(&mut self, instant: Instant) {
+    fn handle_tick(&mut self, instant: Instant) {
         // For the purposes of this routine, we assume that `instant` occurs
         // AFTER every `worker`'s `collected_at` instant.
-        for worker in self.workers.values() {
+        let liveness_timeout = self.liveness_timeout;
+        let mut lost = Vec::new();
+        self.workers.retain(|&addr, worker| {
             let maybe_elapsed = instant.checked_duration_since(worker.collected_at);
             let Some(elapsed) = maybe_elapsed else {
                 // collected_at occurred after instant, so the worker is alive
-                continue;
+                return true;
             };
-            if elapsed < self.liveness_timeout {
-                // elapsed time is within the timeout bounds, so worker is alive
-                continue;
+            // if elapsed time is within the timeout bounds, worker is alive;
+            // otherwise, it's most possibly dead
+            let alive = elapsed < liveness_timeout;
+            if !alive {
+                lost.push(addr);
             }
-            // worker is most possibly dead, send a bye
-            self.handle.send(Msg::Bye(worker.addr)).await;
+            alive
+        });
+        for addr in lost {
+            warn!(
+                ?addr,
+                "worker missed liveness timeout, removed from ctl pool"
+            );
+            self.store.append(store::Entry::WorkerRemoved { addr });
+            self.notify(WorkerEvent::Lost(addr));
         }
     }
+
+    fn notify(&self, event: WorkerEvent) {
+        // Sending only fails if there are no subscribers.
+        _ = self.events.send(event);
+    }
 }
 
 #[derive(Clone)]
-pub struct WorkerMgrHandle(mpsc::Sender<Msg>);
+pub struct WorkerMgrHandle {
+    tx: mpsc::Sender<Msg>,
+    events: broadcast::Sender<WorkerEvent>,
+}
 
 impl WorkerMgrHandle {
     async fn send(&self, msg: Msg) {
-        _ = self.0.send(msg).await;
+        _ = self.tx.send(msg).await;
+    }
+
+    /// Subscribes to changes to the worker pool.
+    pub fn subscribe(&self) -> broadcast::Receiver<WorkerEvent> {
+        self.events.subscribe()
     }
 
     /// Sends a message and waits for a reply.
@@ -161,8 +250,8 @@ impl WorkerMgrHandle {
         rx.await.expect("actor must be alive")
     }
 
-    pub async fn hello(&self, addr: IpAddr) -> HelloStatus {
-        self.send_wait(|r| Msg::Hello(addr, r)).await
+    pub async fn hello(&self, addr: IpAddr, labels: HashMap<String, String>) -> HelloStatus {
+        self.send_wait(|r| Msg::Hello(addr, labels, r)).await
     }
 
     pub async fn bye(&self, addr: IpAddr) {
@@ -180,7 +269,11 @@ impl WorkerMgrHandle {
 
 #[derive(Debug)]
 enum Msg {
-    Hello(IpAddr, oneshot::Sender<HelloStatus>),
+    Hello(
+        IpAddr,
+        HashMap<String, String>,
+        oneshot::Sender<HelloStatus>,
+    ),
     Bye(IpAddr),
     PushMetrics(IpAddr, Metrics, oneshot::Sender<PushMetricsStatus>),
     QueryWorkers(oneshot::Sender<Vec<WorkerDetails>>),
@@ -1,6 +1,14 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     clients::BaseClient,
@@ -11,8 +19,13 @@ use crate::{
     },
     ctl::{
         deployer::{
-            DeployServiceReq, DeployServiceRes, RedeploymentPolicy, ReportDeployInstanceStatusReq,
-            ReportDeployInstanceStatusRes, TerminateServiceReq, TerminateServiceRes,
+            AbortCanaryReq, AbortCanaryRes, AllocStrategy, DeployServiceReq, DeployServiceRes,
+            DeploymentId, ListRevisionsReq, ListRevisionsRes, ListServicesReq, ListServicesRes,
+            PromoteCanaryReq, PromoteCanaryRes, QueryDeploymentStatusReq, QueryDeploymentStatusRes,
+            RedeploymentPolicy, ReportDeployInstanceStatusReq, ReportDeployInstanceStatusRes,
+            RevisionId, RollbackServiceReq, ScaleServiceReq, ScaleServiceRes, ShowServiceReq,
+            ShowServiceRes, SyncWorkerInstancesReq, SyncWorkerInstancesRes, TerminateServiceReq,
+            TerminateServiceRes,
         },
         worker::{
             ByeRes, HelloReq, HelloRes, PushWorkerMetricsReq, PushWorkerMetricsRes,
@@ -24,33 +37,70 @@ use crate::{
 
 #[derive(Clone)]
 pub struct CtlClient {
-    base_url: Arc<str>,
+    /// The base URL of each controller. Only the leader of a replicated
+    /// control plane serves requests, so the others are tried in turn once the
+    /// current one can't be reached.
+    base_urls: Arc<[String]>,
+    /// The index of the controller to which requests are sent.
+    current: Arc<AtomicUsize>,
     client: BaseClient,
 }
 
 impl CtlClient {
-    #[must_use]
-    pub fn new(ctl_addr: &str) -> Self {
-        let base_url = format!("http://{ctl_addr}:{CTL_HTTP_PORT}")
-            .into_boxed_str()
-            .into();
+    /// Creates a client from a comma-separated list of controller addresses.
+    /// Addresses without a port use the default HTTP port.
+    pub fn new(ctl_addrs: &str) -> eyre::Result<Self> {
+        let base_urls: Arc<[String]> = ctl_addrs
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(base_url)
+            .collect();
+        if base_urls.is_empty() {
+            eyre::bail!("no controller address in {ctl_addrs:?}");
+        }
         let client = BaseClient::new();
-        CtlClient { base_url, client }
+        Ok(CtlClient {
+            base_urls,
+            current: Arc::default(),
+            client,
+        })
     }
 
-    fn url(&self, path: &str) -> String {
+    /// Sends a request to the current controller, failing over to the next
+    /// ones if it can't be reached.
+    ///
+    /// Paths must start with a `/`.
+    async fn send<Req, Res>(&self, path: &str, body: &Req) -> eyre::Result<Res>
+    where
+        Req: Serialize,
+        Res: DeserializeOwned,
+    {
         assert!(path.starts_with('/'));
-        format!("{base}{path}", base = self.base_url)
+        let start = self.current.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for i in 0..self.base_urls.len() {
+            let index = (start + i) % self.base_urls.len();
+            let url = format!("{base}{path}", base = self.base_urls[index]);
+            match self.client.send(url, body).await {
+                Err(error) if is_connect_error(&error) => last_error = Some(error),
+                res => {
+                    self.current.store(index, Ordering::Relaxed);
+                    return res;
+                }
+            }
+        }
+        Err(last_error.expect("at least one controller was tried"))
     }
 
-    pub async fn hello(&self) -> eyre::Result<HelloRes> {
-        let body = HelloReq {};
-        self.client.send(self.url("/worker/hello"), &body).await
+    pub async fn hello(&self, labels: HashMap<String, String>) -> eyre::Result<HelloRes> {
+        let body = HelloReq { labels };
+        self.send("/worker/hello", &body).await
     }
 
     pub async fn bye(&self) -> eyre::Result<ByeRes> {
         let body = ByeRes {};
-        self.client.send(self.url("/worker/bye"), &body).await
+        self.send("/worker/bye", &body).await
     }
 
     pub async fn push_metrics(
@@ -62,28 +112,83 @@ impl CtlClient {
             metrics,
             recorded_at,
         };
-        self.client
-            .send(self.url("/worker/push-metrics"), &body)
-            .await
+        self.send("/worker/push-metrics", &body).await
     }
 
     pub async fn query_workers(&self) -> eyre::Result<QueryWorkersRes> {
         let body = QueryWorkersReq {};
-        self.client.send(self.url("/worker/query"), &body).await
+        self.send("/worker/query", &body).await
     }
 
     pub async fn deploy_service(
         &self,
         service_spec: ServiceSpec,
         redeployment_policy: RedeploymentPolicy,
+        alloc_strategy: AllocStrategy,
     ) -> eyre::Result<DeployServiceRes> {
         let body = DeployServiceReq {
             service_spec,
             redeployment_policy,
+            alloc_strategy,
+        };
+        self.send("/deployer/deploy-service", &body).await
+    }
+
+    pub async fn deployment_status(
+        &self,
+        deployment_id: DeploymentId,
+    ) -> eyre::Result<QueryDeploymentStatusRes> {
+        let body = QueryDeploymentStatusReq { deployment_id };
+        self.send("/deployer/deployment-status", &body).await
+    }
+
+    pub async fn list_services(&self) -> eyre::Result<ListServicesRes> {
+        let body = ListServicesReq {};
+        self.send("/deployer/list-services", &body).await
+    }
+
+    pub async fn show_service(&self, service_id: ServiceId) -> eyre::Result<ShowServiceRes> {
+        let body = ShowServiceReq { service_id };
+        self.send("/deployer/show-service", &body).await
+    }
+
+    pub async fn list_revisions(&self, service_id: ServiceId) -> eyre::Result<ListRevisionsRes> {
+        let body = ListRevisionsReq { service_id };
+        self.send("/deployer/list-revisions", &body).await
+    }
+
+    pub async fn rollback_service(
+        &self,
+        service_id: ServiceId,
+        revision_id: RevisionId,
+    ) -> eyre::Result<DeployServiceRes> {
+        let body = RollbackServiceReq {
+            service_id,
+            revision_id,
+        };
+        self.send("/deployer/rollback-service", &body).await
+    }
+
+    pub async fn promote_canary(&self, service_id: ServiceId) -> eyre::Result<PromoteCanaryRes> {
+        let body = PromoteCanaryReq { service_id };
+        self.send("/deployer/promote-canary", &body).await
+    }
+
+    pub async fn abort_canary(&self, service_id: ServiceId) -> eyre::Result<AbortCanaryRes> {
+        let body = AbortCanaryReq { service_id };
+        self.send("/deployer/abort-canary", &body).await
+    }
+
+    pub async fn scale_service(
+        &self,
+        service_id: ServiceId,
+        concurrency: u32,
+    ) -> eyre::Result<ScaleServiceRes> {
+        let body = ScaleServiceReq {
+            service_id,
+            concurrency,
         };
-        self.client
-            .send(self.url("/deployer/deploy-service"), &body)
-            .await
+        self.send("/deployer/scale-service", &body).await
     }
 
     pub async fn terminate_service(
@@ -91,20 +196,83 @@ impl CtlClient {
         service_id: ServiceId,
     ) -> eyre::Result<TerminateServiceRes> {
         let body = TerminateServiceReq { service_id };
-        self.client
-            .send(self.url("/deployer/terminate-service"), &body)
-            .await
+        self.send("/deployer/terminate-service", &body).await
     }
 
     pub async fn report_instance_status(
         &self,
         instance_id: InstanceId,
+        seq: u64,
         status: instance::Status,
     ) -> eyre::Result<ReportDeployInstanceStatusRes> {
         let body = ReportDeployInstanceStatusReq {
             instance_id,
+            seq,
             status,
         };
-        self.client.send(self.url("/deployer/status"), &body).await
+        self.send("/deployer/status", &body).await
+    }
+
+    pub async fn sync_worker_instances(
+        &self,
+        instances: Vec<InstanceId>,
+    ) -> eyre::Result<SyncWorkerInstancesRes> {
+        let body = SyncWorkerInstancesReq { instances };
+        self.send("/deployer/sync-instances", &body).await
+    }
+}
+
+/// Whether a request failed because the controller couldn't be reached.
+#[must_use]
+pub fn is_connect_error(error: &eyre::Report) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(reqwest::Error::is_connect)
+}
+
+/// Builds the base URL of a controller address, which may be an IP address or
+/// a host name, with or without a port. IPv6 addresses with a port must be
+/// bracketed, as bare ones are all colons.
+fn base_url(addr: &str) -> String {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return format!("http://{addr}");
+    }
+    let ip = addr
+        .strip_prefix('[')
+        .and_then(|addr| addr.strip_suffix(']'))
+        .unwrap_or(addr);
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return format!("http://{}", SocketAddr::new(ip, CTL_HTTP_PORT));
+    }
+    let has_port = addr
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    if has_port {
+        format!("http://{addr}")
+    } else {
+        format!("http://{addr}:{CTL_HTTP_PORT}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_urls_default_to_the_http_port() {
+        let port = CTL_HTTP_PORT;
+        assert_eq!(base_url("10.0.0.1"), format!("http://10.0.0.1:{port}"));
+        assert_eq!(base_url("10.0.0.1:8080"), "http://10.0.0.1:8080");
+        assert_eq!(base_url("::1"), format!("http://[::1]:{port}"));
+        assert_eq!(base_url("[::1]"), format!("http://[::1]:{port}"));
+        assert_eq!(base_url("[::1]:8080"), "http://[::1]:8080");
+        assert_eq!(base_url("ctl"), format!("http://ctl:{port}"));
+        assert_eq!(base_url("ctl:8080"), "http://ctl:8080");
+    }
+
+    #[test]
+    fn empty_address_lists_are_rejected() {
+        assert!(CtlClient::new(" , ").is_err());
+        assert!(CtlClient::new("ctl, 10.0.0.1").is_ok());
     }
 }
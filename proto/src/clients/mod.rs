@@ -1,7 +1,7 @@
 mod ctl;
 use std::time::Duration;
 
-pub use ctl::CtlClient;
+pub use ctl::{is_connect_error, CtlClient};
 
 mod worker;
 use eyre::Context as _;
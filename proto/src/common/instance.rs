@@ -39,6 +39,9 @@ impl InstanceSpec {
             public,
             concurrency: _,
             resource_config,
+            placement: _,
+            autoscaling: _,
+            scale_to_zero: _,
         } = spec;
         InstanceSpec {
             instance_id,
@@ -49,7 +52,7 @@ impl InstanceSpec {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Status {
     /// The instance has successfully started.
     Started,
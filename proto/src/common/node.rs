@@ -19,6 +19,9 @@ pub enum NodeKind {
 pub struct Metrics {
     /// The average CPU usage.
     pub cpu_usage: f64,
+    /// The number of logical CPUs.
+    #[serde(default)]
+    pub cpu_count: u32,
     /// The total memory, in bytes.
     pub mem_total: u64,
     /// The used memory, in bytes.
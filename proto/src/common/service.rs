@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
 use serde::{Deserialize, Serialize};
 
@@ -18,7 +18,7 @@ impl fmt::Display for ServiceId {
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct ServiceImage(pub String);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ServiceSpec {
     /// The service domain.
     pub service_id: ServiceId,
@@ -27,8 +27,76 @@ pub struct ServiceSpec {
     pub public: bool,
     /// The maximum number of instances that Tucano is allowed to run for this
     /// service.
+    ///
+    /// For autoscaled services, this is the initial amount of instances.
     pub concurrency: u32,
     pub resource_config: ResourceConfig,
+    #[serde(default)]
+    pub placement: Placement,
+    #[serde(default)]
+    pub autoscaling: Option<Autoscaling>,
+    #[serde(default)]
+    pub scale_to_zero: Option<ScaleToZero>,
+}
+
+/// Terminates all instances of an idle service. The next request to the
+/// service starts an instance again (i.e., a cold start).
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ScaleToZero {
+    /// Time (in seconds) without requests after which the service is
+    /// considered idle.
+    pub idle_timeout: u64,
+}
+
+/// Adjusts the amount of instances of a service to its load.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Autoscaling {
+    /// The minimum amount of instances.
+    pub min: u32,
+    /// The maximum amount of instances.
+    pub max: u32,
+    /// The load which each instance should be kept at.
+    pub target: ScalingTarget,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ScalingTarget {
+    /// Average CPU usage (percentage) of the workers running the service's
+    /// instances.
+    Cpu { usage: f64 },
+    /// Average amount of in-flight requests per instance.
+    InFlightRequests { per_instance: u32 },
+}
+
+/// Constraints on the workers in which a service's instances may be placed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Placement {
+    /// Labels which a worker must have, with the same values, to run the
+    /// service's instances.
+    #[serde(default)]
+    pub node_selector: HashMap<String, String>,
+    #[serde(default)]
+    pub anti_affinity: Option<AntiAffinity>,
+}
+
+impl Placement {
+    /// Whether instances may be placed in any worker.
+    #[must_use]
+    pub fn is_unconstrained(&self) -> bool {
+        self.node_selector.is_empty() && self.anti_affinity.is_none()
+    }
+}
+
+/// Rules which keep a service's instances apart from each other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AntiAffinity {
+    /// Places at most `max` instances of the service in each worker.
+    MaxPerWorker { max: u32 },
+    /// Evenly spreads the service's instances across the values of the given
+    /// worker label (e.g., `zone`). Workers without the label are not used.
+    SpreadByLabel { key: String },
 }
 
 /// The allocation of resources for a Service.
@@ -1,5 +1,6 @@
-use std::{collections::HashMap, fmt, net::IpAddr};
+use std::{collections::HashMap, fmt, net::IpAddr, str::FromStr};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -11,6 +12,20 @@ use crate::common::{
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
 pub struct RevisionId(pub Uuid);
 
+impl fmt::Display for RevisionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for RevisionId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::parse_str(s).map(RevisionId)
+    }
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
 pub struct DeploymentId(pub Uuid);
 
@@ -25,22 +40,294 @@ impl fmt::Display for DeploymentId {
 pub struct DeployServiceReq {
     pub service_spec: ServiceSpec,
     pub redeployment_policy: RedeploymentPolicy,
+    #[serde(default)]
+    pub alloc_strategy: AllocStrategy,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How a deployment's instances are placed into the cluster's workers.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AllocStrategy {
+    /// Places instances in a round-robin fashion, regardless of the workers'
+    /// resources.
+    RoundRobin,
+    /// Places each instance into the worker which would be left with the
+    /// least amount of free resources, packing instances together.
+    BestFit,
+    /// Places each instance into the worker with the most free resources,
+    /// spreading instances apart.
+    LeastLoaded,
+    /// Places instances using the controller's configured scheduler plugins.
+    #[default]
+    Scheduler,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum RedeploymentPolicy {
     /// Disallow re-deployments if the service is already deployed with running
     /// instances.
     None,
-    // TODO: Add more (blue green, etc)
+    /// Deploys a new set of instances and, once all of them have started,
+    /// terminates the instances of the service's previous deployments.
+    ///
+    /// If some of the new instances fail to start, the previous instances are
+    /// kept running.
+    Replace,
+    /// Gradually replaces the instances of the service's previous deployments
+    /// in batches.
+    ///
+    /// Each new batch is only started once the instances of the previous batch
+    /// have started. New instances which fail to start are replaced, as long
+    /// as no more than `max(max_surge, max_unavailable, 1)` have failed.
+    /// Otherwise, the rollout is aborted and the remaining previous instances
+    /// are kept.
+    Rolling {
+        /// The maximum amount of instances that may be running above the
+        /// service's concurrency during the rollout.
+        max_surge: u32,
+        /// The maximum amount of instances that may be unavailable (below the
+        /// service's concurrency) during the rollout.
+        max_unavailable: u32,
+    },
+    /// Deploys a whole new set of instances while the previous ones keep
+    /// serving traffic. Once all new instances have started, the balancer
+    /// switches to them at once and the previous instances are terminated.
+    ///
+    /// If some of the new instances fail to start, no switch is made and the
+    /// new instances are terminated.
+    BlueGreen,
+    /// Deploys a new set of instances alongside the previous ones, sending
+    /// them only a `weight` percentage of the service's traffic.
+    ///
+    /// The canary deployment must then be either promoted (see
+    /// [`PromoteCanaryReq`]), which terminates the previous instances, or
+    /// aborted (see [`AbortCanaryReq`]), which terminates the canary instances.
+    Canary {
+        /// Percentage (from 0 to 100) of traffic sent to the new instances.
+        weight: u32,
+    },
 }
 
 /// Response for [`DeployReq`].
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeployServiceRes {
     pub deployment_id: DeploymentId,
+    /// The revision recorded for this deploy.
+    pub revision_id: RevisionId,
+    /// The instances which were placed into a worker.
     pub instances: HashMap<InstanceId, IpAddr>,
+    /// The instances which are waiting for a worker with enough capacity.
+    pub pending: Vec<InstanceId>,
+    /// Only present for [`RedeploymentPolicy::Rolling`] deployments.
+    pub rollout: Option<RolloutStatus>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RolloutStatus {
+    pub state: RolloutState,
+    /// The amount of new instances which are waiting for a batch.
+    pub queued: u32,
+    /// The amount of new instances which have failed to start.
+    pub failed: u32,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RolloutState {
+    Progressing,
+    Completed,
+    /// Too many new instances failed to start, so the rollout was stopped.
+    Aborted,
+}
+
+/// Queries the status of a given deployment.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryDeploymentStatusReq {
+    pub deployment_id: DeploymentId,
+}
+
+/// Response for [`QueryDeploymentStatusReq`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryDeploymentStatusRes {
+    pub service_id: ServiceId,
+    pub state: DeploymentState,
+    /// The latest known state of each of the deployment's instances.
+    pub instances: HashMap<InstanceId, InstanceState>,
+    /// Only present for [`RedeploymentPolicy::Rolling`] deployments.
+    pub rollout: Option<RolloutStatus>,
+}
+
+/// The state of a deployment, as tracked by the controller.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DeploymentState {
+    /// No instance has reported its deploy outcome yet.
+    Deploying,
+    /// All instances that have reported so far were successfully deployed.
+    PartialDeployingOk,
+    /// At least one instance has failed to deploy, but some instances haven't
+    /// reported their deploy outcome yet.
+    PartialDeployingErr,
+    /// All instances were successfully deployed.
+    CompleteRunningDeploy,
+    /// All instances reported their deploy outcome, but at least one of them
+    /// has failed to deploy.
+    IncompleteRunningDeploy,
+}
+
+/// Lists all services known by the controller.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListServicesReq {}
+
+/// Response for [`ListServicesReq`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListServicesRes {
+    pub services: Vec<ServiceSummary>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceSummary {
+    pub service_id: ServiceId,
+    /// The spec of the service's latest deployment.
+    pub service_spec: ServiceSpec,
+    pub deployments: usize,
+    /// The number of instances which are currently running.
+    pub running_instances: usize,
+    /// Whether the service's instances keep crashing (see
+    /// [`ShowServiceRes::degraded`]).
+    pub degraded: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Shows the details of a given service.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShowServiceReq {
+    pub service_id: ServiceId,
+}
+
+/// Response for [`ShowServiceReq`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShowServiceRes {
+    /// The spec of the service's latest deployment.
+    pub service_spec: ServiceSpec,
+    pub created_at: DateTime<Utc>,
+    /// Whether the service is crash-looping, i.e., its instances have
+    /// repeatedly failed and had to be replaced in a short period of time.
+    pub degraded: bool,
+    /// The service's deployments, ordered from oldest to newest.
+    pub deployments: Vec<DeploymentInfo>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeploymentInfo {
+    pub deployment_id: DeploymentId,
+    pub state: DeploymentState,
+    pub created_at: DateTime<Utc>,
+    pub instances: HashMap<InstanceId, InstanceInfo>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstanceInfo {
+    pub state: InstanceState,
+    /// The address of the worker in which the instance lives. Absent while
+    /// the instance is pending.
+    pub worker_addr: Option<IpAddr>,
+    pub created_at: DateTime<Utc>,
+    /// The moment of the instance's last state transition.
+    pub updated_at: DateTime<Utc>,
+    /// Transitions which were unexpected in the instance's state at the time,
+    /// and thus were ignored (e.g., late or duplicate worker reports).
+    #[serde(default)]
+    pub anomalies: Vec<InstanceAnomaly>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstanceAnomaly {
+    /// The state in which the transition was received.
+    pub state: InstanceState,
+    /// A description of the unexpected transition.
+    pub transition: String,
+    pub at: DateTime<Utc>,
+}
+
+/// An immutable record of a deploy of a service.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub revision_id: RevisionId,
+    pub service_spec: ServiceSpec,
+    /// The deployment which resulted from this revision.
+    pub deployment_id: DeploymentId,
+    #[serde(default)]
+    pub alloc_strategy: AllocStrategy,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Lists the service's revisions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListRevisionsReq {
+    pub service_id: ServiceId,
+}
+
+/// Response for [`ListRevisionsReq`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListRevisionsRes {
+    /// The service's retained revisions, ordered from oldest to newest.
+    pub revisions: Vec<RevisionInfo>,
+}
+
+/// Redeploys the spec of one of the service's earlier revisions, using the
+/// redeployment policy of the service's latest deployment.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackServiceReq {
+    pub service_id: ServiceId,
+    pub revision_id: RevisionId,
+}
+
+/// Promotes the service's canary deployment, so that it receives all of the
+/// service's traffic. Instances of previous deployments are terminated.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteCanaryReq {
+    pub service_id: ServiceId,
+}
+
+/// Response for [`PromoteCanaryReq`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteCanaryRes {
+    /// The promoted deployment.
+    pub deployment_id: DeploymentId,
+}
+
+/// Aborts the service's canary deployment, terminating its instances.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AbortCanaryReq {
+    pub service_id: ServiceId,
+}
+
+/// Response for [`AbortCanaryReq`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AbortCanaryRes {
+    /// The aborted deployment.
+    pub deployment_id: DeploymentId,
+}
+
+/// Changes the amount of instances of the service's current deployment,
+/// without redeploying it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScaleServiceReq {
+    pub service_id: ServiceId,
+    pub concurrency: u32,
+}
+
+/// Response for [`ScaleServiceReq`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScaleServiceRes {
+    /// The scaled deployment.
+    pub deployment_id: DeploymentId,
+    /// The instances which were added to the deployment.
+    pub started: Vec<InstanceId>,
+    /// The surplus instances, which are being terminated.
+    pub terminating: Vec<InstanceId>,
 }
 
 /// Stops a given service from running in the system.
@@ -50,14 +337,70 @@ pub struct TerminateServiceReq {
 }
 
 /// Response for [`TerminateReq`].
+///
+/// Only sent after every instance of the service has reached a final state.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct TerminateServiceRes {}
+pub struct TerminateServiceRes {
+    pub outcome: TerminationOutcome,
+    /// The final state of each of the service's instances.
+    pub instances: HashMap<InstanceId, InstanceState>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TerminationOutcome {
+    /// All instances were successfully terminated.
+    Complete,
+    /// At least one instance failed to terminate gracefully.
+    Incomplete,
+}
+
+/// The state of an instance, as tracked by the controller.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum InstanceState {
+    Init,
+    /// The instance is waiting for a worker with enough capacity.
+    Pending,
+    Deploying,
+    FailedToStart,
+    PreTerminating,
+    NeverStarted,
+    Started,
+    UnexpectedTerminated,
+    UnexpectedCrashed,
+    Terminating,
+    Terminated,
+    Crashed,
+    FailedToTerminate,
+    /// The instance's worker was removed from the cluster pool.
+    Lost,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReportDeployInstanceStatusReq {
     pub instance_id: InstanceId,
+    /// The report's position among the instance's reports, starting at 1.
+    /// Workers retry reports until acknowledged, so the controller may
+    /// receive them more than once and out of order.
+    pub seq: u64,
     pub status: instance::Status,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReportDeployInstanceStatusRes {}
+
+/// Sent by a worker once it restarts, listing the instances whose containers
+/// survived the restart.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncWorkerInstancesReq {
+    pub instances: Vec<InstanceId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncWorkerInstancesRes {
+    /// The surviving instances adopted by the controller, along with the
+    /// sequence number of the last status report applied for each. The worker
+    /// must terminate the other ones.
+    pub adopted: HashMap<InstanceId, u64>,
+}
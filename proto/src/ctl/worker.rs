@@ -1,4 +1,4 @@
-use std::net::IpAddr;
+use std::{collections::HashMap, net::IpAddr};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -6,7 +6,11 @@ use serde::{Deserialize, Serialize};
 use crate::common::node::Metrics;
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct HelloReq {}
+pub struct HelloReq {
+    /// Key/value labels which describe the worker (e.g., its zone).
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HelloRes {
@@ -0,0 +1,9 @@
+//! Parsers shared by the command-line arguments of the binaries.
+
+/// Parses a label in the `key=value` format.
+pub fn parse_label(arg: &str) -> eyre::Result<(String, String)> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| eyre::eyre!("label must be in the `key=value` format"))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
@@ -9,6 +9,7 @@ use tracing::error;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+#[derive(Debug)]
 pub struct Error {
     public: Option<(StatusCode, Cow<'static, str>)>,
     inner: eyre::Report,
@@ -1,3 +1,4 @@
+pub mod args;
 pub mod fmt;
 pub mod http;
 pub mod server;
@@ -1,10 +1,13 @@
 use std::time::Duration;
 
 use clap::Parser;
+use utils::args::parse_label;
 
 #[derive(Debug, Parser)]
 pub struct WorkerArgs {
-    /// Controller's HTTP address.
+    /// Controller's HTTP address. With a replicated control plane, a
+    /// comma-separated list of every controller's address, which are tried in
+    /// turn until the leader is found.
     #[arg(short, long)]
     pub ctl_addr: String,
 
@@ -29,6 +32,14 @@ pub struct WorkerArgs {
     value_parser = parse_duration
 )]
     pub metrics_report_interval: Duration,
+
+    /// Labels advertised to the controller, in the `key=value` format (e.g.,
+    /// `--label zone=us-east-1a`), which services may select through their
+    /// node selectors.
+    ///
+    /// May be specified multiple times.
+    #[arg(long = "label", value_parser = parse_label)]
+    pub labels: Vec<(String, String)>,
 }
 
 fn parse_duration(arg: &str) -> eyre::Result<Duration> {
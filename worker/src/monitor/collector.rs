@@ -23,6 +23,7 @@ impl MetricsCollector {
 
         Metrics {
             cpu_usage: self.get_cpu_usage(),
+            cpu_count: u32::try_from(self.system.cpus().len()).unwrap_or(u32::MAX),
             mem_total: self.get_total_memory(),
             mem_used: self.get_used_memory(),
         }
@@ -14,7 +14,7 @@ pub async fn start_pusher(args: Arc<WorkerArgs>, ctl_client: CtlClient) -> eyre:
 
     // Try to join the cluster
     ctl_client
-        .hello()
+        .hello(args.labels.iter().cloned().collect())
         .await
         .wrap_err("worker failed to join the cluster")?;
 
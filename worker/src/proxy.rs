@@ -87,7 +87,7 @@ impl ProxyState {
             ports: ports.clone(),
             client: {
                 let mut connector = HttpConnector::new();
-                connector.set_keepalive(Some(Duration::from_secs(60)));
+                connector.set_keepalive(Some(Duration::from_mins(1)));
                 connector.set_nodelay(true);
                 Client::builder(TokioExecutor::new()).build::<_, Body>(connector)
             },
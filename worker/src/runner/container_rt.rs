@@ -2,8 +2,8 @@ use std::{collections::HashMap, sync::Arc};
 
 use bollard::{
     container::{
-        Config, CreateContainerOptions, KillContainerOptions, StartContainerOptions,
-        WaitContainerOptions,
+        Config, CreateContainerOptions, KillContainerOptions, ListContainersOptions,
+        StartContainerOptions, WaitContainerOptions,
     },
     errors::Error as BollardError,
     secret::{ContainerCreateResponse, ContainerWaitExitError, ContainerWaitResponse, HostConfig},
@@ -14,11 +14,17 @@ use proto::{
     common::instance::{InstanceId, InstanceSpec, Status},
     well_known::GRACEFUL_SHUTDOWN_DEADLINE,
 };
-use tracing::{error, instrument, trace};
+use tracing::{error, instrument, trace, warn};
 
 use super::RunnerHandle;
 use crate::args::WorkerArgs;
 
+/// Label of the containers created by the worker, holding their instance's id.
+const INSTANCE_ID_LABEL: &str = "tucano.instance-id";
+
+/// Label holding the port in which a container's instance listens.
+const PORT_LABEL: &str = "tucano.port";
+
 #[derive(Clone)]
 pub struct ContainerRuntime {
     docker: Arc<Docker>,
@@ -58,26 +64,53 @@ impl ContainerRuntime {
             .report_instance_status(spec.instance_id, Status::Started)
             .await;
 
-        match self
-            .wait_container(spec.instance_id)
-            .await
-            .expect("infallible operation")
-        {
+        self.supervise_instance(spec.instance_id, handle).await;
+    }
+
+    /// Waits for the container of a running instance to stop, reporting how
+    /// it stopped.
+    #[instrument(skip(self, handle))]
+    pub async fn supervise_instance(&self, id: InstanceId, handle: RunnerHandle) {
+        match self.wait_container(id).await.expect("infallible operation") {
             ExitStatus::Terminated => {
                 trace!("container terminated");
-                handle
-                    .report_instance_status(spec.instance_id, Status::Terminated)
-                    .await;
+                handle.report_instance_status(id, Status::Terminated).await;
             }
             ExitStatus::Crashed { status, error } => {
-                error!(status, instance_id = %spec.instance_id, "container crashed");
+                error!(status, instance_id = %id, "container crashed");
                 handle
-                    .report_instance_status(spec.instance_id, Status::Crashed { error })
+                    .report_instance_status(id, Status::Crashed { error })
                     .await;
             }
         }
     }
 
+    /// Lists the running containers created by this worker (e.g., by its
+    /// previous process), along with their instance's port.
+    pub async fn list_instances(&self) -> eyre::Result<Vec<(InstanceId, u16)>> {
+        let options = Some(ListContainersOptions {
+            filters: HashMap::from([("label", vec![INSTANCE_ID_LABEL])]),
+            ..Default::default()
+        });
+        let containers = self.docker.list_containers(options).await?;
+
+        let instances = containers
+            .into_iter()
+            .filter_map(|container| {
+                let labels = container.labels.unwrap_or_default();
+                let id = labels.get(INSTANCE_ID_LABEL)?;
+                let port = labels.get(PORT_LABEL)?;
+                if let (Ok(id), Ok(port)) = (InstanceId::try_from(id.as_str()), port.parse()) {
+                    Some((id, port))
+                } else {
+                    warn!(id, port, "ignoring container with malformed labels");
+                    None
+                }
+            })
+            .collect();
+        Ok(instances)
+    }
+
     pub async fn terminate_instance(&self, id: InstanceId) {
         if let Err(e) = self.kill_container(id, "SIGTERM").await {
             error!(%e, "error when killing instance (term)");
@@ -189,6 +222,10 @@ impl ContainerRuntime {
 
         Config {
             image: Some(spec.image.0),
+            labels: Some(HashMap::from([
+                (INSTANCE_ID_LABEL.to_string(), spec.instance_id.to_string()),
+                (PORT_LABEL.to_string(), port.to_string()),
+            ])),
             exposed_ports: Some(HashMap::from([(
                 format!("{port}/tcp"),
                 #[allow(clippy::zero_sized_map_values)]
@@ -6,20 +6,27 @@ use std::{
 use bollard::Docker;
 use container_rt::ContainerRuntime;
 use eyre::{Context as _, Ok, Report};
+use outbox::{Outbox, BASE_BACKOFF, MAX_BACKOFF};
 use proto::{
-    clients::CtlClient,
+    clients::{is_connect_error, CtlClient},
     common::instance::{self, InstanceId, InstanceSpec},
 };
 use tokio::{
     net::TcpListener,
     sync::{mpsc, oneshot},
     task,
+    time::sleep,
 };
-use tracing::{error, trace};
+use tracing::{error, info, warn};
 
 mod container_rt;
+mod outbox;
 use crate::{args::WorkerArgs, proxy::ProxyHandle};
 
+/// The number of times the surviving instances are synced with the controller
+/// while it can't be reached, e.g., while it fails over.
+const MAX_SYNC_ATTEMPTS: u32 = 8;
+
 pub struct Runner {
     rx: mpsc::Receiver<Msg>,
     instances: HashMap<InstanceId, u16>,
@@ -29,6 +36,8 @@ pub struct Runner {
     worker_args: Arc<WorkerArgs>,
     container_runtime: Arc<ContainerRuntime>,
     ctl_client: CtlClient,
+    /// Status report outboxes of the instances which are yet to finish.
+    outboxes: HashMap<InstanceId, Outbox>,
 }
 
 impl Runner {
@@ -50,11 +59,62 @@ impl Runner {
             worker_args,
             container_runtime: Arc::new(ContainerRuntime::new(docker)),
             ctl_client,
+            outboxes: HashMap::default(),
         };
         (actor, handle)
     }
 
+    /// Syncs the containers which survived a restart of the worker with the
+    /// controller in the background, so that messages are handled meanwhile.
+    /// The adopted instances are then sent back to the runner.
+    fn spawn_instance_sync(&self) {
+        let rt = self.container_runtime.clone();
+        let ctl_client = self.ctl_client.clone();
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            if let Err(error) = sync_surviving_instances(&rt, &ctl_client, &handle).await {
+                error!(?error, "failed to adopt surviving instances");
+            }
+        });
+    }
+
+    /// Adopts the containers which survived a restart of the worker, so that
+    /// their instances are reachable through the proxy and supervised again.
+    ///
+    /// Only the instances the controller still tracks are adopted. The others
+    /// are terminated.
+    fn adopt_instances(
+        &mut self,
+        surviving: Vec<(InstanceId, u16)>,
+        adopted: &HashMap<InstanceId, u64>,
+    ) -> eyre::Result<()> {
+        for (id, port) in surviving {
+            // The controller may have redeployed it while the sync was in
+            // flight.
+            if self.instances.contains_key(&id) {
+                continue;
+            }
+            let Some(&applied_seq) = adopted.get(&id) else {
+                info!(%id, "terminating instance unknown to ctl");
+                self.terminate_instance(id)?;
+                continue;
+            };
+            info!(%id, port, "adopting instance");
+            self.add_instance(id, port);
+            let outbox = Outbox::resumed(id, self.ctl_client.clone(), applied_seq + 1);
+            self.outboxes.insert(id, outbox);
+
+            let rt = self.container_runtime.clone();
+            let handle = self.handle.clone();
+            tokio::spawn(async move {
+                rt.supervise_instance(id, handle).await;
+            });
+        }
+        Ok(())
+    }
+
     pub async fn run(mut self) {
+        self.spawn_instance_sync();
         while let Some(msg) = self.rx.recv().await {
             self.handle_msg(msg).await;
         }
@@ -73,10 +133,20 @@ impl Runner {
             Msg::ReportInstanceStatus(id, status) => {
                 self.report_instance_status(id, status);
             }
+            Msg::AdoptInstances(surviving, adopted) => {
+                if let Err(error) = self.adopt_instances(surviving, &adopted) {
+                    error!(?error, "failed to adopt surviving instances");
+                }
+            }
         }
     }
 
     async fn deploy_instance(&mut self, spec: InstanceSpec) -> eyre::Result<()> {
+        // The controller may retry a deployment attempt which is still in
+        // progress.
+        if self.instances.contains_key(&spec.instance_id) {
+            eyre::bail!("instance is already deployed");
+        }
         let port = self.get_available_instance_port().await?;
         self.add_instance(spec.instance_id, port);
 
@@ -99,20 +169,24 @@ impl Runner {
 
     fn report_instance_status(&mut self, instance_id: InstanceId, status: instance::Status) {
         use instance::Status::*;
-        match &status {
-            Started => (),
+        let finished = match &status {
+            Started => false,
             Terminated | Crashed { .. } | Killed { .. } | FailedToStart { .. } => {
                 self.remove_instance(instance_id);
+                true
             }
-        }
+        };
 
-        let ctl_client = self.ctl_client.clone();
-        tokio::spawn(async move {
-            trace!(?instance_id, ?status, "reporting status");
-            if let Err(error) = ctl_client.report_instance_status(instance_id, status).await {
-                error!(?error, "failed to report instance status");
-            }
-        });
+        let ctl_client = &self.ctl_client;
+        self.outboxes
+            .entry(instance_id)
+            .or_insert_with(|| Outbox::new(instance_id, ctl_client.clone()))
+            .push(status);
+        // The instance won't report anything else, though its outbox is still
+        // delivered.
+        if finished {
+            self.outboxes.remove(&instance_id);
+        }
     }
 
     async fn get_available_instance_port(&mut self) -> eyre::Result<u16> {
@@ -176,6 +250,43 @@ pub enum Msg {
     /// Sends a report to `ctl::http` component regarding current
     /// instance status. Furthermore updating discovery
     ReportInstanceStatus(InstanceId, instance::Status),
+    /// Adopts the surviving instances, given along with their ports, which
+    /// the controller still tracks.
+    AdoptInstances(Vec<(InstanceId, u16)>, HashMap<InstanceId, u64>),
+}
+
+/// Tells the controller which containers survived a restart of the worker,
+/// sending the ones it adopted to the runner.
+///
+/// Only connection failures are retried, a bounded number of times.
+async fn sync_surviving_instances(
+    rt: &ContainerRuntime,
+    ctl_client: &CtlClient,
+    handle: &RunnerHandle,
+) -> eyre::Result<()> {
+    let surviving = rt
+        .list_instances()
+        .await
+        .wrap_err("failed to list containers")?;
+    let ids: Vec<_> = surviving.iter().map(|&(id, _)| id).collect();
+    let mut backoff = BASE_BACKOFF;
+    let mut attempt = 1;
+    let adopted = loop {
+        match ctl_client.sync_worker_instances(ids.clone()).await {
+            Err(error) if attempt < MAX_SYNC_ATTEMPTS && is_connect_error(&error) => {
+                warn!(
+                    ?error,
+                    attempt, "failed to reach ctl to sync instances, retrying"
+                );
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            res => break res.wrap_err("failed to sync instances with ctl")?.adopted,
+        }
+    };
+    handle.send(Msg::AdoptInstances(surviving, adopted)).await;
+    Ok(())
 }
 
 async fn get_available_port() -> eyre::Result<u16> {
@@ -0,0 +1,133 @@
+//! Ordered, at-least-once delivery of instance status reports.
+//!
+//! Each instance has its own outbox, whose reports are numbered with
+//! monotonically increasing sequence numbers and delivered one at a time, in
+//! order. A report is retried with backoff until the controller acknowledges
+//! it, and only then is the next one sent. The controller uses the sequence
+//! numbers to drop duplicates and to reorder reports.
+
+use std::time::Duration;
+
+use proto::{
+    clients::CtlClient,
+    common::instance::{InstanceId, Status},
+};
+use tokio::{sync::mpsc, time::sleep};
+use tracing::{instrument, trace, warn};
+
+/// The delay before the first retry of a request to the controller.
+pub const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The maximum delay between two retries of a request to the controller.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct Outbox {
+    tx: mpsc::UnboundedSender<(u64, Status)>,
+    next_seq: u64,
+}
+
+impl Outbox {
+    /// Creates the outbox of the given instance, spawning its delivery task.
+    ///
+    /// The task stops once the outbox is dropped and all of its reports were
+    /// delivered.
+    pub fn new(instance_id: InstanceId, ctl_client: CtlClient) -> Self {
+        Self::resumed(instance_id, ctl_client, 1)
+    }
+
+    /// Creates the outbox of an instance adopted after a restart of the
+    /// worker, whose reports up to `next_seq` (exclusive) were already
+    /// delivered by the previous process.
+    pub fn resumed(instance_id: InstanceId, ctl_client: CtlClient, next_seq: u64) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(deliver(instance_id, ctl_client, rx));
+        Outbox { tx, next_seq }
+    }
+
+    pub fn push(&mut self, status: Status) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        // The delivery task only stops once the outbox is dropped.
+        _ = self.tx.send((seq, status));
+    }
+}
+
+#[instrument(skip(ctl_client, rx))]
+async fn deliver(
+    instance_id: InstanceId,
+    ctl_client: CtlClient,
+    mut rx: mpsc::UnboundedReceiver<(u64, Status)>,
+) {
+    while let Some((seq, status)) = rx.recv().await {
+        let mut backoff = BASE_BACKOFF;
+        loop {
+            trace!(seq, ?status, "reporting status");
+            let result = ctl_client
+                .report_instance_status(instance_id, seq, status.clone())
+                .await;
+            match result {
+                Ok(_) => break,
+                Err(error) => {
+                    warn!(?error, seq, "failed to report instance status, retrying");
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use proto::ctl::deployer::{ReportDeployInstanceStatusReq, ReportDeployInstanceStatusRes};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// The sequence numbers received by the fake controller, including the
+    /// ones of failed requests.
+    type Received = Arc<Mutex<Vec<u64>>>;
+
+    /// Fails the first request, acknowledging the following ones.
+    async fn report(
+        State(received): State<Received>,
+        Json(req): Json<ReportDeployInstanceStatusReq>,
+    ) -> Result<Json<ReportDeployInstanceStatusRes>, StatusCode> {
+        let mut received = received.lock().unwrap();
+        received.push(req.seq);
+        if received.len() == 1 {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Ok(Json(ReportDeployInstanceStatusRes {}))
+    }
+
+    #[tokio::test]
+    async fn reports_are_delivered_in_order_until_acknowledged() {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/deployer/status", post(report))
+            .with_state(received.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let ctl_client = CtlClient::new(&addr.to_string()).unwrap();
+        let mut outbox = Outbox::new(InstanceId(Uuid::now_v7()), ctl_client);
+        outbox.push(Status::Started);
+        outbox.push(Status::Terminated);
+        drop(outbox);
+
+        let expected = [1, 1, 2];
+        for _ in 0..50 {
+            if received.lock().unwrap().len() == expected.len() {
+                break;
+            }
+            sleep(BASE_BACKOFF / 5).await;
+        }
+        assert_eq!(*received.lock().unwrap(), expected);
+    }
+}